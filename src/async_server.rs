@@ -0,0 +1,371 @@
+//! A TFTP server running on a [tokio](https://tokio.rs) runtime, for sharing it with
+//! other asynchronous services. Available with the `tokio` cargo feature.
+//!
+//! It uses the same protocol implementation and configuration as `server::TftpServerImpl`,
+//! but runs every transfer as a separate task, with its timeouts driven by tokio timers.
+//! Like it, the time is read from a `Clock`, the system one unless given another via `with_clock`.
+
+use crate::clock::{Clock, SystemClock};
+use crate::connection::ConnectionState;
+use crate::packet::{ErrorCode, Packet, PacketRef, MAX_PACKET_SIZE};
+use crate::server::{self, log_error, Result, ServerConfig};
+use crate::tftp_proto::*;
+use log::*;
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time;
+
+pub type AsyncTftpServer = AsyncTftpServerImpl<FSAdapter>;
+
+/// A RRQ or WRQ packet received on one of the server sockets
+struct Request {
+    data: Vec<u8>,
+    src: SocketAddr,
+    /// The address to reply from
+    local_addr: SocketAddr,
+    ctx: RequestCtx,
+}
+
+pub struct AsyncTftpServerImpl<IO: IOAdapter, C: Clock = SystemClock> {
+    /// The connection timeout
+    timeout: Duration,
+    /// The sockets that receive RRQ and WRQ packets, until the server runs
+    server_sockets: Vec<(net::UdpSocket, RequestCtx)>,
+    /// The TFTP protocol state machine and filesystem accessor
+    proto_handler: TftpServerProto<IO>,
+    /// Changes when transfers blocked on file I/O may resume, or the clock moved
+    wake: watch::Receiver<()>,
+    /// The source of time for connection deadlines
+    clock: C,
+}
+
+impl<IO> AsyncTftpServerImpl<IO>
+where
+    IO: IOAdapter + Default + Send + 'static,
+    IO::R: Send + 'static,
+    IO::W: Send + 'static,
+{
+    /// Creates a new TFTP server from a random open UDP port.
+    pub fn new() -> Result<Self> {
+        Self::with_cfg(&Default::default())
+    }
+
+    /// Creates a new TFTP server from the provided config.
+    /// The sockets are bound immediately, so this needs no runtime.
+    pub fn with_cfg(cfg: &ServerConfig) -> Result<Self> {
+        Self::with_clock(cfg, SystemClock)
+    }
+}
+
+impl<IO, C> AsyncTftpServerImpl<IO, C>
+where
+    IO: IOAdapter + Default + Send + 'static,
+    IO::R: Send + 'static,
+    IO::W: Send + 'static,
+    C: Clock,
+{
+    /// Like `with_cfg`, with timeouts following the given clock instead of the system one
+    pub fn with_clock(cfg: &ServerConfig, clock: C) -> Result<Self> {
+        let server_sockets = server::bind_server_sockets(cfg, false)?;
+        info!(
+            "Async server listening on {:?}",
+            server_sockets
+                .iter()
+                .map(|(socket, ctx)| format!(
+                    "{} (max blocksize {})",
+                    socket.local_addr().unwrap(),
+                    ctx.max_blocksize
+                ))
+                .collect::<Vec<_>>()
+        );
+
+        let mut proto_handler = server::proto_handler(cfg);
+        let (wake_tx, wake) = watch::channel(());
+        let waker: IOWaker = Arc::new(move || {
+            wake_tx.send_replace(());
+        });
+        proto_handler.set_waker(waker.clone());
+        proto_handler.set_clock(clock.clone());
+        clock.set_waker(waker);
+
+        Ok(Self {
+            timeout: cfg.timeout,
+            server_sockets,
+            proto_handler,
+            wake,
+            clock,
+        })
+    }
+
+    /// Serves requests until the returned future is cancelled, e.g. by dropping it,
+    /// aborting the task running it, or losing a `tokio::select!`.
+    /// Cancelling stops the server and aborts all ongoing transfers.
+    ///
+    /// Must be polled from within a tokio runtime. The server no longer listens
+    /// on its addresses afterwards, and cannot be run again.
+    pub async fn run(&mut self) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(1024);
+        let mut listeners = JoinSet::new();
+        for (socket, ctx) in mem::take(&mut self.server_sockets) {
+            let socket = UdpSocket::from_std(socket)?;
+            listeners.spawn(listen(socket, ctx, tx.clone()));
+        }
+        drop(tx);
+
+        let mut transfers = JoinSet::new();
+        // the requests whose file is still being opened
+        let mut blocked = vec![];
+        let mut wake = self.wake.clone();
+        loop {
+            tokio::select! {
+                request = rx.recv() => match request {
+                    Some(request) => {
+                        let result = self.handle_request(request, &mut transfers, &mut blocked);
+                        log_error(result.await)
+                    }
+                    // all listeners failed
+                    None => break,
+                },
+                Some(_) = transfers.join_next() => {}
+                Ok(()) = wake.changed(), if !blocked.is_empty() => {
+                    for request in mem::take(&mut blocked) {
+                        let result = self.handle_request(request, &mut transfers, &mut blocked);
+                        log_error(result.await);
+                    }
+                }
+            }
+        }
+        while transfers.join_next().await.is_some() {}
+        info!("Async server stopped");
+        Ok(())
+    }
+
+    /// Stores the local addresses in the provided vec, until the server runs
+    pub fn get_local_addrs(&self, bag: &mut Vec<SocketAddr>) -> Result<()> {
+        for (socket, _) in &self.server_sockets {
+            bag.push(socket.local_addr()?);
+        }
+        Ok(())
+    }
+
+    /// Replies to a RRQ or WRQ packet, starting a task for the transfer it requests
+    /// Requests whose file the IOAdapter is still opening are added to `blocked`
+    async fn handle_request(
+        &mut self,
+        request: Request,
+        transfers: &mut JoinSet<()>,
+        blocked: &mut Vec<Request>,
+    ) -> Result<()> {
+        let packet = Packet::read(&request.data)?;
+        let (xfer, res) = self.proto_handler.rx_initial_with_ctx(packet, &request.ctx);
+        let reply_packet = match res {
+            Err(TftpError::WouldBlock) => {
+                blocked.push(request);
+                return Ok(());
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                return Ok(());
+            }
+            Ok(packet) => packet,
+        };
+
+        let socket = UdpSocket::from_std(server::bind_socket(request.local_addr)?)?;
+
+        // send packet back for all cases
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let amt = reply_packet.write_to_slice(&mut buf)?;
+        socket.send_to(&buf[..amt], request.src).await?;
+
+        if let Some(xfer) = xfer {
+            let state = ConnectionState::with_clock(
+                xfer,
+                &buf[..amt],
+                request.src,
+                self.timeout,
+                self.clock.clone(),
+            );
+            info!("Created connection with {}", request.src);
+            let mut wake = self.wake.clone();
+            wake.borrow_and_update();
+            transfers.spawn(
+                Connection {
+                    socket,
+                    state,
+                    wake,
+                    clock: self.clock.clone(),
+                }
+                .run(buf),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Receives requests on a server socket, forwarding them to the server task
+async fn listen(socket: UdpSocket, ctx: RequestCtx, tx: mpsc::Sender<Request>) {
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+    loop {
+        let (amt, src, dst) = match recv_from_to(&socket, &mut buf).await {
+            Ok(received) => received,
+            // swallow Io errors, like for any other packet
+            Err(_) => continue,
+        };
+        // when bound to a wildcard address, reply from the one the client contacted
+        let local_addr = match dst {
+            Some(dst) => dst,
+            None => match socket.local_addr() {
+                Ok(addr) => SocketAddr::new(addr.ip(), 0),
+                Err(_) => continue,
+            },
+        };
+        let request = Request {
+            data: buf[..amt].to_vec(),
+            src,
+            local_addr,
+            ctx: RequestCtx {
+                client: Some(src.ip()),
+                ..ctx.clone()
+            },
+        };
+        if tx.send(request).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+async fn recv_from_to(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    loop {
+        socket.readable().await?;
+        match socket.try_io(tokio::io::Interest::READABLE, || {
+            crate::pktinfo::recv_from_to(socket, buf)
+        }) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+async fn recv_from_to(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let (amt, src) = socket.recv_from(buf).await?;
+    Ok((amt, src, None))
+}
+
+/// What woke up a connection task
+enum Event {
+    Packet(usize, SocketAddr),
+    Timeout,
+    Wake,
+}
+
+/// A connection with a client, corresponding to a single read/write transfer.
+/// If dropped before the transfer is over, the client is notified that it was aborted
+struct Connection<IO: IOAdapter, C: Clock> {
+    socket: UdpSocket,
+    state: ConnectionState<IO, C>,
+    wake: watch::Receiver<()>,
+    clock: C,
+}
+
+impl<IO: IOAdapter, C: Clock> Connection<IO, C> {
+    /// Runs the transfer until it is over and its timeout expired
+    async fn run(mut self, mut buf: Vec<u8>) {
+        loop {
+            // without a real-time wait, the deadline is checked again when the clock wakes us up
+            let wait = self.clock.wait_until(self.state.deadline);
+            let event = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((amt, src)) => Event::Packet(amt, src),
+                    Err(_) => continue,
+                },
+                _ = time::sleep(wait.unwrap_or_default()), if wait.is_some() => Event::Timeout,
+                Ok(()) = self.wake.changed() => Event::Wake,
+            };
+            let remote = self.state.remote;
+            let result = match event {
+                Event::Packet(amt, src) => self.handle_packet(&mut buf, amt, src).await,
+                Event::Timeout => match self.state.expire() {
+                    Ok(Some(packets)) => send_all(&self.socket, packets, remote).await,
+                    Ok(None) => break,
+                    Err(e) => Err(e.into()),
+                },
+                Event::Wake if self.state.transfer.is_blocked() => match self.state.resume() {
+                    Ok(packets) => send_all(&self.socket, packets, remote).await,
+                    Err(e) => Err(e.into()),
+                },
+                Event::Wake => Ok(()),
+            };
+            log_error(result);
+        }
+    }
+
+    async fn handle_packet(&mut self, buf: &mut [u8], amt: usize, src: SocketAddr) -> Result<()> {
+        if self.state.remote != src {
+            // packet from somehere else, reply with error
+            let amt = Packet::from(ErrorCode::UnknownID).write_to_slice(buf)?;
+            self.socket.send_to(&buf[..amt], src).await?;
+            return Ok(());
+        }
+        // only the client shows it is still there
+        self.state.reset_timeout();
+        let packet = PacketRef::read(&buf[..amt])?;
+        let packets = self.state.receive(packet)?;
+        send_all(&self.socket, packets, src).await
+    }
+}
+
+impl<IO: IOAdapter, C: Clock> Drop for Connection<IO, C> {
+    fn drop(&mut self) {
+        if !self.state.transfer.is_done() {
+            let packet = Packet::ERROR {
+                code: ErrorCode::NotDefined,
+                msg: "Server shutting down".to_owned(),
+            };
+            if let Ok(data) = packet.into_bytes() {
+                // the transfer is dropped anyway, so sending the error is best-effort
+                let _ = self.socket.try_send_to(&data, self.state.remote);
+            }
+        }
+        info!(
+            "Closing connection with {} after {} retransmissions, estimated RTT {:?}",
+            self.state.remote,
+            self.state.retries,
+            self.state.srtt()
+        );
+    }
+}
+
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+async fn send_all(socket: &UdpSocket, packets: &[Vec<u8>], remote: SocketAddr) -> Result<()> {
+    let mut sent = 0;
+    while sent < packets.len() {
+        sent += socket
+            .async_io(tokio::io::Interest::WRITABLE, || {
+                crate::mmsg::send_batch(socket, &packets[sent..], remote)
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "mmsg", any(target_os = "linux", target_os = "android"))))]
+async fn send_all(socket: &UdpSocket, packets: &[Vec<u8>], remote: SocketAddr) -> Result<()> {
+    for pkt in packets {
+        socket.send_to(pkt, remote).await?;
+    }
+    Ok(())
+}
@@ -2,7 +2,9 @@ use std::net::*;
 use std::path::Path;
 use std::str::FromStr;
 use std::time::Duration;
+use tftp_server::remap::Rules;
 use tftp_server::server::{ServerConfig, TftpServer};
+use tftp_server::tftp_proto::{Rollover, WritePolicy};
 
 use clap::{crate_version, App, Arg};
 
@@ -11,8 +13,19 @@ fn main() {
 
     let arg_ip = "IP address";
     let arg_dir = "Directory";
+    let arg_map_file = "Map file";
+    let arg_backslashes = "Backslashes";
+    let arg_ignore_case = "Ignore case";
     let arg_timeout = "Timeout";
     let arg_readonly = "Readonly";
+    let arg_overwrite = "Overwrite";
+    let arg_retries = "Retries";
+    let arg_max_backoff = "Max backoff";
+    let arg_congestion = "Congestion control";
+    let arg_rollover = "Rollover";
+    let arg_max_blocksize = "Max blocksize";
+    let arg_workers = "Workers";
+    let arg_multicast = "Multicast";
 
     // TODO: test argument handling
     let matches = App::new("TFTP Server")
@@ -35,6 +48,23 @@ fn main() {
                 .takes_value(true)
                 .value_name("DIRECTORY"),
         )
+        .arg(
+            Arg::with_name(arg_map_file)
+                .long("map-file")
+                .help("a file of rules rewriting or denying requested paths, as for tftpd-hpa")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name(arg_backslashes)
+                .long("backslashes")
+                .help("treats backslashes in requested paths as separators, for Windows clients"),
+        )
+        .arg(
+            Arg::with_name(arg_ignore_case)
+                .long("ignore-case")
+                .help("looks up requested paths regardless of case"),
+        )
         .arg(
             Arg::with_name(arg_timeout)
                 .short("t")
@@ -43,12 +73,75 @@ fn main() {
                 .takes_value(true)
                 .value_name("SECONDS"),
         )
+        .arg(
+            Arg::with_name(arg_retries)
+                .long("retries")
+                .help("the number of consecutive retransmissions before a transfer is abandoned")
+                .takes_value(true)
+                .value_name("COUNT"),
+        )
+        .arg(
+            Arg::with_name(arg_max_backoff)
+                .long("max-backoff")
+                .help("doubles the timeout after each retransmission, up to this many seconds")
+                .takes_value(true)
+                .value_name("SECONDS"),
+        )
+        .arg(
+            Arg::with_name(arg_congestion)
+                .long("congestion-control")
+                .help("adapts the number of blocks sent per window to packet loss"),
+        )
+        .arg(
+            Arg::with_name(arg_rollover)
+                .long("rollover")
+                .help("the block number after 65535 unless negotiated (0, 1 or \"never\")")
+                .takes_value(true)
+                .possible_values(&["0", "1", "never"])
+                .value_name("BLOCK"),
+        )
+        .arg(
+            Arg::with_name(arg_max_blocksize)
+                .long("max-blocksize")
+                .help("the largest block size accepted (by default, based on the interface MTU)")
+                .takes_value(true)
+                .value_name("BYTES"),
+        )
+        .arg(
+            Arg::with_name(arg_workers)
+                .long("workers")
+                .help("the number of threads serving transfers (more than 1 needs SO_REUSEPORT)")
+                .takes_value(true)
+                .value_name("COUNT"),
+        )
+        .arg(
+            Arg::with_name(arg_multicast)
+                .long("multicast")
+                .help("a multicast group to send files to, for clients asking for it (RFC 2090)")
+                .takes_value(true)
+                .multiple(true)
+                .value_name("IPAddr:PORT"),
+        )
         .arg(
             Arg::with_name(arg_readonly)
                 .short("r")
                 .long("readonly")
                 .help("rejects all write requests"),
         )
+        .arg(
+            Arg::with_name(arg_overwrite)
+                .long("overwrite")
+                .help("whether uploads replace existing files, and whether those are kept")
+                .takes_value(true)
+                .possible_values(&[
+                    "never",
+                    "always",
+                    "world-writable",
+                    "numbered",
+                    "timestamped",
+                ])
+                .value_name("POLICY"),
+        )
         .get_matches();
 
     let addrs = matches
@@ -86,22 +179,104 @@ fn main() {
         .unwrap_or(3);
     let timeout = Duration::from_secs(timeout);
 
+    let max_retries = matches
+        .value_of(arg_retries)
+        .map(|s| {
+            u32::from_str(s).unwrap_or_else(|_| panic!("error parsing \"{}\" as retry count", s))
+        })
+        .unwrap_or(1);
+
+    let max_backoff = matches.value_of(arg_max_backoff).map(|s| {
+        let n = u64::from_str(s).unwrap_or_else(|_| panic!("error parsing \"{}\" as backoff", s));
+        Duration::from_secs(n)
+    });
+
+    let rollover = match matches.value_of(arg_rollover) {
+        Some("1") => Rollover::ToOne,
+        Some("never") => Rollover::Forbidden,
+        _ => Rollover::ToZero,
+    };
+
+    let write_policy = match matches.value_of(arg_overwrite) {
+        Some("always") => WritePolicy::Overwrite,
+        Some("world-writable") => WritePolicy::OverwriteWorldWritable,
+        Some("numbered") => WritePolicy::KeepNumbered,
+        Some("timestamped") => WritePolicy::KeepTimestamped,
+        _ => WritePolicy::Never,
+    };
+
+    let max_blocksize = matches.value_of(arg_max_blocksize).map(|s| {
+        let n = u16::from_str(s).unwrap_or_else(|_| panic!("error parsing \"{}\" as blocksize", s));
+        if n < 8 {
+            panic!("blocksize must be at least 8 bytes")
+        }
+        n
+    });
+
+    let workers = matches
+        .value_of(arg_workers)
+        .map(|s| {
+            let n = usize::from_str(s)
+                .unwrap_or_else(|_| panic!("error parsing \"{}\" as worker count", s));
+            if n == 0 {
+                panic!("at least 1 worker is needed")
+            }
+            n
+        })
+        .unwrap_or(1);
+
+    let multicast = matches
+        .values_of(arg_multicast)
+        .map(|groups| {
+            groups
+                .map(|s| {
+                    let group = SocketAddr::from_str(s)
+                        .unwrap_or_else(|_| panic!("error parsing \"{}\" as multicast group", s));
+                    if !group.ip().is_multicast() {
+                        panic!("{} is not a multicast address", group.ip())
+                    }
+                    group
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
     let dir = matches.value_of(arg_dir).map(|dir| {
         let path = Path::new(dir);
         assert!(path.exists(), "specified path \"{}\" does not exist", dir);
         path.to_owned()
     });
 
+    let remap = matches
+        .value_of(arg_map_file)
+        .map(|file| {
+            Rules::load(Path::new(file))
+                .unwrap_or_else(|e| panic!("error loading map file \"{}\": {}", file, e))
+        })
+        .unwrap_or_default();
+
     let cfg = ServerConfig {
         readonly: matches.is_present(arg_readonly),
         addrs,
         dir,
+        remap,
+        backslashes: matches.is_present(arg_backslashes),
+        case_insensitive: matches.is_present(arg_ignore_case),
+        write_policy,
         timeout,
+        max_retries,
+        max_backoff,
+        congestion_control: matches.is_present(arg_congestion),
+        rollover,
+        max_blocksize,
+        workers,
+        multicast,
+        observer: None,
     };
 
     let mut server = TftpServer::with_cfg(&cfg).expect("Error creating server");
 
-    match server.run() {
+    match server.run_workers() {
         Ok(_) => println!("Server completed successfully!"),
         Err(e) => println!("Error: {:?}", e),
     }
@@ -0,0 +1,685 @@
+//! A blocking TFTP client, usable against any RFC 1350 server
+//!
+//! Options (`blksize`, `timeout`, `tsize` and `windowsize`) are only proposed
+//! when set in the `ClientConfig`, and the values acknowledged by the server
+//! via OACK are used for the transfer.
+
+use crate::netascii::{ModeReader, ModeWriter, NetasciiReader, NetasciiWriter};
+use crate::packet::{
+    write_data_header, ErrorCode, Packet, PacketErr, TftpOption, TransferMode, DATA_HEADER_LEN,
+    MAX_PACKET_SIZE,
+};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::result;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum TftpError {
+    /// The server refused or aborted the transfer with an ERROR packet
+    Remote {
+        code: ErrorCode,
+        msg: String,
+    },
+    /// The server stopped replying, even after retransmissions
+    Timeout,
+    /// The server sent a packet that is not valid at this point of the transfer
+    UnexpectedPacket(Packet),
+    /// The server acknowledged an option that was not proposed, or with a larger value
+    BadOption(TftpOption),
+    PacketError(PacketErr),
+    IoError(io::Error),
+}
+
+impl TftpError {
+    /// Returns the TFTP error code describing this error,
+    /// either the one received from the server or the closest match
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            TftpError::Remote { code, .. } => code,
+            TftpError::UnexpectedPacket(_) => ErrorCode::IllegalTFTP,
+            TftpError::BadOption(_) => ErrorCode::BadOption,
+            TftpError::Timeout | TftpError::PacketError(_) | TftpError::IoError(_) => {
+                ErrorCode::NotDefined
+            }
+        }
+    }
+}
+
+impl From<io::Error> for TftpError {
+    fn from(err: io::Error) -> TftpError {
+        TftpError::IoError(err)
+    }
+}
+
+impl From<PacketErr> for TftpError {
+    fn from(err: PacketErr) -> TftpError {
+        TftpError::PacketError(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, TftpError>;
+
+/// Struct used to specify the behavior of a client
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// The transfer mode, `Octet` or `Netascii`
+    pub mode: TransferMode,
+    /// The block size to propose via the `blksize` option
+    pub blocksize: Option<u16>,
+    /// The window size to propose via the `windowsize` option
+    pub window_size: Option<u16>,
+    /// The retransmission timeout (in seconds) to propose via the `timeout` option
+    pub timeout_secs: Option<u8>,
+    /// Whether to request (for reads) or announce (for writes) the size
+    /// of the transferred file via the `tsize` option
+    pub transfer_size: bool,
+    /// The time to wait for a reply before retransmitting, used unless a timeout is negotiated
+    pub timeout: Duration,
+    /// The number of retransmissions without a reply before a transfer is abandoned
+    pub retries: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            mode: TransferMode::Octet,
+            blocksize: None,
+            window_size: None,
+            timeout_secs: None,
+            transfer_size: false,
+            timeout: Duration::from_secs(3),
+            retries: 5,
+        }
+    }
+}
+
+/// Transfer parameters in effect after option negotiation
+#[derive(Debug)]
+struct Negotiated {
+    blocksize: u16,
+    window_size: u16,
+    timeout: Duration,
+    transfer_size: Option<u64>,
+}
+
+/// A client for reading and writing files on a TFTP server.
+/// Each transfer uses its own socket, so a single client can be shared by multiple threads.
+/// Replies from any other address than the server's are refused, on multihomed servers too.
+pub struct TftpClient {
+    server: SocketAddr,
+    cfg: ClientConfig,
+}
+
+impl TftpClient {
+    /// Creates a client for the given server using the default configuration
+    pub fn new(server: SocketAddr) -> Self {
+        Self::with_cfg(server, Default::default())
+    }
+
+    /// Creates a client for the given server from the provided config
+    pub fn with_cfg(server: SocketAddr, cfg: ClientConfig) -> Self {
+        Self { server, cfg }
+    }
+
+    /// Reads `file` from the server, writing its contents into `dest`.
+    /// Returns the number of data bytes received
+    pub fn get<W: Write>(&self, file: &str, dest: W) -> Result<u64> {
+        self.get_with_progress(file, dest, |_, _| {})
+    }
+
+    /// Same as `get`, additionally calling `progress` after every block received
+    /// with the number of data bytes so far and the transfer size, if announced by the server
+    pub fn get_with_progress<W, F>(&self, file: &str, dest: W, mut progress: F) -> Result<u64>
+    where
+        W: Write,
+        F: FnMut(u64, Option<u64>),
+    {
+        let mut options = self.proposed_options();
+        if self.cfg.transfer_size {
+            options.push(TftpOption::TransferSize(0));
+        }
+        let mut conn = Connection::open(self.server)?;
+        conn.send(&Packet::RRQ {
+            filename: file.into(),
+            mode: self.cfg.mode,
+            options: options.clone(),
+        })?;
+
+        let mut dest = match self.cfg.mode {
+            TransferMode::Netascii => ModeWriter::Netascii(NetasciiWriter::new(dest)),
+            _ => ModeWriter::Octet(dest),
+        };
+
+        let mut retries = 0;
+        let (neg, first_data) = loop {
+            match conn.recv(self.cfg.timeout)? {
+                None => {
+                    retries += 1;
+                    if retries > self.cfg.retries {
+                        return Err(TftpError::Timeout);
+                    }
+                    conn.resend_last()?;
+                }
+                Some(Packet::OACK { options: acked }) => {
+                    let neg = self.negotiate(&options, acked, &mut conn)?;
+                    conn.send(&Packet::ACK(0))?;
+                    break (neg, None);
+                }
+                Some(Packet::DATA { block_num: 1, data }) => {
+                    // options not supported by the server, so defaults apply
+                    break (self.negotiate(&options, vec![], &mut conn)?, Some(data));
+                }
+                Some(p) => return Err(conn.unexpected(p)),
+            }
+        };
+
+        let mut rx = Receiver {
+            neg,
+            last: 0,
+            in_window: 0,
+            received: 0,
+        };
+        if let Some(data) = first_data {
+            let done = rx.data(1, &data, &mut conn, &mut dest)?;
+            progress(rx.received, rx.neg.transfer_size);
+            if done {
+                dest.finish()?;
+                return Ok(rx.received);
+            }
+        }
+
+        let mut retries = 0;
+        loop {
+            match conn.recv(rx.neg.timeout)? {
+                None => {
+                    retries += 1;
+                    if retries > self.cfg.retries {
+                        return Err(TftpError::Timeout);
+                    }
+                    // acknowledge what arrived of an incomplete window, as per RFC 7440
+                    rx.in_window = 0;
+                    conn.send(&Packet::ACK(rx.last))?;
+                }
+                Some(Packet::DATA { block_num, data }) => {
+                    retries = 0;
+                    let done = rx.data(block_num, &data, &mut conn, &mut dest)?;
+                    progress(rx.received, rx.neg.transfer_size);
+                    if done {
+                        dest.finish()?;
+                        return Ok(rx.received);
+                    }
+                }
+                Some(Packet::OACK { .. }) if rx.last == 0 => {
+                    // the ACK of the OACK was lost
+                    conn.send(&Packet::ACK(0))?;
+                }
+                Some(p) => return Err(conn.unexpected(p)),
+            }
+        }
+    }
+
+    /// Writes the contents of `src` into `file` on the server.
+    /// `size` is the amount of bytes `src` will yield, and is used for the `tsize` option.
+    /// Returns the number of data bytes sent
+    pub fn put<R: Read>(&self, file: &str, src: R, size: Option<u64>) -> Result<u64> {
+        self.put_with_progress(file, src, size, |_, _| {})
+    }
+
+    /// Same as `put`, additionally calling `progress` after every acknowledgement
+    /// with the number of data bytes acknowledged so far and the given `size`
+    pub fn put_with_progress<R, F>(
+        &self,
+        file: &str,
+        src: R,
+        size: Option<u64>,
+        mut progress: F,
+    ) -> Result<u64>
+    where
+        R: Read,
+        F: FnMut(u64, Option<u64>),
+    {
+        let mut options = self.proposed_options();
+        if let (true, Some(size)) = (self.cfg.transfer_size, size) {
+            // netascii expands the data, so the size is only known for octet transfers
+            if self.cfg.mode == TransferMode::Octet {
+                options.push(TftpOption::TransferSize(size));
+            }
+        }
+        let mut conn = Connection::open(self.server)?;
+        conn.send(&Packet::WRQ {
+            filename: file.into(),
+            mode: self.cfg.mode,
+            options: options.clone(),
+        })?;
+
+        let mut src = match self.cfg.mode {
+            TransferMode::Netascii => ModeReader::Netascii(NetasciiReader::new(src)),
+            _ => ModeReader::Octet(src),
+        };
+
+        let mut retries = 0;
+        let neg = loop {
+            match conn.recv(self.cfg.timeout)? {
+                None => {
+                    retries += 1;
+                    if retries > self.cfg.retries {
+                        return Err(TftpError::Timeout);
+                    }
+                    conn.resend_last()?;
+                }
+                Some(Packet::OACK { options: acked }) => {
+                    break self.negotiate(&options, acked, &mut conn)?;
+                }
+                Some(Packet::ACK(0)) => break self.negotiate(&options, vec![], &mut conn)?,
+                Some(p) => return Err(conn.unexpected(p)),
+            }
+        };
+
+        let mut acked = 0u16;
+        let mut sent = 0u64;
+        let mut acked_bytes = 0u64;
+        let mut read_all = false;
+        // DATA packets sent but not yet acknowledged, oldest first
+        let mut pending = VecDeque::new();
+        let mut retries = 0;
+        loop {
+            while pending.len() < neg.window_size as usize && !read_all {
+                let block_num = acked.wrapping_add(pending.len() as u16 + 1);
+                // read straight into the packet, after its header
+                let mut packet = vec![0; DATA_HEADER_LEN];
+                packet.reserve(neg.blocksize as usize);
+                write_data_header(block_num, &mut packet)?;
+                let len = src
+                    .by_ref()
+                    .take(u64::from(neg.blocksize))
+                    .read_to_end(&mut packet)?;
+                read_all = len < neg.blocksize as usize;
+                sent += len as u64;
+                conn.send_raw(&packet)?;
+                pending.push_back(packet);
+            }
+
+            match conn.recv(neg.timeout)? {
+                None => {
+                    retries += 1;
+                    if retries > self.cfg.retries {
+                        return Err(TftpError::Timeout);
+                    }
+                    for packet in &pending {
+                        conn.send_raw(packet)?;
+                    }
+                }
+                Some(Packet::ACK(block)) => {
+                    let count = block.wrapping_sub(acked) as usize;
+                    if count > pending.len() {
+                        // stale duplicate, or for a block never sent
+                        continue;
+                    }
+                    if count == 0 {
+                        // the server lost the block following the acknowledged one,
+                        // which only counts as progress once it is acknowledged
+                        retries += 1;
+                        if retries > self.cfg.retries {
+                            return Err(TftpError::Timeout);
+                        }
+                        for packet in &pending {
+                            conn.send_raw(packet)?;
+                        }
+                        continue;
+                    }
+                    retries = 0;
+                    acked_bytes += pending
+                        .drain(..count)
+                        .map(|p| (p.len() - DATA_HEADER_LEN) as u64)
+                        .sum::<u64>();
+                    acked = block;
+                    progress(acked_bytes, size);
+                    if pending.is_empty() && read_all {
+                        return Ok(sent);
+                    }
+                    // the server expects the rest of the window again, in order
+                    for packet in &pending {
+                        conn.send_raw(packet)?;
+                    }
+                }
+                Some(Packet::OACK { .. }) if acked == 0 => {
+                    // the first window was lost
+                    for packet in &pending {
+                        conn.send_raw(packet)?;
+                    }
+                }
+                Some(p) => return Err(conn.unexpected(p)),
+            }
+        }
+    }
+
+    fn proposed_options(&self) -> Vec<TftpOption> {
+        let mut options = vec![];
+        if let Some(size) = self.cfg.blocksize {
+            options.push(TftpOption::Blocksize(size));
+        }
+        if let Some(secs) = self.cfg.timeout_secs {
+            options.push(TftpOption::TimeoutSecs(secs));
+        }
+        if let Some(size) = self.cfg.window_size {
+            options.push(TftpOption::WindowSize(size));
+        }
+        options
+    }
+
+    /// Checks the options acknowledged by the server against the proposed ones,
+    /// and returns the resulting transfer parameters
+    fn negotiate(
+        &self,
+        proposed: &[TftpOption],
+        acked: Vec<TftpOption>,
+        conn: &mut Connection,
+    ) -> Result<Negotiated> {
+        let mut neg = Negotiated {
+            blocksize: 512,
+            window_size: 1,
+            timeout: self.cfg.timeout,
+            transfer_size: None,
+        };
+        for opt in acked {
+            let valid = proposed.iter().any(|p| match (p, &opt) {
+                (TftpOption::Blocksize(p), TftpOption::Blocksize(a)) => a <= p,
+                (TftpOption::WindowSize(p), TftpOption::WindowSize(a)) => a <= p,
+                (TftpOption::TimeoutSecs(p), TftpOption::TimeoutSecs(a)) => a == p,
+                (TftpOption::TransferSize(_), TftpOption::TransferSize(_)) => true,
+                _ => false,
+            });
+            if !valid {
+                conn.send(&ErrorCode::BadOption.into())?;
+                return Err(TftpError::BadOption(opt));
+            }
+            match opt {
+                TftpOption::Blocksize(size) => neg.blocksize = size,
+                TftpOption::WindowSize(size) => neg.window_size = size,
+                TftpOption::TimeoutSecs(secs) => neg.timeout = Duration::from_secs(u64::from(secs)),
+                TftpOption::TransferSize(size) => neg.transfer_size = Some(size),
+                // never proposed, so rejected above
+                TftpOption::Multicast(_)
+                | TftpOption::Rollover(_)
+                | TftpOption::TimeoutMicros(_) => {}
+            }
+        }
+        Ok(neg)
+    }
+}
+
+/// The receiving side state of a read transfer
+struct Receiver {
+    neg: Negotiated,
+    /// The last block received in sequence
+    last: u16,
+    /// How many blocks of the current window were received
+    in_window: u16,
+    received: u64,
+}
+
+impl Receiver {
+    /// Handles a DATA packet, returning whether it was the final one
+    fn data<W: Write>(
+        &mut self,
+        block_num: u16,
+        data: &[u8],
+        conn: &mut Connection,
+        dest: &mut W,
+    ) -> Result<bool> {
+        if block_num != self.last.wrapping_add(1) {
+            // lost, reordered or duplicated, so restart the window after the last good block
+            self.in_window = 0;
+            conn.send(&Packet::ACK(self.last))?;
+            return Ok(false);
+        }
+        if let Err(e) = dest.write_all(data) {
+            let _ = conn.send(&Packet::ERROR {
+                code: ErrorCode::DiskFull,
+                msg: "Write failed".to_owned(),
+            });
+            return Err(e.into());
+        }
+        self.last = block_num;
+        self.received += data.len() as u64;
+        self.in_window += 1;
+
+        let is_final = data.len() < self.neg.blocksize as usize;
+        if is_final || self.in_window == self.neg.window_size {
+            self.in_window = 0;
+            conn.send(&Packet::ACK(self.last))?;
+        }
+        Ok(is_final)
+    }
+}
+
+/// A UDP socket used for a single transfer, tracking the server's transfer ID
+struct Connection {
+    socket: UdpSocket,
+    server: SocketAddr,
+    /// The address the server replies from, known after its first reply
+    peer: Option<SocketAddr>,
+    buf: Vec<u8>,
+    last_sent: Vec<u8>,
+}
+
+impl Connection {
+    fn open(server: SocketAddr) -> Result<Self> {
+        let local_ip = match server {
+            SocketAddr::V4(_) => IpAddr::from([0; 4]),
+            SocketAddr::V6(_) => IpAddr::from([0; 16]),
+        };
+        Ok(Self {
+            socket: UdpSocket::bind((local_ip, 0))?,
+            server,
+            peer: None,
+            buf: vec![0; MAX_PACKET_SIZE],
+            last_sent: vec![],
+        })
+    }
+
+    /// Sends a packet to the server, remembering it for retransmission
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        let bytes = packet.to_bytes()?;
+        self.send_raw(&bytes)?;
+        self.last_sent = bytes;
+        Ok(())
+    }
+
+    fn send_raw(&self, bytes: &[u8]) -> Result<()> {
+        self.socket
+            .send_to(bytes, self.peer.unwrap_or(self.server))?;
+        Ok(())
+    }
+
+    fn resend_last(&self) -> Result<()> {
+        self.send_raw(&self.last_sent)
+    }
+
+    /// Waits for the next packet from the server, returning `None` if none arrives in time.
+    /// ERROR packets are returned as `TftpError::Remote`
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Packet>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(left))?;
+            let (amt, src) = match self.socket.recv_from(&mut self.buf) {
+                Ok(r) => r,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let foreign = match self.peer {
+                Some(peer) => peer != src,
+                // so servers must reply from the address they were contacted at,
+                // which multihomed ones may not do
+                None => src.ip() != self.server.ip(),
+            };
+            if foreign {
+                // not part of this transfer
+                let amt = Packet::from(ErrorCode::UnknownID).write_to_slice(&mut self.buf)?;
+                self.socket.send_to(&self.buf[..amt], src)?;
+                continue;
+            }
+
+            let packet = match Packet::read(&self.buf[..amt]) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            // the first reply determines the server's transfer ID
+            self.peer = Some(src);
+            if let Packet::ERROR { code, msg } = packet {
+                return Err(TftpError::Remote { code, msg });
+            }
+            return Ok(Some(packet));
+        }
+    }
+
+    /// Notifies the server about an unexpected packet and returns the error for it
+    fn unexpected(&mut self, packet: Packet) -> TftpError {
+        let _ = self.send(&ErrorCode::IllegalTFTP.into());
+        TftpError::UnexpectedPacket(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// A server replying to the packets of a single transfer as `script` says,
+    /// which gets each received packet and returns those to send back, if any
+    fn scripted_server<F>(mut script: F) -> (SocketAddr, thread::JoinHandle<()>)
+    where
+        F: FnMut(Packet) -> Option<Vec<Packet>> + Send + 'static,
+    {
+        let socket = UdpSocket::bind((IpAddr::from([127, 0, 0, 1]), 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        let thread = thread::spawn(move || {
+            let mut buf = vec![0; MAX_PACKET_SIZE];
+            // until the client is done and stops sending
+            while let Ok((amt, src)) = socket.recv_from(&mut buf) {
+                let packet = Packet::read(&buf[..amt]).unwrap();
+                match script(packet) {
+                    Some(replies) => {
+                        for reply in replies {
+                            socket.send_to(&reply.to_bytes().unwrap(), src).unwrap();
+                        }
+                    }
+                    None => break,
+                }
+            }
+        });
+        (addr, thread)
+    }
+
+    fn client(server: SocketAddr) -> TftpClient {
+        TftpClient::with_cfg(
+            server,
+            ClientConfig {
+                blocksize: Some(600),
+                timeout: Duration::from_secs(5),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn oack() -> Packet {
+        Packet::OACK {
+            options: vec![TftpOption::Blocksize(600)],
+        }
+    }
+
+    #[test]
+    fn get_ack_of_oack_lost() {
+        let mut acks = 0;
+        let (addr, server) = scripted_server(move |packet| match packet {
+            Packet::RRQ { .. } => Some(vec![oack()]),
+            // the first one is lost, so the OACK is sent again
+            Packet::ACK(0) => {
+                acks += 1;
+                Some(if acks == 1 {
+                    vec![oack()]
+                } else {
+                    vec![Packet::DATA {
+                        block_num: 1,
+                        data: vec![7; 10],
+                    }]
+                })
+            }
+            Packet::ACK(1) => None,
+            packet => panic!("unexpected {:?}", packet),
+        });
+        let mut v = vec![];
+        assert_eq!(client(addr).get("file", &mut v).unwrap(), 10);
+        assert_eq!(v, vec![7; 10]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn put_first_data_lost() {
+        let mut blocks = 0;
+        let (addr, server) = scripted_server(move |packet| match packet {
+            Packet::WRQ { .. } => Some(vec![oack()]),
+            Packet::DATA { block_num: 1, data } => {
+                assert_eq!(data, vec![7; 10]);
+                blocks += 1;
+                // the first one is lost, so the OACK is sent again
+                if blocks == 1 {
+                    Some(vec![oack()])
+                } else {
+                    Some(vec![Packet::ACK(1)])
+                }
+            }
+            packet => panic!("unexpected {:?}", packet),
+        });
+        assert_eq!(client(addr).put("file", &[7; 10][..], None).unwrap(), 10);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn put_duplicate_ack_resends() {
+        let mut blocks = 0;
+        let (addr, server) = scripted_server(move |packet| match packet {
+            Packet::WRQ { .. } => Some(vec![oack()]),
+            Packet::DATA { block_num: 1, .. } => {
+                blocks += 1;
+                // the first one is lost, which the server signals right away
+                Some(vec![Packet::ACK(if blocks == 1 { 0 } else { 1 })])
+            }
+            packet => panic!("unexpected {:?}", packet),
+        });
+        let started = Instant::now();
+        assert_eq!(client(addr).put("file", &[7; 10][..], None).unwrap(), 10);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "waited for the timeout"
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn put_duplicate_acks_are_retries() {
+        let (addr, server) = scripted_server(move |packet| match packet {
+            Packet::WRQ { .. } => Some(vec![oack()]),
+            Packet::DATA { block_num: 1, .. } => Some(vec![Packet::ACK(0)]),
+            packet => panic!("unexpected {:?}", packet),
+        });
+        let result = client(addr).put("file", &[7; 10][..], None);
+        assert!(matches!(result, Err(TftpError::Timeout)));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,423 @@
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::*;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tftp_server::client::{ClientConfig, TftpClient, TftpError};
+use tftp_server::packet::{ErrorCode, TransferMode};
+
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+
+/// Exit code for errors on the local side (bad arguments, file access)
+const EXIT_LOCAL: i32 = 1;
+/// Exit code for transfers abandoned because the server stopped replying
+const EXIT_TIMEOUT: i32 = 2;
+/// Errors received from (or sent to) the server exit with this plus the `ErrorCode` value
+const EXIT_ERROR_CODE_BASE: i32 = 10;
+
+fn exit_code(err: &TftpError) -> i32 {
+    match *err {
+        TftpError::Timeout => EXIT_TIMEOUT,
+        TftpError::IoError(_) => EXIT_LOCAL,
+        _ => EXIT_ERROR_CODE_BASE + err.error_code() as i32,
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("tftp: {}", msg);
+    process::exit(EXIT_LOCAL)
+}
+
+fn parse_num<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|s| {
+        T::from_str(s).unwrap_or_else(|_| fail(&format!("error parsing \"{}\" as {}", s, name)))
+    })
+}
+
+/// Prints the progress of a transfer on a single terminal line
+struct Progress {
+    quiet: bool,
+    start: Instant,
+    last_update: Cell<Option<Instant>>,
+}
+
+impl Progress {
+    fn update(&self, bytes: u64, total: Option<u64>) {
+        if self.quiet {
+            return;
+        }
+        // don't flood the terminal, a few updates per second are enough
+        let now = Instant::now();
+        match self.last_update.get() {
+            Some(last) if now.duration_since(last) < Duration::from_millis(100) => return,
+            _ => self.last_update.set(Some(now)),
+        }
+        match total {
+            Some(total) if total > 0 => {
+                let pct = (bytes * 100 / total).min(100);
+                eprint!("\r{} / {} bytes ({}%)", bytes, total, pct)
+            }
+            _ => eprint!("\r{} bytes", bytes),
+        }
+    }
+
+    fn finish(&self, bytes: u64) {
+        if self.quiet {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let rate = if secs > 0.0 { bytes as f64 / secs } else { 0.0 };
+        eprintln!(
+            "\rtransferred {} bytes in {:.2} s ({:.1} KiB/s)",
+            bytes,
+            secs,
+            rate / 1024.0
+        );
+    }
+}
+
+const ARG_SERVER: &str = "Server";
+const ARG_MODE: &str = "Mode";
+const ARG_BLOCKSIZE: &str = "Blocksize";
+const ARG_WINDOWSIZE: &str = "Windowsize";
+const ARG_TIMEOUT: &str = "Timeout";
+const ARG_TSIZE: &str = "Tsize";
+const ARG_RETRIES: &str = "Retries";
+const ARG_QUIET: &str = "Quiet";
+const ARG_REMOTE: &str = "Remote file";
+const ARG_LOCAL: &str = "Local file";
+
+fn app() -> App<'static, 'static> {
+    App::new("TFTP Client")
+    .about("A client implementation of the TFTP Protocol (IETF RFC 1350)")
+    .version(crate_version!())
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .after_help(
+        "EXIT CODES:\n    0 on success, 1 for local errors, 2 if the server stopped replying,\n    \
+         10 + the TFTP error code (e.g. 11 for \"File not found\") for protocol errors",
+    )
+    .arg(
+        Arg::with_name(ARG_SERVER)
+            .help("the server address, 69 is used if no port is given")
+            .required(true)
+            .value_name("IPAddr[:PORT]"),
+    )
+    .arg(
+        Arg::with_name(ARG_MODE)
+            .short("m")
+            .long("mode")
+            .help("the transfer mode")
+            .takes_value(true)
+            .possible_values(&["octet", "netascii"])
+            .default_value("octet"),
+    )
+    .arg(
+        Arg::with_name(ARG_BLOCKSIZE)
+            .short("b")
+            .long("blocksize")
+            .help("proposes a block size (blksize option)")
+            .takes_value(true)
+            .value_name("BYTES"),
+    )
+    .arg(
+        Arg::with_name(ARG_WINDOWSIZE)
+            .short("w")
+            .long("windowsize")
+            .help("proposes a window size (windowsize option)")
+            .takes_value(true)
+            .value_name("BLOCKS"),
+    )
+    .arg(
+        Arg::with_name(ARG_TIMEOUT)
+            .short("t")
+            .long("timeout")
+            .help("the number of seconds to wait before retransmitting, also proposed to the server (timeout option)")
+            .takes_value(true)
+            .value_name("SECONDS"),
+    )
+    .arg(
+        Arg::with_name(ARG_TSIZE)
+            .short("s")
+            .long("tsize")
+            .help("requests or announces the transfer size (tsize option)"),
+    )
+    .arg(
+        Arg::with_name(ARG_RETRIES)
+            .short("r")
+            .long("retries")
+            .help("the number of retransmissions before giving up")
+            .takes_value(true)
+            .value_name("COUNT"),
+    )
+    .arg(
+        Arg::with_name(ARG_QUIET)
+            .short("q")
+            .long("quiet")
+            .help("does not print progress and throughput"),
+    )
+    .subcommand(
+        SubCommand::with_name("get")
+            .about("reads a file from the server")
+            .arg(Arg::with_name(ARG_REMOTE).required(true))
+            .arg(Arg::with_name(ARG_LOCAL).help("defaults to the last component of the remote file name, \"-\" for stdout")),
+    )
+    .subcommand(
+        SubCommand::with_name("put")
+            .about("writes a file to the server")
+            .arg(Arg::with_name(ARG_LOCAL).required(true))
+            .arg(Arg::with_name(ARG_REMOTE).help("defaults to the local file name")),
+    )
+}
+
+/// Parses the server argument, first as ip:port, then as just an ip with the default port
+fn parse_server(server: &str) -> Option<SocketAddr> {
+    if let Ok(sk) = SocketAddr::from_str(server) {
+        Some(sk)
+    } else if let Ok(ip) = IpAddr::from_str(server) {
+        Some(SocketAddr::new(ip, 69))
+    } else {
+        None
+    }
+}
+
+/// Returns the local name of a download that doesn't have one: the last component
+/// of the remote path, as in tftp-hpa, rather than creating local directories
+fn default_local(remote: &str) -> &str {
+    Path::new(remote)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(remote)
+}
+
+/// Returns the path a download to `local` is written to until it completes,
+/// so that a failed transfer doesn't leave a truncated file behind
+fn partial_path(local: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}.part", local, process::id()))
+}
+
+/// Downloads `remote` into the file `local`, replacing it only once the transfer succeeded
+fn get_to_file<F: Fn(u64, Option<u64>)>(
+    client: &TftpClient,
+    remote: &str,
+    local: &str,
+    progress: F,
+) -> Result<u64, TftpError> {
+    let partial = partial_path(local);
+    let file = File::create(&partial)
+        .unwrap_or_else(|e| fail(&format!("cannot create \"{}\": {}", partial.display(), e)));
+    let result = client
+        .get_with_progress(remote, file, progress)
+        .and_then(|bytes| {
+            fs::rename(&partial, local)
+                .map(|_| bytes)
+                .map_err(Into::into)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = app().get_matches();
+
+    let server = matches.value_of(ARG_SERVER).unwrap();
+    let server = parse_server(server).unwrap_or_else(|| {
+        fail(&format!(
+            "error parsing argument \"{}\" as ip address",
+            server
+        ))
+    });
+
+    let mut cfg = ClientConfig {
+        mode: match matches.value_of(ARG_MODE) {
+            Some("netascii") => TransferMode::Netascii,
+            _ => TransferMode::Octet,
+        },
+        blocksize: parse_num(&matches, ARG_BLOCKSIZE),
+        window_size: parse_num(&matches, ARG_WINDOWSIZE),
+        timeout_secs: parse_num(&matches, ARG_TIMEOUT),
+        transfer_size: matches.is_present(ARG_TSIZE),
+        ..Default::default()
+    };
+    if let Some(secs) = cfg.timeout_secs {
+        if secs == 0 {
+            fail("timeout may not be 0 seconds");
+        }
+        cfg.timeout = Duration::from_secs(u64::from(secs));
+    }
+    if let Some(retries) = parse_num(&matches, ARG_RETRIES) {
+        cfg.retries = retries;
+    }
+
+    let octet = cfg.mode == TransferMode::Octet;
+    let client = TftpClient::with_cfg(server, cfg);
+    let progress = Progress {
+        quiet: matches.is_present(ARG_QUIET),
+        start: Instant::now(),
+        last_update: Cell::new(None),
+    };
+
+    let result = match matches.subcommand() {
+        ("get", Some(sub)) => {
+            let remote = sub.value_of(ARG_REMOTE).unwrap();
+            let local = sub
+                .value_of(ARG_LOCAL)
+                .unwrap_or_else(|| default_local(remote));
+            let update = |bytes, total| progress.update(bytes, total);
+            if local == "-" {
+                let stdout = io::stdout();
+                let lock = stdout.lock();
+                client.get_with_progress(remote, lock, update)
+            } else {
+                get_to_file(&client, remote, local, update)
+            }
+        }
+        ("put", Some(sub)) => {
+            let local = sub.value_of(ARG_LOCAL).unwrap();
+            let remote = sub.value_of(ARG_REMOTE).unwrap_or(local);
+            let file = File::open(local)
+                .unwrap_or_else(|e| fail(&format!("cannot open \"{}\": {}", local, e)));
+            let size = file.metadata().ok().map(|m| m.len());
+            client.put_with_progress(remote, file, size, |bytes, total| {
+                // netascii expands line endings, so the file size can't be compared with
+                progress.update(bytes, total.filter(|_| octet))
+            })
+        }
+        _ => unreachable!(),
+    };
+
+    match result {
+        Ok(bytes) => {
+            io::stdout().flush().ok();
+            progress.finish(bytes)
+        }
+        Err(e) => {
+            if !progress.quiet {
+                eprintln!();
+            }
+            match e {
+                TftpError::Remote { code, ref msg }
+                    if code != ErrorCode::NotDefined && *msg != code.to_string() =>
+                {
+                    eprintln!("tftp: {} ({})", code.to_string(), msg)
+                }
+                TftpError::Remote { ref msg, .. } => eprintln!("tftp: {}", msg),
+                TftpError::Timeout => eprintln!("tftp: transfer timed out"),
+                ref e => eprintln!("tftp: {:?}", e),
+            }
+            process::exit(exit_code(&e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tftp_server::packet::Packet;
+
+    #[test]
+    fn server_address() {
+        assert_eq!(
+            parse_server("10.0.0.1:6969"),
+            Some("10.0.0.1:6969".parse().unwrap())
+        );
+        assert_eq!(
+            parse_server("10.0.0.1"),
+            Some("10.0.0.1:69".parse().unwrap())
+        );
+        assert_eq!(parse_server("::1"), Some("[::1]:69".parse().unwrap()));
+        assert_eq!(parse_server("localhost"), None);
+    }
+
+    #[test]
+    fn arguments() {
+        let matches = app()
+            .get_matches_from_safe(["tftp", "-b", "1428", "-s", "127.0.0.1", "get", "a.txt"])
+            .unwrap();
+        assert_eq!(parse_num::<u16>(&matches, ARG_BLOCKSIZE), Some(1428));
+        assert_eq!(parse_num::<u16>(&matches, ARG_WINDOWSIZE), None);
+        assert!(matches.is_present(ARG_TSIZE));
+        assert_eq!(matches.value_of(ARG_MODE), Some("octet"));
+        let (name, sub) = matches.subcommand();
+        assert_eq!(name, "get");
+        let sub = sub.unwrap();
+        assert_eq!(sub.value_of(ARG_REMOTE), Some("a.txt"));
+        assert_eq!(sub.value_of(ARG_LOCAL), None);
+
+        let matches = app()
+            .get_matches_from_safe(["tftp", "-m", "netascii", "127.0.0.1", "put", "a", "b"])
+            .unwrap();
+        assert_eq!(matches.value_of(ARG_MODE), Some("netascii"));
+        let sub = matches.subcommand_matches("put").unwrap();
+        assert_eq!(sub.value_of(ARG_LOCAL), Some("a"));
+        assert_eq!(sub.value_of(ARG_REMOTE), Some("b"));
+
+        let rejected: &[&[&str]] = &[
+            &["tftp", "127.0.0.1"],
+            &["tftp", "127.0.0.1", "get"],
+            &["tftp", "-m", "mail", "127.0.0.1", "get", "a"],
+        ];
+        for args in rejected {
+            assert!(app().get_matches_from_safe(*args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn default_local_name() {
+        assert_eq!(default_local("a.txt"), "a.txt");
+        assert_eq!(default_local("pxelinux.cfg/default"), "default");
+        assert_eq!(default_local("/boot/vmlinuz"), "vmlinuz");
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(&TftpError::Timeout), EXIT_TIMEOUT);
+        assert_eq!(
+            exit_code(&io::Error::from(io::ErrorKind::NotFound).into()),
+            EXIT_LOCAL
+        );
+        let not_found = TftpError::Remote {
+            code: ErrorCode::FileNotFound,
+            msg: String::new(),
+        };
+        assert_eq!(exit_code(&not_found), 11);
+        let disk_full = TftpError::Remote {
+            code: ErrorCode::DiskFull,
+            msg: String::new(),
+        };
+        assert_eq!(exit_code(&disk_full), 13);
+    }
+
+    #[test]
+    fn failed_get_leaves_no_file() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (_, src) = server.recv_from(&mut buf).unwrap();
+            let reply = Packet::ERROR {
+                code: ErrorCode::FileNotFound,
+                msg: "no such file".into(),
+            };
+            server.send_to(&reply.into_bytes().unwrap(), src).unwrap();
+        });
+
+        let dir = std::env::temp_dir().join(format!("tftp_client_bin_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let local = dir.join("missing.txt");
+        let local = local.to_str().unwrap();
+        let client = TftpClient::new(addr);
+        let err = get_to_file(&client, "missing.txt", local, |_, _| {}).unwrap_err();
+        handle.join().unwrap();
+        assert_eq!(exit_code(&err), 11);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
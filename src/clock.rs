@@ -0,0 +1,96 @@
+//! Sources of time for the server's timeouts.
+//!
+//! Servers use the `SystemClock` by default. A `ManualClock` only moves when
+//! advanced explicitly, so that timeouts and retransmissions can be tested
+//! without waiting for them in real time.
+
+use crate::tftp_proto::IOWaker;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The time source deciding when connection deadlines expire,
+/// and how long transfers took
+pub trait Clock: Clone + Send + Sync + 'static {
+    /// Returns the current time
+    fn now(&self) -> Instant;
+
+    /// Returns how long to wait in real time for the clock to reach `deadline`,
+    /// or `None` if it only gets there through the waker passed to `set_waker`
+    fn wait_until(&self, deadline: Instant) -> Option<Duration>;
+
+    /// Receives the function to call whenever the time changes other than by waiting,
+    /// so that a server checks its deadlines again
+    fn set_waker(&self, _waker: IOWaker) {}
+}
+
+/// The monotonic system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wait_until(&self, deadline: Instant) -> Option<Duration> {
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// A clock that stands still until `advance` is called.
+/// Clones share the same time, so one can be kept to control a server using another
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>,
+}
+
+struct ManualState {
+    now: Instant,
+    wakers: Vec<IOWaker>,
+}
+
+impl Default for ManualState {
+    fn default() -> Self {
+        ManualState {
+            now: Instant::now(),
+            wakers: vec![],
+        }
+    }
+}
+
+impl ManualClock {
+    /// Creates a clock starting at the current time
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Moves the time forward, waking up everything using the clock
+    pub fn advance(&self, duration: Duration) {
+        let wakers = {
+            let mut state = self.state.lock().unwrap();
+            state.now += duration;
+            state.wakers.clone()
+        };
+        for wake in wakers {
+            wake();
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    fn wait_until(&self, deadline: Instant) -> Option<Duration> {
+        if deadline <= self.now() {
+            Some(Duration::from_secs(0))
+        } else {
+            None
+        }
+    }
+
+    fn set_waker(&self, waker: IOWaker) {
+        self.state.lock().unwrap().wakers.push(waker);
+    }
+}
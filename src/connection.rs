@@ -0,0 +1,224 @@
+//! The retransmission and timing state of a connection with a client,
+//! shared by the server implementations regardless of how they drive their sockets.
+//!
+//! It has no sockets or timers of its own: the caller sends the packets it returns,
+//! passes on those received from the client, and calls `expire` once `deadline` passes.
+//! Time is read from a `Clock`, the system one unless given another via `with_clock`.
+//! See the `tftp_proto` module for an example.
+
+use crate::clock::{Clock, SystemClock};
+use crate::packet::{PacketRef, Result};
+use crate::rtt::RttEstimator;
+use crate::tftp_proto::*;
+use log::*;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The state of an ongoing read/write connection with a client,
+/// corresponding to a single read/write transfer
+pub struct ConnectionState<IO: IOAdapter, C: Clock = SystemClock> {
+    /// When the timeout for the last packet expires. Every time a new packet is received,
+    /// the timeout is reset.
+    pub deadline: Instant,
+    /// The protocol state associated with this transfer
+    pub transfer: Transfer<IO>,
+    /// The last packets sent.
+    /// This is useful when packets have to be resent due to timeouts or other errors
+    last_packets: Vec<Vec<u8>>,
+    /// Buffers of packets that were acknowledged, reused for the next ones
+    spare: Vec<Vec<u8>>,
+    /// The address of the client socket to reply to.
+    pub remote: SocketAddr,
+    /// The total number of retransmissions caused by timeouts
+    pub retries: u32,
+    /// When the last packets were sent, if they can be used for measuring
+    /// the round-trip time (i.e. they were not retransmissions)
+    sent_at: Option<Instant>,
+    /// Round-trip time statistics, used for the timeout unless one was negotiated
+    rtt: RttEstimator,
+    /// When the last packet from the client was received
+    last_recv: Instant,
+    /// The idle time until the connection is closed, and the timeout used
+    /// until the round-trip time is known
+    idle_timeout: Duration,
+    /// The source of time for all of the above
+    clock: C,
+}
+
+impl<IO: IOAdapter> ConnectionState<IO> {
+    /// Creates the state for a transfer whose first reply, `packet`, was just sent
+    pub fn new(
+        transfer: Transfer<IO>,
+        packet: &[u8],
+        remote: SocketAddr,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self::with_clock(transfer, packet, remote, idle_timeout, SystemClock)
+    }
+}
+
+impl<IO: IOAdapter, C: Clock> ConnectionState<IO, C> {
+    /// Like `new`, with deadlines following the given clock
+    pub fn with_clock(
+        transfer: Transfer<IO>,
+        packet: &[u8],
+        remote: SocketAddr,
+        idle_timeout: Duration,
+        clock: C,
+    ) -> Self {
+        let now = clock.now();
+        ConnectionState {
+            deadline: now + transfer.timeout().unwrap_or(idle_timeout),
+            transfer,
+            last_packets: vec![packet.to_vec()],
+            spare: vec![],
+            remote,
+            retries: 0,
+            sent_at: Some(now),
+            rtt: Default::default(),
+            last_recv: now,
+            idle_timeout,
+            clock,
+        }
+    }
+
+    /// Returns the smoothed round-trip time measured so far, if any
+    pub fn srtt(&self) -> Option<Duration> {
+        self.rtt.srtt()
+    }
+
+    /// Resets the timeout after packets were sent, or after receiving
+    /// a packet that is ignored but shows the client is still there
+    pub fn reset_timeout(&mut self) {
+        let timeout = match self.transfer.timeout() {
+            Some(negotiated) => self.transfer.retry_timeout(negotiated),
+            None => match self.rtt.rto(self.idle_timeout) {
+                Some(rto) => rto,
+                None => self.transfer.retry_timeout(self.idle_timeout),
+            },
+        };
+        self.deadline = self.clock.now() + timeout;
+    }
+
+    /// Handles a packet received from the client, returning the packets to send in reply
+    pub fn receive(&mut self, packet: PacketRef) -> Result<&[Vec<u8>]> {
+        let now = self.clock.now();
+        self.last_recv = now;
+        if let Some(sent_at) = self.sent_at.take() {
+            self.rtt.sample(now.saturating_duration_since(sent_at));
+        }
+
+        let window = self.transfer.effective_window();
+        let response = match self.transfer.rx_ref(packet) {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("{:?}", e);
+                self.reset_timeout();
+                return Ok(&[]);
+            }
+        };
+        match self.transfer.effective_window() {
+            Some(new_window) if Some(new_window) != window => {
+                debug!("Effective window for {} is now {}", self.remote, new_window)
+            }
+            _ => {}
+        }
+
+        let (sent_packets, repeated) = self.collect(response)?;
+
+        // after the packet was handled, so that the timeout reflects a reset retry count
+        self.reset_timeout();
+        if sent_packets.is_empty() {
+            return Ok(&[]);
+        }
+        if !repeated {
+            self.sent_at = Some(self.clock.now());
+        }
+        let acked = mem::replace(&mut self.last_packets, sent_packets);
+        self.spare.extend(acked);
+        self.transfer.recycle(&mut self.spare);
+        Ok(&self.last_packets)
+    }
+
+    /// Retries file I/O that would have blocked, returning the packets that can now be sent
+    pub fn resume(&mut self) -> Result<&[Vec<u8>]> {
+        let response = self.transfer.resume();
+        let (sent_packets, _) = self.collect(response)?;
+        if sent_packets.is_empty() {
+            return Ok(&[]);
+        }
+        self.reset_timeout();
+        self.sent_at = Some(self.clock.now());
+        // the rest of the window whose start was sent before blocking
+        let start = self.last_packets.len();
+        self.last_packets.extend(sent_packets);
+        Ok(&self.last_packets[start..])
+    }
+
+    /// Serializes the packets to send for `response`, and whether any of them are repeated.
+    /// Repeated packets are moved out of `last_packets`
+    fn collect(&mut self, response: Response) -> Result<(Vec<Vec<u8>>, bool)> {
+        let mut sent_packets = vec![];
+        let mut repeated = false;
+        for item in response {
+            match item {
+                ResponseItem::Done => break,
+                ResponseItem::Packet(packet) => {
+                    let mut bytes = self.spare.pop().unwrap_or_default();
+                    bytes.clear();
+                    packet.append_to(&mut bytes)?;
+                    sent_packets.push(bytes);
+                }
+                ResponseItem::Data(bytes) => sent_packets.push(bytes),
+                ResponseItem::RepeatLast(count) => {
+                    // still unacknowledged, so they may need resending again
+                    let skipped = self.last_packets.len().saturating_sub(count);
+                    sent_packets.extend(self.last_packets.drain(skipped..));
+                    repeated = true;
+                }
+            }
+        }
+        Ok((sent_packets, repeated))
+    }
+
+    /// Handles the expiry of the timeout, returning the packets to resend,
+    /// or `None` if the transfer is over and the connection must be closed
+    pub fn expire(&mut self) -> Result<Option<&[Vec<u8>]>> {
+        // resends driven by the measured RTT come early, so they only count
+        // as retries once the client has been idle for the full timeout
+        let adaptive =
+            self.transfer.timeout().is_none() && self.rtt.rto(self.idle_timeout).is_some();
+        let idle = self.clock.now().saturating_duration_since(self.last_recv);
+        let response = if adaptive && idle < self.idle_timeout {
+            self.transfer.retransmit()
+        } else {
+            self.transfer.timeout_expired()
+        };
+        if response != ResponseItem::Done {
+            self.retries += 1;
+            // the reply could be to either transmission, so it can't be measured
+            self.sent_at = None;
+            self.rtt.backoff();
+        }
+        let resent = match response {
+            ResponseItem::Packet(packet) => {
+                let mut bytes = self.spare.pop().unwrap_or_default();
+                bytes.clear();
+                packet.append_to(&mut bytes)?;
+                self.spare.append(&mut self.last_packets);
+                self.last_packets.push(bytes);
+                0
+            }
+            ResponseItem::Data(bytes) => {
+                self.spare.append(&mut self.last_packets);
+                self.last_packets.push(bytes);
+                0
+            }
+            ResponseItem::RepeatLast(count) => self.last_packets.len().saturating_sub(count),
+            ResponseItem::Done => return Ok(None),
+        };
+        self.reset_timeout();
+        Ok(Some(&self.last_packets[resent..]))
+    }
+}
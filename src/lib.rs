@@ -1,7 +1,25 @@
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod client;
+pub mod clock;
+pub mod connection;
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+mod mmsg;
+#[cfg(any(feature = "mio", feature = "tokio"))]
+mod mtu;
+pub mod multicast;
+mod netascii;
+pub mod offload;
 mod options;
 pub mod packet;
+#[cfg(any(feature = "mio", feature = "tokio"))]
+mod pktinfo;
+pub mod remap;
+#[cfg(any(feature = "mio", feature = "tokio"))]
+mod reuseport;
+mod rtt;
 pub mod server;
-mod tftp_proto;
+pub mod tftp_proto;
 
 #[cfg(test)]
 mod tftp_proto_tests;
@@ -0,0 +1,218 @@
+//! Batched UDP I/O on Linux, available with the `mmsg` cargo feature:
+//! whole windows are sent with a single `sendmmsg`, and listening sockets
+//! are drained with `recvmmsg`. With the `gso` feature, windows of equally sized
+//! packets are instead sent as one UDP GSO buffer, which the kernel segments.
+
+use crate::pktinfo;
+use nix::sys::socket::{
+    recvmmsg, sendmmsg, ControlMessage, MsgFlags, MultiHeaders, SockaddrStorage,
+};
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+/// The most datagrams received at once from a listening socket
+pub const RECV_BATCH: usize = 32;
+
+/// Sends as many of the packets to `remote` as possible with a single system call,
+/// returning how many were sent
+pub fn send_batch<S: AsRawFd>(
+    socket: &S,
+    packets: &[Vec<u8>],
+    remote: SocketAddr,
+) -> io::Result<usize> {
+    if packets.is_empty() {
+        return Ok(0);
+    }
+    #[cfg(feature = "gso")]
+    {
+        if let Some(sent) = gso::send(socket, packets, remote)? {
+            return Ok(sent);
+        }
+    }
+
+    let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(packets.len(), None);
+    let slices = packets
+        .iter()
+        .map(|packet| [IoSlice::new(packet)])
+        .collect::<Vec<_>>();
+    let addrs = vec![Some(SockaddrStorage::from(remote)); packets.len()];
+    let cmsgs: [ControlMessage; 0] = [];
+    let results = sendmmsg(
+        socket.as_raw_fd(),
+        &mut headers,
+        &slices,
+        addrs,
+        cmsgs,
+        MsgFlags::empty(),
+    )?;
+    Ok(results.count())
+}
+
+/// Sends all packets to `remote`, in as few system calls as possible
+pub fn send_all<S: AsRawFd>(socket: &S, packets: &[Vec<u8>], remote: SocketAddr) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < packets.len() {
+        sent += send_batch(socket, &packets[sent..], remote)?;
+    }
+    Ok(())
+}
+
+/// The size of a received datagram unless it was truncated, its source,
+/// and its local destination
+pub type Received = (Option<usize>, SocketAddr, Option<SocketAddr>);
+
+/// Receives as many datagrams as are queued, up to one per buffer, with a single system call.
+/// Returns the size, the source and the local destination (see `pktinfo`) of each,
+/// with no size for those that didn't fit in their buffer
+pub fn recv_batch<S: AsRawFd>(socket: &S, bufs: &mut [Vec<u8>]) -> io::Result<Vec<Received>> {
+    let mut headers =
+        MultiHeaders::<SockaddrStorage>::preallocate(bufs.len(), Some(pktinfo::cmsg_space()));
+    let mut slices = bufs
+        .iter_mut()
+        .map(|buf| [IoSliceMut::new(buf)])
+        .collect::<Vec<_>>();
+    let results = recvmmsg(
+        socket.as_raw_fd(),
+        &mut headers,
+        &mut slices,
+        MsgFlags::MSG_DONTWAIT,
+        None,
+    )?;
+    results
+        .map(|msg| {
+            let (src, dst) = pktinfo::addresses(&msg)?;
+            let truncated = msg.flags.contains(MsgFlags::MSG_TRUNC);
+            Ok(((!truncated).then_some(msg.bytes), src, dst))
+        })
+        .collect()
+}
+
+#[cfg(feature = "gso")]
+mod gso {
+    use nix::errno::Errno;
+    use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags, SockaddrStorage};
+    use std::io::{self, IoSlice};
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// The most segments the kernel accepts in one send
+    const MAX_SEGMENTS: usize = 64;
+    /// The most bytes sent at once, within the limit of a single UDP datagram
+    const MAX_BYTES: usize = 65000;
+
+    /// Cleared once the kernel or the network device turned out not to support GSO
+    static SUPPORTED: AtomicBool = AtomicBool::new(true);
+
+    /// Sends the packets as one buffer segmented by the kernel, returning how many were sent,
+    /// or `None` if they can't be sent this way
+    pub fn send<S: AsRawFd>(
+        socket: &S,
+        packets: &[Vec<u8>],
+        remote: SocketAddr,
+    ) -> io::Result<Option<usize>> {
+        if packets.len() < 2 || !SUPPORTED.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let segment = packets[0].len();
+        if segment == 0 {
+            return Ok(None);
+        }
+        // all segments are as large as the first, except for a shorter last one
+        let max = MAX_SEGMENTS.min(MAX_BYTES / segment).min(packets.len());
+        let mut count = 0;
+        for packet in &packets[..max] {
+            count += 1;
+            if packet.len() != segment {
+                break;
+            }
+        }
+        if count < 2 || packets[count - 1].len() > segment {
+            return Ok(None);
+        }
+
+        let slices = packets[..count]
+            .iter()
+            .map(|packet| IoSlice::new(packet))
+            .collect::<Vec<_>>();
+        let segment = segment as u16;
+        let cmsgs = [ControlMessage::UdpGsoSegments(&segment)];
+        let addr = SockaddrStorage::from(remote);
+        match sendmsg(
+            socket.as_raw_fd(),
+            &slices,
+            &cmsgs,
+            MsgFlags::empty(),
+            Some(&addr),
+        ) {
+            Ok(_) => Ok(Some(count)),
+            Err(Errno::EIO) | Err(Errno::EINVAL) | Err(Errno::ENOPROTOOPT) => {
+                SUPPORTED.store(false, Ordering::Relaxed);
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, UdpSocket};
+    use std::time::Duration;
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind((IpAddr::from([127, 0, 0, 1]), 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        socket
+    }
+
+    #[test]
+    fn send_window() {
+        let (tx, rx) = (socket(), socket());
+        let mut packets = (0..10u8).map(|i| vec![i; 516]).collect::<Vec<_>>();
+        packets.push(vec![10; 7]);
+        send_all(&tx, &packets, rx.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0; 1024];
+        for packet in &packets {
+            let amt = rx.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..amt], &packet[..]);
+        }
+    }
+
+    #[test]
+    fn receive_queued() {
+        let (tx, rx) = (socket(), socket());
+        let addr = rx.local_addr().unwrap();
+        for i in 0..3u8 {
+            tx.send_to(&[i; 3], addr).unwrap();
+        }
+        // loopback datagrams are queued as soon as they are sent
+        let mut bufs = vec![vec![0; 16]; RECV_BATCH];
+        let received = recv_batch(&rx, &mut bufs).unwrap();
+        assert_eq!(received.len(), 3);
+        let received = received
+            .iter()
+            .zip(&bufs)
+            .map(|(&(amt, src, _), buf)| {
+                assert_eq!(src, tx.local_addr().unwrap());
+                buf[..amt.unwrap()].to_vec()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![vec![0; 3], vec![1; 3], vec![2; 3]]);
+    }
+
+    #[test]
+    fn receive_truncated() {
+        let (tx, rx) = (socket(), socket());
+        tx.send_to(&[1; 17], rx.local_addr().unwrap()).unwrap();
+        let mut bufs = vec![vec![0; 16]; RECV_BATCH];
+        let received = recv_batch(&rx, &mut bufs).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, None);
+    }
+}
@@ -0,0 +1,104 @@
+//! Detection of the largest block size that fits in a single IP packet,
+//! so that DATA packets never get fragmented.
+
+use crate::packet::MAX_BLOCKSIZE;
+use std::net::IpAddr;
+
+/// The MTU assumed when the interface can't be determined (that of Ethernet)
+pub const DEFAULT_MTU: u32 = 1500;
+
+const IPV4_HEADER: u32 = 20;
+const IPV6_HEADER: u32 = 40;
+const UDP_HEADER: u32 = 8;
+/// Opcode and block number
+const DATA_HEADER: u32 = 4;
+
+/// Returns the largest block size for which a DATA packet fits in the given MTU
+pub fn blocksize_for_mtu(mtu: u32, ip: IpAddr) -> u16 {
+    let ip_header = match ip {
+        IpAddr::V4(_) => IPV4_HEADER,
+        IpAddr::V6(_) => IPV6_HEADER,
+    };
+    let size = mtu.saturating_sub(ip_header + UDP_HEADER + DATA_HEADER);
+    size.min(u32::from(MAX_BLOCKSIZE)).max(8) as u16
+}
+
+/// Returns the largest block size that avoids fragmentation for a socket bound to `ip`.
+/// For unspecified addresses the smallest MTU of all interfaces of that family is used
+pub fn max_blocksize(ip: IpAddr) -> u16 {
+    blocksize_for_mtu(interface_mtu(ip).unwrap_or(DEFAULT_MTU), ip)
+}
+
+#[cfg(unix)]
+fn interface_mtu(ip: IpAddr) -> Option<u32> {
+    use nix::ifaddrs::getifaddrs;
+    use nix::net::if_::InterfaceFlags;
+
+    let mut names = getifaddrs()
+        .ok()?
+        .filter(|ifaddr| {
+            let addr = match ifaddr.address {
+                Some(ref addr) => addr,
+                None => return false,
+            };
+            let if_ip = if let Some(sin) = addr.as_sockaddr_in() {
+                IpAddr::V4(sin.ip())
+            } else if let Some(sin6) = addr.as_sockaddr_in6() {
+                IpAddr::V6(sin6.ip())
+            } else {
+                return false;
+            };
+            if ip.is_unspecified() {
+                // any interface of the same family, except loopback which is never the bottleneck
+                ip.is_ipv4() == if_ip.is_ipv4()
+                    && !ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+            } else {
+                if_ip == ip
+            }
+        })
+        .map(|ifaddr| ifaddr.interface_name)
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names.iter().filter_map(|name| read_mtu(name)).min()
+}
+
+#[cfg(unix)]
+fn read_mtu(interface: &str) -> Option<u32> {
+    let path = format!("/sys/class/net/{}/mtu", interface);
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(not(unix))]
+fn interface_mtu(_: IpAddr) -> Option<u32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_by_family() {
+        let v4 = IpAddr::from([192, 168, 0, 1]);
+        let v6 = IpAddr::from([0xfe80, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(blocksize_for_mtu(1500, v4), 1468);
+        assert_eq!(blocksize_for_mtu(1500, v6), 1448);
+        assert_eq!(blocksize_for_mtu(9000, v4), 8968);
+    }
+
+    #[test]
+    fn limits() {
+        let v4 = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(blocksize_for_mtu(65536, v4), MAX_BLOCKSIZE);
+        assert_eq!(blocksize_for_mtu(20, v4), 8);
+    }
+
+    #[test]
+    fn detected_size_in_range() {
+        for &ip in &[IpAddr::from([127, 0, 0, 1]), IpAddr::from([0; 4])] {
+            let size = max_blocksize(ip);
+            assert!((8..=MAX_BLOCKSIZE).contains(&size), "{} for {}", size, ip);
+        }
+    }
+}
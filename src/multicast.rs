@@ -0,0 +1,431 @@
+//! Reads sent to many clients at once through a multicast group, as described in
+//! [RFC 2090](https://tools.ietf.org/html/rfc2090).
+//!
+//! A `MulticastSession` sends a file to a group on behalf of all the clients reading it
+//! with the `multicast` option. Only the master client, the first one, acknowledges the data.
+//! Others may join at any time, keeping the blocks they receive meanwhile. Once the master
+//! client has the whole file, the next client becomes master via another OACK, and
+//! acknowledges the last block it has in sequence, after which the file is sent again.
+//! Like `connection::ConnectionState`, it has no sockets or timers of its own.
+//!
+//! The block number of an ACK repositioning the transfer is taken as is, so files with
+//! more than 65535 blocks can only be read by clients that were there from the start.
+
+use crate::clock::{Clock, SystemClock};
+use crate::packet::{ErrorCode, MulticastGroup, Packet, PacketRef, Result, TftpOption};
+use crate::tftp_proto::*;
+use log::*;
+use std::collections::VecDeque;
+use std::mem;
+#[cfg(feature = "mio")]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::result;
+use std::time::{Duration, Instant};
+
+/// Serialized packets to send, each with its destination
+pub type Datagrams = Vec<(SocketAddr, Vec<u8>)>;
+
+/// Checks whether a request is a read with the `multicast` option
+pub fn requested(request: &Packet) -> bool {
+    match *request {
+        Packet::RRQ { ref options, .. } => options
+            .iter()
+            .any(|opt| matches!(*opt, TftpOption::Multicast(_))),
+        _ => false,
+    }
+}
+
+/// A file being sent to a multicast group, and the clients reading it
+pub struct MulticastSession<IO: IOAdapter, C: Clock = SystemClock> {
+    /// When the master client times out
+    pub deadline: Instant,
+    /// The group the file is sent to
+    pub group: SocketAddr,
+    /// The clients reading the file, the first one being the master client
+    clients: VecDeque<SocketAddr>,
+    /// Whether the master client didn't acknowledge the OACK making it master yet
+    promoted: bool,
+    /// The read currently driven by the master client
+    transfer: Transfer<IO>,
+    /// The request that started the session, repeated to read the file again
+    request: Packet,
+    /// The requested path once remapped, which joining requests must be remapped to as well
+    file: String,
+    ctx: RequestCtx,
+    /// The options acknowledged to every client, besides the multicast one
+    options: Vec<TftpOption>,
+    /// The last packets sent to the group, resent if the master client doesn't reply
+    last_packets: Vec<Vec<u8>>,
+    /// The number of retransmissions since the master client last replied
+    retries: u32,
+    /// The number of consecutive retransmissions before the master client is dropped
+    max_retries: u32,
+    /// The time to wait for the master client
+    timeout: Duration,
+    /// The source of time for the deadline
+    clock: C,
+}
+
+/// The packets produced by a step of the transfer
+enum Collected {
+    /// Packets for the group, and whether the master client has the whole file
+    Sent(Vec<Vec<u8>>, bool),
+    /// The transfer failed with this error
+    Failed(Packet),
+}
+
+impl<IO: IOAdapter> MulticastSession<IO> {
+    /// Starts sending the file read by `request` to `group`, with `remote` as the master client.
+    /// Returns the session, if the file can be read, and the reply to `remote`,
+    /// which must come from the socket the session sends from
+    pub fn new(
+        proto: &mut TftpServerProto<IO>,
+        request: Packet,
+        ctx: &RequestCtx,
+        group: SocketAddr,
+        remote: SocketAddr,
+        idle_timeout: Duration,
+    ) -> (Option<Self>, result::Result<Packet, TftpError>) {
+        Self::with_clock(
+            proto,
+            request,
+            ctx,
+            group,
+            remote,
+            idle_timeout,
+            SystemClock,
+        )
+    }
+}
+
+impl<IO: IOAdapter, C: Clock> MulticastSession<IO, C> {
+    /// Like `new`, with deadlines following the given clock
+    pub fn with_clock(
+        proto: &mut TftpServerProto<IO>,
+        request: Packet,
+        ctx: &RequestCtx,
+        group: SocketAddr,
+        remote: SocketAddr,
+        idle_timeout: Duration,
+        clock: C,
+    ) -> (Option<Self>, result::Result<Packet, TftpError>) {
+        if !requested(&request) {
+            return (None, Err(TftpError::NotInitiatingPacket));
+        }
+        let ctx = RequestCtx {
+            multicast: Some(group),
+            ..ctx.clone()
+        };
+        // a denied path is answered by the protocol
+        let file = remapped(proto, &request, &ctx);
+        let (transfer, options, file) =
+            match (proto.rx_initial_with_ctx(request.clone(), &ctx), file) {
+                ((Some(transfer), Ok(Packet::OACK { options })), Some(file)) => {
+                    (transfer, options, file)
+                }
+                ((_, reply), _) => return (None, reply),
+            };
+        let options = options
+            .into_iter()
+            .filter(|opt| !matches!(*opt, TftpOption::Multicast(_)))
+            .collect();
+        let timeout = transfer.timeout().unwrap_or(idle_timeout);
+
+        let session = MulticastSession {
+            deadline: clock.now() + timeout,
+            group,
+            clients: VecDeque::from(vec![remote]),
+            promoted: true,
+            transfer,
+            request,
+            file,
+            ctx,
+            options,
+            last_packets: vec![],
+            retries: 0,
+            max_retries: proto.transfer_cfg().max_retries,
+            timeout,
+            clock,
+        };
+        let reply = session.oack(true);
+        (Some(session), Ok(reply))
+    }
+
+    /// Checks whether a request reads the file the same way as the one starting the session,
+    /// so that its client can join. The requested paths are compared once remapped
+    /// by `proto`, for the client of each request
+    pub fn accepts(&self, proto: &TftpServerProto<IO>, request: &Packet, ctx: &RequestCtx) -> bool {
+        match (request, &self.request) {
+            (
+                Packet::RRQ { mode, options, .. },
+                Packet::RRQ {
+                    mode: m,
+                    options: o,
+                    ..
+                },
+            ) => {
+                mode == m
+                    && options == o
+                    && remapped(proto, request, ctx).as_ref() == Some(&self.file)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the number of clients reading the file
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Checks whether all clients are gone, so that the session must be closed
+    pub fn is_done(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Checks whether the transfer waits for its reader, see `Transfer::is_blocked`
+    pub fn is_blocked(&self) -> bool {
+        self.transfer.is_blocked()
+    }
+
+    /// Adds a client that requested the file, returning the OACK to send it.
+    /// It only becomes master once the clients before it are done
+    pub fn join(&mut self, remote: SocketAddr) -> Result<Datagrams> {
+        // a repeated request gets the same reply
+        if !self.clients.contains(&remote) {
+            self.clients.push_back(remote);
+        }
+        let master = self.clients.front() == Some(&remote);
+        Ok(vec![(remote, self.oack(master).into_bytes()?)])
+    }
+
+    /// Handles a packet received from `remote`, returning the packets to send in reply.
+    /// The file is opened again through `proto` if the master client needs earlier blocks
+    pub fn receive(
+        &mut self,
+        proto: &mut TftpServerProto<IO>,
+        remote: SocketAddr,
+        packet: PacketRef,
+    ) -> Result<Datagrams> {
+        if !self.clients.contains(&remote) {
+            return Ok(vec![(
+                remote,
+                Packet::from(ErrorCode::UnknownID).into_bytes()?,
+            )]);
+        }
+        let master = self.clients.front() == Some(&remote);
+        match packet {
+            PacketRef::ACK(block) if master => self.master_ack(proto, block),
+            PacketRef::ERROR { code, msg } => {
+                // the client leaves the group
+                self.clients.retain(|&client| client != remote);
+                if master {
+                    // the next master client reads the file again
+                    self.transfer
+                        .abort(AbortReason::ClientError(code, msg.to_owned()));
+                    self.promote()
+                } else {
+                    Ok(vec![])
+                }
+            }
+            // only the master client acknowledges data
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Retries file I/O that would have blocked, returning the packets that can now be sent
+    pub fn resume(&mut self) -> Result<Datagrams> {
+        let response = self.transfer.resume();
+        match self.collect(response)? {
+            Collected::Sent(packets, _) => {
+                let start = self.last_packets.len();
+                self.last_packets.extend(packets);
+                Ok(self.to_group(start))
+            }
+            Collected::Failed(error) => self.abort(error),
+        }
+    }
+
+    /// Handles the expiry of the timeout, returning the packets to resend,
+    /// or `None` if no client is left and the session must be closed
+    pub fn expire(&mut self) -> Result<Option<Datagrams>> {
+        self.retries += 1;
+        let packets = if self.retries > self.max_retries {
+            if let Some(master) = self.clients.pop_front() {
+                info!("Master client {} of {} timed out", master, self.group);
+                self.transfer.abort(AbortReason::Timeout);
+            }
+            self.promote()?
+        } else if self.promoted {
+            match self.clients.front() {
+                Some(&master) => vec![(master, self.oack(true).into_bytes()?)],
+                None => vec![],
+            }
+        } else {
+            match self.transfer.retransmit() {
+                ResponseItem::RepeatLast(count) => {
+                    self.to_group(self.last_packets.len().saturating_sub(count))
+                }
+                _ => vec![],
+            }
+        };
+        if self.is_done() {
+            return Ok(None);
+        }
+        self.deadline = self.clock.now() + self.timeout;
+        Ok(Some(packets))
+    }
+
+    /// Continues the transfer after the block acknowledged by the master client
+    fn master_ack(&mut self, proto: &mut TftpServerProto<IO>, block: u16) -> Result<Datagrams> {
+        if !self.transfer.expects_ack(block) {
+            // a new master client, missing blocks sent before it joined
+            debug!(
+                "Multicast to {} continues after block {}",
+                self.group, block
+            );
+            match proto.rx_initial_with_ctx(self.request.clone(), &self.ctx) {
+                (Some(mut transfer), _) => {
+                    transfer.skip_to(block);
+                    self.transfer = transfer;
+                }
+                // still opening the file, so this waits for the master client
+                // to repeat its ACK after the next retransmission
+                (None, Err(TftpError::WouldBlock)) => return Ok(vec![]),
+                _ => return self.abort(ErrorCode::FileNotFound.into()),
+            }
+            self.last_packets.clear();
+        }
+        self.retries = 0;
+        self.promoted = false;
+        self.deadline = self.clock.now() + self.timeout;
+
+        let response = match self.transfer.rx_ref(PacketRef::ACK(block)) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("{:?}", e);
+                return Ok(vec![]);
+            }
+        };
+        match self.collect(response)? {
+            Collected::Sent(_, true) => {
+                // the master client has the whole file
+                self.clients.pop_front();
+                self.promote()
+            }
+            Collected::Sent(packets, false) => {
+                if packets.is_empty() {
+                    return Ok(vec![]);
+                }
+                let mut acked = mem::replace(&mut self.last_packets, packets);
+                self.transfer.recycle(&mut acked);
+                Ok(self.to_group(0))
+            }
+            Collected::Failed(error) => self.abort(error),
+        }
+    }
+
+    /// Serializes the packets of `response`, moving repeated ones out of `last_packets`
+    fn collect(&mut self, response: Response) -> Result<Collected> {
+        let mut packets = vec![];
+        for item in response {
+            match item {
+                ResponseItem::Packet(error @ Packet::ERROR { .. }) => {
+                    return Ok(Collected::Failed(error))
+                }
+                ResponseItem::Packet(packet) => packets.push(packet.into_bytes()?),
+                ResponseItem::Data(bytes) => packets.push(bytes),
+                ResponseItem::RepeatLast(count) => {
+                    let skipped = self.last_packets.len().saturating_sub(count);
+                    packets.extend(self.last_packets.drain(skipped..));
+                }
+                ResponseItem::Done => return Ok(Collected::Sent(packets, true)),
+            }
+        }
+        Ok(Collected::Sent(packets, false))
+    }
+
+    /// Makes the next client master, if any, with an OACK it must acknowledge
+    fn promote(&mut self) -> Result<Datagrams> {
+        self.retries = 0;
+        self.deadline = self.clock.now() + self.timeout;
+        match self.clients.front() {
+            Some(&master) => {
+                info!("{} is now the master client of {}", master, self.group);
+                self.promoted = true;
+                Ok(vec![(master, self.oack(true).into_bytes()?)])
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Ends the session, sending the error to every client
+    fn abort(&mut self, error: Packet) -> Result<Datagrams> {
+        let bytes = error.into_bytes()?;
+        Ok(mem::take(&mut self.clients)
+            .into_iter()
+            .map(|client| (client, bytes.clone()))
+            .collect())
+    }
+
+    /// Returns the OACK for a client
+    fn oack(&self, master: bool) -> Packet {
+        let mut options = self.options.clone();
+        options.push(TftpOption::Multicast(Some(MulticastGroup {
+            addr: self.group,
+            master,
+        })));
+        Packet::OACK { options }
+    }
+
+    /// Returns the last packets sent to the group, starting at the given one
+    fn to_group(&self, start: usize) -> Datagrams {
+        self.last_packets[start..]
+            .iter()
+            .map(|packet| (self.group, packet.clone()))
+            .collect()
+    }
+}
+
+/// Returns the path a read request is served from, as remapped for its client,
+/// or `None` if it is denied
+fn remapped<IO: IOAdapter>(
+    proto: &TftpServerProto<IO>,
+    request: &Packet,
+    ctx: &RequestCtx,
+) -> Option<String> {
+    match *request {
+        Packet::RRQ { ref filename, .. } => proto.remap(filename, false, ctx.client),
+        _ => None,
+    }
+}
+
+/// The multicast groups not used by any session of a server
+#[cfg(feature = "mio")]
+#[derive(Default)]
+pub(crate) struct GroupPool {
+    free: Vec<SocketAddr>,
+}
+
+#[cfg(feature = "mio")]
+impl GroupPool {
+    pub(crate) fn new(groups: &[SocketAddr]) -> Self {
+        GroupPool {
+            free: groups.to_vec(),
+        }
+    }
+
+    /// Takes a free group of the same address family as `local`
+    pub(crate) fn take(&mut self, local: IpAddr) -> Option<SocketAddr> {
+        let i = self
+            .free
+            .iter()
+            .position(|group| group.is_ipv4() == local.is_ipv4())?;
+        Some(self.free.remove(i))
+    }
+
+    /// Makes a group available again once its session is closed
+    pub(crate) fn release(&mut self, group: SocketAddr) {
+        self.free.push(group);
+    }
+}
@@ -0,0 +1,332 @@
+//! Translation between local text files and the netascii transfer format
+//! described in [RFC 764](https://tools.ietf.org/html/rfc764).
+//!
+//! On the wire every line ends with CR LF, and a bare CR is sent as CR NUL.
+//! Files are assumed to use plain LF line endings locally.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUL: u8 = 0;
+
+/// Wraps a reader of local file contents and yields them encoded as netascii
+#[derive(Debug)]
+pub struct NetasciiReader<R> {
+    inner: BufReader<R>,
+    /// Second byte of a translated sequence that didn't fit in the last read
+    pending: Option<u8>,
+}
+
+impl<R: Read> NetasciiReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            pending: None,
+        }
+    }
+}
+
+impl<R: Read> Read for NetasciiReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        let mut n = 0;
+        if let Some(b) = self.pending.take() {
+            out[0] = b;
+            n = 1;
+        }
+
+        let avail = match self.inner.fill_buf() {
+            Ok(avail) => avail,
+            // report what we have so far, the error will come up again on the next read
+            Err(_) if n > 0 => return Ok(n),
+            Err(e) => return Err(e),
+        };
+
+        let mut used = 0;
+        for &b in avail {
+            if n == out.len() {
+                break;
+            }
+            used += 1;
+            let (first, second) = match b {
+                LF => (CR, Some(LF)),
+                CR => (CR, Some(NUL)),
+                _ => (b, None),
+            };
+            out[n] = first;
+            n += 1;
+            if let Some(second) = second {
+                if n == out.len() {
+                    self.pending = Some(second);
+                    break;
+                }
+                out[n] = second;
+                n += 1;
+            }
+        }
+        self.inner.consume(used);
+        Ok(n)
+    }
+}
+
+/// Wraps a writer of local file contents, decoding the netascii data written into it
+#[derive(Debug)]
+pub struct NetasciiWriter<W> {
+    inner: W,
+    /// The previous chunk ended in a CR, so its meaning depends on the next byte
+    pending_cr: bool,
+    /// Decoded data not accepted by the inner writer yet, because it would block
+    unwritten: Vec<u8>,
+}
+
+impl<W: Write> NetasciiWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending_cr: false,
+            unwritten: vec![],
+        }
+    }
+
+    /// Writes out any CR left dangling at the end of the data and flushes the inner writer.
+    /// Must be called once after the last chunk of the transfer was written,
+    /// and again if it fails with `io::ErrorKind::WouldBlock`.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.pending_cr {
+            self.pending_cr = false;
+            self.unwritten.push(CR);
+        }
+        self.flush()
+    }
+
+    /// Passes the data decoded earlier to the inner writer
+    fn write_unwritten(&mut self) -> io::Result<()> {
+        while !self.unwritten.is_empty() {
+            match self.inner.write(&self.unwritten) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.unwritten.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for NetasciiWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // nothing of `buf` is consumed while the inner writer would block
+        self.write_unwritten()?;
+        let decoded = &mut self.unwritten;
+        for &b in buf {
+            if self.pending_cr {
+                self.pending_cr = false;
+                match b {
+                    LF => decoded.push(LF),
+                    NUL => decoded.push(CR),
+                    CR => {
+                        // malformed bare CR, keep it and look at what follows the next one
+                        decoded.push(CR);
+                        self.pending_cr = true;
+                    }
+                    _ => decoded.extend_from_slice(&[CR, b]),
+                }
+            } else if b == CR {
+                self.pending_cr = true;
+            } else {
+                decoded.push(b);
+            }
+        }
+        match self.write_unwritten() {
+            // already decoded, so it's written by the next call
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            result => result?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_unwritten()?;
+        self.inner.flush()
+    }
+}
+
+/// Reads file contents in the format required by the transfer mode
+#[derive(Debug)]
+pub(crate) enum ModeReader<R: Read> {
+    Octet(R),
+    Netascii(NetasciiReader<R>),
+}
+
+impl<R: Read> Read for ModeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ModeReader::Octet(ref mut r) => r.read(buf),
+            ModeReader::Netascii(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+/// Writes file contents received in the format required by the transfer mode
+#[derive(Debug)]
+pub(crate) enum ModeWriter<W: Write> {
+    Octet(W),
+    Netascii(NetasciiWriter<W>),
+}
+
+impl<W: Write> ModeWriter<W> {
+    /// Returns the writer of the file
+    pub fn get_mut(&mut self) -> &mut W {
+        match *self {
+            ModeWriter::Octet(ref mut w) => w,
+            ModeWriter::Netascii(ref mut w) => &mut w.inner,
+        }
+    }
+
+    /// Completes the written data after the last block has been received
+    pub fn finish(&mut self) -> io::Result<()> {
+        match *self {
+            ModeWriter::Octet(ref mut w) => w.flush(),
+            ModeWriter::Netascii(ref mut w) => w.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for ModeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ModeWriter::Octet(ref mut w) => w.write(buf),
+            ModeWriter::Netascii(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ModeWriter::Octet(ref mut w) => w.flush(),
+            ModeWriter::Netascii(ref mut w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(input: &[u8], chunk: usize) -> Vec<u8> {
+        let mut reader = NetasciiReader::new(input);
+        let mut v = vec![];
+        let mut buf = vec![0; chunk];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            v.extend_from_slice(&buf[..n]);
+        }
+        v
+    }
+
+    fn decode(input: &[u8], chunk: usize) -> Vec<u8> {
+        let mut v = vec![];
+        {
+            let mut writer = NetasciiWriter::new(&mut v);
+            for c in input.chunks(chunk) {
+                writer.write_all(c).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        v
+    }
+
+    #[test]
+    fn encode_line_endings() {
+        assert_eq!(encode(b"ab\ncd\n", 100), b"ab\r\ncd\r\n");
+        assert_eq!(encode(b"a\rb", 100), b"a\r\0b");
+        assert_eq!(encode(b"", 100), b"");
+    }
+
+    #[test]
+    fn encode_split_sequences() {
+        let input = b"\n\r\nab\r\r\n\n";
+        let whole = encode(input, 100);
+        assert_eq!(whole, b"\r\n\r\0\r\nab\r\0\r\0\r\n\r\n");
+        for chunk in 1..whole.len() {
+            assert_eq!(encode(input, chunk), whole, "chunk size {}", chunk);
+        }
+    }
+
+    #[test]
+    fn decode_line_endings() {
+        assert_eq!(decode(b"ab\r\ncd\r\n", 100), b"ab\ncd\n");
+        assert_eq!(decode(b"a\r\0b", 100), b"a\rb");
+        assert_eq!(decode(b"", 100), b"");
+    }
+
+    #[test]
+    fn decode_split_sequences() {
+        let input = b"\r\n\r\0\r\nab\r\0\r\0\r\n\r\n";
+        for chunk in 1..input.len() {
+            assert_eq!(
+                decode(input, chunk),
+                b"\n\r\nab\r\r\n\n",
+                "chunk size {}",
+                chunk
+            );
+        }
+    }
+
+    /// Accepts a single byte per write, and would block on every other call
+    struct SlowWriter {
+        data: Vec<u8>,
+        block: bool,
+    }
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.block = !self.block;
+            if self.block {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.data.extend_from_slice(&buf[..1]);
+            Ok(1)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn retry(mut f: impl FnMut() -> io::Result<()>) {
+        loop {
+            match f() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_to_blocking_writer() {
+        let input = b"ab\r\ncd\r\0\r";
+        let mut writer = NetasciiWriter::new(SlowWriter {
+            data: vec![],
+            block: false,
+        });
+        for c in input.chunks(3) {
+            retry(|| writer.write(c).map(|n| assert_eq!(n, c.len())));
+        }
+        retry(|| writer.finish());
+        assert_eq!(writer.inner.data, b"ab\ncd\r\r");
+    }
+
+    #[test]
+    fn decode_malformed_cr() {
+        assert_eq!(decode(b"a\rb", 1), b"a\rb");
+        assert_eq!(decode(b"a\r\r\n", 1), b"a\r\n");
+        assert_eq!(decode(b"a\r", 1), b"a\r");
+    }
+}
@@ -0,0 +1,636 @@
+//! An `IOAdapter` running the file I/O of another one on a pool of worker threads,
+//! so that slow storage only delays the transfers using it, not the whole server.
+//!
+//! Files are looked up, opened and created by the workers too, and reads are prefetched a chunk
+//! ahead while writes are queued and written behind, up to committing the file.
+//! Until the workers catch up, all of these fail with `io::ErrorKind::WouldBlock`,
+//! and the server is woken up once they can make progress.
+
+use crate::netascii::NetasciiReader;
+use crate::tftp_proto::{Commit, IOAdapter, IOWaker, WritePolicy};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+/// The number of worker threads used by `OffloadAdapter::default`
+pub const DEFAULT_THREADS: usize = 4;
+/// How much data is read ahead, or queued for writing, per file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The outcome of the background operations on each file, kept until the request
+/// needing it is handled again. `None` while a worker is still busy with it
+type Pending<T> = Arc<Mutex<HashMap<PathBuf, Option<T>>>>;
+/// An opened file and its size, as returned by `IOAdapter::open_read`
+type Opened<R> = (OffloadReader<R>, Option<u64>);
+
+/// Wraps an `IOAdapter`, moving the opening, reads and writes of its files to worker threads
+pub struct OffloadAdapter<IO: IOAdapter> {
+    io: Arc<RwLock<IO>>,
+    jobs: Sender<Job>,
+    waker: IOWaker,
+    reads: Pending<io::Result<Opened<IO::R>>>,
+    creates: Pending<io::Result<OffloadWriter<IO::W>>>,
+    netascii_sizes: Pending<Option<u64>>,
+    lookups: Pending<io::Result<PathBuf>>,
+}
+
+impl<IO: IOAdapter> OffloadAdapter<IO> {
+    /// Creates an adapter running the I/O of `io` on `threads` worker threads,
+    /// which exit once the adapter and all its files are dropped
+    pub fn new(io: IO, threads: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads.max(1) {
+            let rx = Arc::clone(&rx);
+            thread::spawn(move || loop {
+                let job = match rx.lock() {
+                    Ok(rx) => rx.recv(),
+                    Err(_) => break,
+                };
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            });
+        }
+        OffloadAdapter {
+            io: Arc::new(RwLock::new(io)),
+            jobs,
+            waker: Arc::new(|| {}),
+            reads: Default::default(),
+            creates: Default::default(),
+            netascii_sizes: Default::default(),
+            lookups: Default::default(),
+        }
+    }
+
+    /// Returns the outcome of `op` for `file` once a worker ran it, starting it if needed.
+    /// Until then, fails with `io::ErrorKind::WouldBlock`
+    fn in_background<T, F>(&self, pending: &Pending<T>, file: &Path, op: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&RwLock<IO>, &Path) -> T + Send + 'static,
+        IO: Send + Sync + 'static,
+    {
+        let mut outcomes = pending.lock().map_err(|_| lost_worker())?;
+        match outcomes.remove(file) {
+            Some(Some(outcome)) => return Ok(outcome),
+            Some(None) => {
+                outcomes.insert(file.to_owned(), None);
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            None => {}
+        }
+        outcomes.insert(file.to_owned(), None);
+        let io = Arc::clone(&self.io);
+        let shared = Arc::clone(pending);
+        let waker = Arc::clone(&self.waker);
+        let file = file.to_owned();
+        let _ = self.jobs.send(Box::new(move || {
+            let outcome = op(&io, &file);
+            if let Ok(mut outcomes) = shared.lock() {
+                outcomes.insert(file, Some(outcome));
+            }
+            waker();
+        }));
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    /// Forgets the lookups that found `file`, once the request needing them is handled
+    fn forget_lookups(&self, file: &Path) {
+        if let Ok(mut lookups) = self.lookups.lock() {
+            lookups.retain(|_, found| !matches!(found, Some(Ok(path)) if path == file));
+        }
+    }
+}
+
+impl<IO: IOAdapter + Default> Default for OffloadAdapter<IO> {
+    fn default() -> Self {
+        Self::new(IO::default(), DEFAULT_THREADS)
+    }
+}
+
+impl<IO> IOAdapter for OffloadAdapter<IO>
+where
+    IO: IOAdapter + Send + Sync + 'static,
+    IO::R: Send + 'static,
+    IO::W: Send + 'static,
+{
+    type R = OffloadReader<IO::R>;
+    type W = OffloadWriter<IO::W>;
+
+    fn open_read(&self, file: &Path) -> io::Result<(Self::R, Option<u64>)> {
+        let jobs = self.jobs.clone();
+        let waker = Arc::clone(&self.waker);
+        let opened = self.in_background(&self.reads, file, move |io, file| {
+            let (mut inner, len) = io.read().map_err(|_| lost_worker())?.open_read(file)?;
+            let mut buffered = vec![];
+            let eof = read_chunk(&mut inner, &mut buffered)?;
+            let state = ReadState {
+                inner: Some(inner),
+                buffered,
+                consumed: 0,
+                eof,
+                error: None,
+                busy: false,
+            };
+            let reader = OffloadReader {
+                state: Arc::new(Mutex::new(state)),
+                jobs,
+                waker,
+            };
+            Ok((reader, len))
+        })?;
+        // the request that needed the size is handled now
+        if let Ok(mut sizes) = self.netascii_sizes.lock() {
+            sizes.remove(file);
+        }
+        self.forget_lookups(file);
+        opened
+    }
+
+    fn netascii_size(&self, file: &Path) -> io::Result<Option<u64>> {
+        let size = self.in_background(&self.netascii_sizes, file, |io, file| {
+            let (fread, _) = io.read().ok()?.open_read(file).ok()?;
+            io::copy(&mut NetasciiReader::new(fread), &mut io::sink()).ok()
+        })?;
+        // kept until the file is opened, which might have to be retried in turn
+        let mut sizes = self.netascii_sizes.lock().map_err(|_| lost_worker())?;
+        sizes.insert(file.to_owned(), Some(size));
+        Ok(size)
+    }
+
+    fn create_new(&mut self, file: &Path, len: Option<u64>) -> io::Result<Self::W> {
+        self.create(file, len, WritePolicy::Never)
+    }
+
+    fn create(
+        &mut self,
+        file: &Path,
+        len: Option<u64>,
+        policy: WritePolicy,
+    ) -> io::Result<Self::W> {
+        let jobs = self.jobs.clone();
+        let waker = Arc::clone(&self.waker);
+        let created = self.in_background(&self.creates, file, move |io, file| {
+            let inner = io
+                .write()
+                .map_err(|_| lost_worker())?
+                .create(file, len, policy)?;
+            let state = WriteState {
+                inner: Some(inner),
+                queued: vec![],
+                dirty: false,
+                flush: false,
+                commit: false,
+                committed: false,
+                error: None,
+                busy: false,
+            };
+            Ok(OffloadWriter {
+                state: Arc::new(Mutex::new(state)),
+                jobs,
+                waker,
+            })
+        })?;
+        self.forget_lookups(file);
+        created
+    }
+
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<OsString>> {
+        self.io.read().map_err(|_| lost_worker())?.list_dir(dir)
+    }
+
+    fn find_ignoring_case(&self, dir: &Path, file: &Path) -> io::Result<PathBuf> {
+        let requested = dir.join(file);
+        let (dir, file) = (dir.to_owned(), file.to_owned());
+        let found = self.in_background(&self.lookups, &requested, move |io, _| {
+            io.read()
+                .map_err(|_| lost_worker())?
+                .find_ignoring_case(&dir, &file)
+        })?;
+        // kept until the file is opened or created, which might have to be retried in turn
+        if let Ok(ref path) = found {
+            let mut lookups = self.lookups.lock().map_err(|_| lost_worker())?;
+            lookups.insert(requested, Some(Ok(path.clone())));
+        }
+        found
+    }
+
+    fn set_waker(&mut self, waker: IOWaker) {
+        self.waker = Arc::clone(&waker);
+        if let Ok(mut io) = self.io.write() {
+            io.set_waker(waker);
+        }
+    }
+}
+
+/// Reads up to a chunk, returning whether the end of the file was reached
+fn read_chunk<R: Read>(inner: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let n = inner.by_ref().take(CHUNK_SIZE as u64).read_to_end(buf)?;
+    Ok(n < CHUNK_SIZE)
+}
+
+fn lost_worker() -> io::Error {
+    io::Error::other("I/O worker thread panicked")
+}
+
+struct ReadState<R> {
+    /// The wrapped reader, unless a worker is using it
+    inner: Option<R>,
+    buffered: Vec<u8>,
+    /// How much of `buffered` was already read
+    consumed: usize,
+    eof: bool,
+    error: Option<io::Error>,
+    /// Whether a worker is reading the next chunk
+    busy: bool,
+}
+
+/// Reads a file prefetched by the worker threads of an `OffloadAdapter`
+pub struct OffloadReader<R> {
+    state: Arc<Mutex<ReadState<R>>>,
+    jobs: Sender<Job>,
+    waker: IOWaker,
+}
+
+impl<R: Read + Send + 'static> OffloadReader<R> {
+    /// Starts reading the next chunk on a worker, if not already done
+    fn prefetch(&self, state: &mut ReadState<R>) {
+        if state.busy || state.eof || state.error.is_some() {
+            return;
+        }
+        state.busy = true;
+        let shared = Arc::clone(&self.state);
+        let waker = Arc::clone(&self.waker);
+        let _ = self.jobs.send(Box::new(move || {
+            let mut inner = match shared.lock().ok().and_then(|mut s| s.inner.take()) {
+                Some(inner) => inner,
+                None => return,
+            };
+            let mut chunk = vec![];
+            let result = read_chunk(&mut inner, &mut chunk);
+            if let Ok(mut state) = shared.lock() {
+                state.inner = Some(inner);
+                state.busy = false;
+                match result {
+                    Ok(eof) => {
+                        state.eof = eof;
+                        let consumed = state.consumed;
+                        state.buffered.drain(..consumed);
+                        state.consumed = 0;
+                        state.buffered.extend_from_slice(&chunk);
+                    }
+                    Err(e) => state.error = Some(e),
+                }
+            }
+            waker();
+        }));
+    }
+}
+
+impl<R: Read + Send + 'static> Read for OffloadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().map_err(|_| lost_worker())?;
+        let available = &state.buffered[state.consumed..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        state.consumed += n;
+
+        // keep a chunk ahead of the reads
+        if state.buffered.len() - state.consumed < CHUNK_SIZE {
+            self.prefetch(&mut state);
+        }
+        if n > 0 || buf.is_empty() {
+            Ok(n)
+        } else if let Some(e) = state.error.take() {
+            Err(e)
+        } else if state.eof && !state.busy {
+            Ok(0)
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+struct WriteState<W> {
+    /// The wrapped writer, unless a worker is using it
+    inner: Option<W>,
+    /// Data accepted but not written yet
+    queued: Vec<u8>,
+    /// Whether data was written since the last flush
+    dirty: bool,
+    /// Whether a flush was requested
+    flush: bool,
+    /// Whether committing the wrapped writer was requested
+    commit: bool,
+    /// Whether the wrapped writer was committed
+    committed: bool,
+    error: Option<io::Error>,
+    /// Whether a worker is writing
+    busy: bool,
+}
+
+/// Writes a file behind the worker threads of an `OffloadAdapter`
+pub struct OffloadWriter<W> {
+    state: Arc<Mutex<WriteState<W>>>,
+    jobs: Sender<Job>,
+    waker: IOWaker,
+}
+
+impl<W: Commit + Send + 'static> OffloadWriter<W> {
+    /// Starts writing the queued data, and committing if requested, on a worker,
+    /// if not already done
+    fn write_behind(&self, state: &mut WriteState<W>) {
+        if state.busy {
+            return;
+        }
+        state.busy = true;
+        let shared = Arc::clone(&self.state);
+        let waker = Arc::clone(&self.waker);
+        let _ = self.jobs.send(Box::new(move || loop {
+            let (mut inner, data, flush, commit) = match shared.lock() {
+                Ok(mut state) => {
+                    let commit = state.commit && !state.committed;
+                    if state.queued.is_empty() && !state.flush && !commit {
+                        state.busy = false;
+                        return;
+                    }
+                    let data = mem::take(&mut state.queued);
+                    let flush = mem::replace(&mut state.flush, false);
+                    match state.inner.take() {
+                        Some(inner) => (inner, data, flush, commit),
+                        None => return,
+                    }
+                }
+                Err(_) => return,
+            };
+            let mut result = inner.write_all(&data);
+            if flush {
+                result = result.and_then(|_| inner.flush());
+            }
+            // which may sync the file, taking as long as writing it
+            if commit {
+                result = result.and_then(|_| inner.commit());
+            }
+            if let Ok(mut state) = shared.lock() {
+                state.inner = Some(inner);
+                match result {
+                    Ok(()) => {
+                        if flush && state.queued.is_empty() {
+                            state.dirty = false;
+                        }
+                        state.committed |= commit;
+                    }
+                    Err(e) => {
+                        state.commit = false;
+                        state.error = Some(e);
+                        state.busy = false;
+                        drop(state);
+                        waker();
+                        return;
+                    }
+                }
+            }
+            waker();
+        }));
+    }
+}
+
+impl<W: Commit + Send + 'static> Write for OffloadWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().map_err(|_| lost_worker())?;
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        if state.queued.len() >= CHUNK_SIZE {
+            // nothing is accepted, as required of writers that would block
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        state.queued.extend_from_slice(buf);
+        state.dirty = true;
+        self.write_behind(&mut state);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().map_err(|_| lost_worker())?;
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        if !state.dirty {
+            return Ok(());
+        }
+        state.flush = true;
+        self.write_behind(&mut state);
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl<W: Commit + Send + 'static> Commit for OffloadWriter<W> {
+    /// Commits the wrapped writer on a worker, after the data queued before
+    fn commit(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().map_err(|_| lost_worker())?;
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        if state.committed {
+            return Ok(());
+        }
+        state.commit = true;
+        self.write_behind(&mut state);
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    /// Serves a fixed buffer as the only file, and collects the written one
+    /// along with the threads committing it and listing the directory
+    #[derive(Default)]
+    struct MemIO {
+        written: Arc<Mutex<Vec<u8>>>,
+        committed_by: Arc<Mutex<Option<thread::ThreadId>>>,
+        listed_by: Mutex<Option<thread::ThreadId>>,
+    }
+
+    struct SharedWriter {
+        written: Arc<Mutex<Vec<u8>>>,
+        committed_by: Arc<Mutex<Option<thread::ThreadId>>>,
+    }
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Commit for SharedWriter {
+        fn commit(&mut self) -> io::Result<()> {
+            *self.committed_by.lock().unwrap() = Some(thread::current().id());
+            Ok(())
+        }
+    }
+
+    fn contents() -> Vec<u8> {
+        (0..CHUNK_SIZE * 3 + 100).map(|i| i as u8).collect()
+    }
+
+    impl IOAdapter for MemIO {
+        type R = io::Cursor<Vec<u8>>;
+        type W = SharedWriter;
+        fn open_read(&self, _: &Path) -> io::Result<(Self::R, Option<u64>)> {
+            Ok((io::Cursor::new(contents()), None))
+        }
+        fn create_new(&mut self, _: &Path, _: Option<u64>) -> io::Result<Self::W> {
+            Ok(SharedWriter {
+                written: Arc::clone(&self.written),
+                committed_by: Arc::clone(&self.committed_by),
+            })
+        }
+        fn list_dir(&self, _: &Path) -> io::Result<Vec<OsString>> {
+            *self.listed_by.lock().unwrap() = Some(thread::current().id());
+            Ok(vec!["file".into()])
+        }
+    }
+
+    fn adapter() -> (OffloadAdapter<MemIO>, Arc<AtomicUsize>) {
+        let mut io = OffloadAdapter::new(MemIO::default(), 2);
+        let wakes = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&wakes);
+        io.set_waker(Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        (io, wakes)
+    }
+
+    /// Retries an operation that would block, failing if that takes too long
+    fn retry<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let start = Instant::now();
+        loop {
+            match f() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(start.elapsed() < Duration::from_secs(5), "still blocking");
+                    thread::yield_now();
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn read_whole_file() {
+        let (io, wakes) = adapter();
+        let (mut reader, _) = retry(|| io.open_read(Path::new("file"))).unwrap();
+        let mut read = vec![];
+        let mut buf = [0; 1000];
+        loop {
+            let n = retry(|| reader.read(&mut buf)).unwrap();
+            if n == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(read, contents());
+        assert!(wakes.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn write_whole_file() {
+        let (mut io, wakes) = adapter();
+        let mut writer = retry(|| io.create_new(Path::new("file"), None)).unwrap();
+        let data = contents();
+        let mut written = 0;
+        while written < data.len() {
+            let end = (written + 512).min(data.len());
+            written += retry(|| writer.write(&data[written..end])).unwrap();
+        }
+        retry(|| writer.flush()).unwrap();
+        assert_eq!(*io.io.read().unwrap().written.lock().unwrap(), data);
+        assert!(wakes.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn commit_in_background() {
+        let (mut io, _) = adapter();
+        let mut writer = retry(|| io.create_new(Path::new("file"), None)).unwrap();
+        writer.write_all(&contents()[..100]).unwrap();
+        retry(|| writer.flush()).unwrap();
+        assert_eq!(
+            writer.commit().err().map(|e| e.kind()),
+            Some(io::ErrorKind::WouldBlock)
+        );
+        retry(|| writer.commit()).unwrap();
+        let committed_by = *io.io.read().unwrap().committed_by.lock().unwrap();
+        assert!(committed_by.is_some());
+        assert_ne!(committed_by, Some(thread::current().id()));
+    }
+
+    #[test]
+    fn open_in_background() {
+        let (io, wakes) = adapter();
+        let file = Path::new("file");
+        assert_eq!(
+            io.open_read(file).err().map(|e| e.kind()),
+            Some(io::ErrorKind::WouldBlock)
+        );
+        let (mut reader, _) = retry(|| io.open_read(file)).unwrap();
+        assert!(wakes.load(Ordering::SeqCst) > 0);
+        // the first chunk is read along
+        let mut buf = [0; 1000];
+        assert_eq!(reader.read(&mut buf).unwrap(), buf.len());
+        assert_eq!(&buf[..], &contents()[..buf.len()]);
+        // another request opens the file again
+        assert!(io.open_read(file).is_err());
+    }
+
+    #[test]
+    fn netascii_size_kept_until_opened() {
+        let (io, _) = adapter();
+        let file = Path::new("file");
+        let expected = io::copy(
+            &mut NetasciiReader::new(io::Cursor::new(contents())),
+            &mut io::sink(),
+        )
+        .unwrap();
+        assert_eq!(retry(|| io.netascii_size(file)).unwrap(), Some(expected));
+        // while the request waits for the file to be opened
+        assert_eq!(io.netascii_size(file).unwrap(), Some(expected));
+        retry(|| io.open_read(file)).unwrap();
+        assert!(io.netascii_size(file).is_err());
+    }
+
+    #[test]
+    fn lookup_kept_until_opened() {
+        let (io, _) = adapter();
+        let (dir, file) = (Path::new("dir"), Path::new("FILE"));
+        assert_eq!(
+            io.find_ignoring_case(dir, file).err().map(|e| e.kind()),
+            Some(io::ErrorKind::WouldBlock)
+        );
+        let found = retry(|| io.find_ignoring_case(dir, file)).unwrap();
+        assert_eq!(found, Path::new("dir/file"));
+        let listed_by = *io.io.read().unwrap().listed_by.lock().unwrap();
+        assert!(listed_by.is_some());
+        assert_ne!(listed_by, Some(thread::current().id()));
+        // while the request waits for the file to be opened
+        assert_eq!(io.find_ignoring_case(dir, file).unwrap(), found);
+        retry(|| io.open_read(&found)).unwrap();
+        assert!(io.find_ignoring_case(dir, file).is_err());
+    }
+}
@@ -1,6 +1,11 @@
 use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
 
 pub const MAX_BLOCKSIZE: u16 = 65_464;
+/// The smallest `utimeout`, in microseconds, as in tftpd-hpa
+pub const MIN_UTIMEOUT: u32 = 10_000;
+/// The largest `utimeout`, in microseconds
+pub const MAX_UTIMEOUT: u32 = 255_000_000;
 
 /// A TFTP protocol option, proposed via RRQ/WRQ and acknowledged via OACK
 #[derive(PartialEq, Clone, Debug)]
@@ -8,11 +13,26 @@ pub enum TftpOption {
     Blocksize(u16),
     TransferSize(u64),
     TimeoutSecs(u8),
+    /// The `utimeout` extension, for timeouts below a second
+    TimeoutMicros(u32),
     WindowSize(u16),
+    /// The block number following 65535, either 0 or 1
+    Rollover(u8),
+    /// RFC 2090 multicast, empty in requests
+    Multicast(Option<MulticastGroup>),
+}
+
+/// The multicast group a file is sent to, as acknowledged to one of the clients
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct MulticastGroup {
+    /// The group address and port
+    pub addr: SocketAddr,
+    /// Whether the client is the master client, which acknowledges the data
+    pub master: bool,
 }
 
 impl TftpOption {
-    pub fn write_to(&self, buf: &mut Write) -> io::Result<()> {
+    pub fn write_to(&self, buf: &mut dyn Write) -> io::Result<()> {
         use self::TftpOption::*;
         match *self {
             Blocksize(size) => {
@@ -24,9 +44,22 @@ impl TftpOption {
             TimeoutSecs(t) => {
                 write!(buf, "timeout\0{}\0", t)?;
             }
+            TimeoutMicros(t) => {
+                write!(buf, "utimeout\0{}\0", t)?;
+            }
             WindowSize(t) => {
                 write!(buf, "windowsize\0{}\0", t)?;
             }
+            Rollover(block) => {
+                write!(buf, "rollover\0{}\0", block)?;
+            }
+            Multicast(None) => {
+                write!(buf, "multicast\0\0")?;
+            }
+            Multicast(Some(group)) => {
+                let (addr, port) = (group.addr.ip(), group.addr.port());
+                write!(buf, "multicast\0{},{},{}\0", addr, port, group.master as u8)?;
+            }
         };
         Ok(())
     }
@@ -34,7 +67,7 @@ impl TftpOption {
     pub fn try_from(name: &str, value: &str) -> Option<Self> {
         if "blksize".eq_ignore_ascii_case(name) {
             let val = value.parse::<u16>().ok()?;
-            if val >= 8 && val <= MAX_BLOCKSIZE {
+            if (8..=MAX_BLOCKSIZE).contains(&val) {
                 return Some(TftpOption::Blocksize(val));
             }
         } else if "timeout".eq_ignore_ascii_case(name) {
@@ -42,6 +75,12 @@ impl TftpOption {
             if val > 0 {
                 return Some(TftpOption::TimeoutSecs(val));
             }
+        } else if "utimeout".eq_ignore_ascii_case(name) {
+            let val = value.parse().ok()?;
+            // no longer than the largest `timeout`, nor so short that every packet is resent
+            if (MIN_UTIMEOUT..=MAX_UTIMEOUT).contains(&val) {
+                return Some(TftpOption::TimeoutMicros(val));
+            }
         } else if "tsize".eq_ignore_ascii_case(name) {
             let val = value.parse().ok()?;
             return Some(TftpOption::TransferSize(val));
@@ -50,6 +89,29 @@ impl TftpOption {
             if val > 0 {
                 return Some(TftpOption::WindowSize(val));
             }
+        } else if "rollover".eq_ignore_ascii_case(name) {
+            let val = value.parse().ok()?;
+            if val <= 1 {
+                return Some(TftpOption::Rollover(val));
+            }
+        } else if "multicast".eq_ignore_ascii_case(name) {
+            if value.is_empty() {
+                return Some(TftpOption::Multicast(None));
+            }
+            let mut fields = value.split(',');
+            let addr = fields.next()?.parse::<IpAddr>().ok()?;
+            let port = fields.next()?.parse().ok()?;
+            let master = match fields.next()? {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            };
+            if fields.next().is_none() {
+                return Some(TftpOption::Multicast(Some(MulticastGroup {
+                    addr: SocketAddr::new(addr, port),
+                    master,
+                })));
+            }
         }
         None
     }
@@ -92,7 +154,7 @@ mod tests {
     fn blocksize_write() {
         let mut v = vec![];
         TftpOption::Blocksize(78).write_to(&mut v).unwrap();
-        assert_eq!(v, b"blksize\078\0");
+        assert_eq!(v, b"blksize\x0078\0");
     }
 
     #[test]
@@ -111,7 +173,7 @@ mod tests {
     fn transfer_size_write() {
         let mut v = vec![];
         TftpOption::TransferSize(54).write_to(&mut v).unwrap();
-        assert_eq!(v, b"tsize\054\0");
+        assert_eq!(v, b"tsize\x0054\0");
     }
 
     #[test]
@@ -144,7 +206,33 @@ mod tests {
     fn timeout_write() {
         let mut v = vec![];
         TftpOption::TimeoutSecs(4).write_to(&mut v).unwrap();
-        assert_eq!(v, b"timeout\04\0");
+        assert_eq!(v, b"timeout\x004\0");
+    }
+
+    #[test]
+    fn utimeout_parse() {
+        assert_eq!(
+            TftpOption::try_from("utimeout", "25000"),
+            Some(TftpOption::TimeoutMicros(25000))
+        );
+        assert_eq!(
+            TftpOption::try_from("utimeout", "10000"),
+            Some(TftpOption::TimeoutMicros(MIN_UTIMEOUT))
+        );
+        assert_eq!(
+            TftpOption::try_from("UTIMEOUT", "255000000"),
+            Some(TftpOption::TimeoutMicros(255_000_000))
+        );
+        assert_eq!(TftpOption::try_from("utimeout", "255000001"), None);
+        assert_eq!(TftpOption::try_from("utimeout", "9999"), None);
+        assert_eq!(TftpOption::try_from("utimeout", "0"), None);
+    }
+
+    #[test]
+    fn utimeout_write() {
+        let mut v = vec![];
+        TftpOption::TimeoutMicros(25000).write_to(&mut v).unwrap();
+        assert_eq!(v, b"utimeout\x0025000\0");
     }
 
     #[test]
@@ -177,6 +265,67 @@ mod tests {
     fn windowsize_write() {
         let mut v = vec![];
         TftpOption::WindowSize(4).write_to(&mut v).unwrap();
-        assert_eq!(v, b"windowsize\04\0");
+        assert_eq!(v, b"windowsize\x004\0");
+    }
+
+    #[test]
+    fn rollover_parse() {
+        assert_eq!(
+            TftpOption::try_from("rollover", "0"),
+            Some(TftpOption::Rollover(0))
+        );
+        assert_eq!(
+            TftpOption::try_from("ROLLOVER", "1"),
+            Some(TftpOption::Rollover(1))
+        );
+        assert_eq!(TftpOption::try_from("rollover", "2"), None);
+        assert_eq!(TftpOption::try_from("rollover", "yes"), None);
+    }
+
+    #[test]
+    fn rollover_write() {
+        let mut v = vec![];
+        TftpOption::Rollover(1).write_to(&mut v).unwrap();
+        assert_eq!(v, b"rollover\x001\0");
+    }
+
+    #[test]
+    fn multicast_parse() {
+        assert_eq!(
+            TftpOption::try_from("multicast", ""),
+            Some(TftpOption::Multicast(None))
+        );
+        assert_eq!(
+            TftpOption::try_from("MULTICAST", "239.1.2.3,1758,1"),
+            Some(TftpOption::Multicast(Some(MulticastGroup {
+                addr: "239.1.2.3:1758".parse().unwrap(),
+                master: true,
+            })))
+        );
+        assert_eq!(
+            TftpOption::try_from("multicast", "ff02::1:2,1758,0"),
+            Some(TftpOption::Multicast(Some(MulticastGroup {
+                addr: "[ff02::1:2]:1758".parse().unwrap(),
+                master: false,
+            })))
+        );
+        assert_eq!(TftpOption::try_from("multicast", "239.1.2.3,1758"), None);
+        assert_eq!(TftpOption::try_from("multicast", "239.1.2.3,1758,2"), None);
+        assert_eq!(TftpOption::try_from("multicast", "239.1.2.3,1758,1,"), None);
+    }
+
+    #[test]
+    fn multicast_write() {
+        let mut v = vec![];
+        TftpOption::Multicast(None).write_to(&mut v).unwrap();
+        assert_eq!(v, b"multicast\0\0");
+
+        let mut v = vec![];
+        let group = MulticastGroup {
+            addr: "239.1.2.3:1758".parse().unwrap(),
+            master: true,
+        };
+        TftpOption::Multicast(Some(group)).write_to(&mut v).unwrap();
+        assert_eq!(v, b"multicast\x00239.1.2.3,1758,1\0");
     }
 }
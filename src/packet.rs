@@ -80,6 +80,7 @@ primitive_enum! (
 
 impl ErrorCode {
     /// Returns the string description of the error code.
+    #[allow(clippy::inherent_to_string)]
     pub fn to_string(self) -> String {
         match self {
             ErrorCode::NotDefined => "Not defined, see error message (if any).",
@@ -167,18 +168,49 @@ impl fmt::Display for TransferMode {
     }
 }
 
+/// A packet borrowing its strings and data from the buffer it was read from,
+/// so that reading one copies nothing. Convert it into a `Packet` to keep it longer
+#[derive(PartialEq, Clone, Debug)]
+pub enum PacketRef<'a> {
+    RRQ {
+        filename: &'a str,
+        mode: TransferMode,
+        options: Vec<TftpOption>,
+    },
+    WRQ {
+        filename: &'a str,
+        mode: TransferMode,
+        options: Vec<TftpOption>,
+    },
+    DATA {
+        block_num: u16,
+        data: &'a [u8],
+    },
+    ACK(u16),
+    ERROR {
+        code: ErrorCode,
+        msg: &'a str,
+    },
+    OACK {
+        options: Vec<TftpOption>,
+    },
+}
+
+/// The size of the opcode and block number preceding the data in a DATA packet
+pub const DATA_HEADER_LEN: usize = 4;
+
+/// Writes the header of a DATA packet to the start of `buf`,
+/// so that the data can be placed right after it, at `DATA_HEADER_LEN`, without copying
+pub fn write_data_header(block_num: u16, mut buf: &mut [u8]) -> Result<()> {
+    buf.write_u16::<BigEndian>(OpCode::DATA as u16)?;
+    buf.write_u16::<BigEndian>(block_num)?;
+    Ok(())
+}
+
 impl Packet {
     /// Creates and returns a packet parsed from its byte representation.
-    pub fn read(mut bytes: &[u8]) -> Result<Packet> {
-        let opcode = OpCode::from_u16(bytes.read_u16::<BigEndian>()?)?;
-        match opcode {
-            OpCode::RRQ => read_rrq_packet(bytes),
-            OpCode::WRQ => read_wrq_packet(bytes),
-            OpCode::DATA => read_data_packet(bytes),
-            OpCode::ACK => read_ack_packet(bytes),
-            OpCode::ERROR => read_error_packet(bytes),
-            OpCode::OACK => read_oack_packet(bytes),
-        }
+    pub fn read(bytes: &[u8]) -> Result<Packet> {
+        PacketRef::read(bytes).map(Packet::from)
     }
 
     /// Consumes the packet and returns the packet in byte representation.
@@ -193,6 +225,35 @@ impl Packet {
         Ok(buf)
     }
 
+    /// Appends the packet bytes to the given vector, reusing its allocation
+    pub fn append_to(&self, buf: &mut Vec<u8>) -> Result<()> {
+        self.write_bytes_to(buf)
+    }
+
+    /// Writes the packet bytes to the give slice, returning the amount of bytes written
+    pub fn write_to_slice(&self, sl: &mut [u8]) -> Result<usize> {
+        PacketRef::from(self).write_to_slice(sl)
+    }
+
+    fn write_bytes_to(&self, buf: &mut impl Write) -> Result<()> {
+        PacketRef::from(self).write_bytes_to(buf)
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    /// Parses a packet from its byte representation, borrowing from `bytes`
+    pub fn read(mut bytes: &'a [u8]) -> Result<Self> {
+        let opcode = OpCode::from_u16(bytes.read_u16::<BigEndian>()?)?;
+        match opcode {
+            OpCode::RRQ => read_rrq_packet(bytes),
+            OpCode::WRQ => read_wrq_packet(bytes),
+            OpCode::DATA => read_data_packet(bytes),
+            OpCode::ACK => read_ack_packet(bytes),
+            OpCode::ERROR => read_error_packet(bytes),
+            OpCode::OACK => read_oack_packet(bytes),
+        }
+    }
+
     /// Writes the packet bytes to the give slice, returning the amount of bytes written
     pub fn write_to_slice(&self, sl: &mut [u8]) -> Result<usize> {
         let left = {
@@ -205,23 +266,89 @@ impl Packet {
 
     fn write_bytes_to(&self, buf: &mut impl Write) -> Result<()> {
         match *self {
+            PacketRef::RRQ {
+                filename,
+                mode,
+                ref options,
+            } => rw_packet_bytes(OpCode::RRQ, filename, mode, options, buf),
+            PacketRef::WRQ {
+                filename,
+                mode,
+                ref options,
+            } => rw_packet_bytes(OpCode::WRQ, filename, mode, options, buf),
+            PacketRef::DATA { block_num, data } => data_packet_bytes(block_num, data, buf),
+            PacketRef::ACK(block_num) => ack_packet_bytes(block_num, buf),
+            PacketRef::ERROR { code, msg } => error_packet_bytes(code, msg, buf),
+            PacketRef::OACK { ref options } => oack_packet_bytes(options, buf),
+        }
+    }
+}
+
+impl<'a> From<&'a Packet> for PacketRef<'a> {
+    fn from(packet: &'a Packet) -> Self {
+        match *packet {
             Packet::RRQ {
                 ref filename,
                 mode,
                 ref options,
-            } => rw_packet_bytes(OpCode::RRQ, filename, mode, options, buf),
+            } => PacketRef::RRQ {
+                filename,
+                mode,
+                options: options.clone(),
+            },
             Packet::WRQ {
                 ref filename,
                 mode,
                 ref options,
-            } => rw_packet_bytes(OpCode::WRQ, filename, mode, options, buf),
+            } => PacketRef::WRQ {
+                filename,
+                mode,
+                options: options.clone(),
+            },
             Packet::DATA {
                 block_num,
                 ref data,
-            } => data_packet_bytes(block_num, data.as_slice(), buf),
-            Packet::ACK(block_num) => ack_packet_bytes(block_num, buf),
-            Packet::ERROR { code, ref msg } => error_packet_bytes(code, msg, buf),
-            Packet::OACK { ref options } => oack_packet_bytes(options, buf),
+            } => PacketRef::DATA { block_num, data },
+            Packet::ACK(block_num) => PacketRef::ACK(block_num),
+            Packet::ERROR { code, ref msg } => PacketRef::ERROR { code, msg },
+            Packet::OACK { ref options } => PacketRef::OACK {
+                options: options.clone(),
+            },
+        }
+    }
+}
+
+impl From<PacketRef<'_>> for Packet {
+    fn from(packet: PacketRef<'_>) -> Self {
+        match packet {
+            PacketRef::RRQ {
+                filename,
+                mode,
+                options,
+            } => Packet::RRQ {
+                filename: filename.to_owned(),
+                mode,
+                options,
+            },
+            PacketRef::WRQ {
+                filename,
+                mode,
+                options,
+            } => Packet::WRQ {
+                filename: filename.to_owned(),
+                mode,
+                options,
+            },
+            PacketRef::DATA { block_num, data } => Packet::DATA {
+                block_num,
+                data: data.to_vec(),
+            },
+            PacketRef::ACK(block_num) => Packet::ACK(block_num),
+            PacketRef::ERROR { code, msg } => Packet::ERROR {
+                code,
+                msg: msg.to_owned(),
+            },
+            PacketRef::OACK { options } => Packet::OACK { options },
         }
     }
 }
@@ -274,36 +401,36 @@ mod strings {
     }
 }
 
-fn read_rrq_packet(bytes: &[u8]) -> Result<Packet> {
+fn read_rrq_packet(bytes: &[u8]) -> Result<PacketRef<'_>> {
     use self::PacketErr::StrOutOfBounds;
     if bytes.len() > 512 {
         Err(StrOutOfBounds)?;
     }
     let mut strings = Strings::from(bytes);
 
-    let filename = strings.next().ok_or(StrOutOfBounds)?.to_owned();
+    let filename = strings.next().ok_or(StrOutOfBounds)?;
     let mode = TransferMode::try_from(strings.next().ok_or(StrOutOfBounds)?)?;
     let options = read_options(strings);
 
-    Ok(Packet::RRQ {
+    Ok(PacketRef::RRQ {
         filename,
         mode,
         options,
     })
 }
 
-fn read_wrq_packet(bytes: &[u8]) -> Result<Packet> {
+fn read_wrq_packet(bytes: &[u8]) -> Result<PacketRef<'_>> {
     use self::PacketErr::StrOutOfBounds;
     if bytes.len() > 512 {
         Err(StrOutOfBounds)?;
     }
     let mut strings = Strings::from(bytes);
 
-    let filename = strings.next().ok_or(StrOutOfBounds)?.to_owned();
+    let filename = strings.next().ok_or(StrOutOfBounds)?;
     let mode = TransferMode::try_from(strings.next().ok_or(StrOutOfBounds)?)?;
     let options = read_options(strings);
 
-    Ok(Packet::WRQ {
+    Ok(PacketRef::WRQ {
         filename,
         mode,
         options,
@@ -323,33 +450,32 @@ fn read_options(mut strings: Strings) -> Vec<TftpOption> {
     options
 }
 
-fn read_data_packet(mut bytes: &[u8]) -> Result<Packet> {
+fn read_data_packet(mut bytes: &[u8]) -> Result<PacketRef<'_>> {
     let block_num = bytes.read_u16::<BigEndian>()?;
-    let mut data = Vec::with_capacity(512);
-    use std::io::Read;
-    bytes.read_to_end(&mut data)?;
-
-    Ok(Packet::DATA { block_num, data })
+    Ok(PacketRef::DATA {
+        block_num,
+        data: bytes,
+    })
 }
 
-fn read_ack_packet(mut bytes: &[u8]) -> Result<Packet> {
+fn read_ack_packet(mut bytes: &[u8]) -> Result<PacketRef<'_>> {
     let block_num = bytes.read_u16::<BigEndian>()?;
-    Ok(Packet::ACK(block_num))
+    Ok(PacketRef::ACK(block_num))
 }
 
-fn read_error_packet(mut bytes: &[u8]) -> Result<Packet> {
+fn read_error_packet(mut bytes: &[u8]) -> Result<PacketRef<'_>> {
     let code = ErrorCode::from_u16(bytes.read_u16::<BigEndian>()?)?;
     let mut strings = Strings::from(bytes);
-    let msg = strings.next().ok_or(PacketErr::StrOutOfBounds)?.to_owned();
+    let msg = strings.next().ok_or(PacketErr::StrOutOfBounds)?;
 
-    Ok(Packet::ERROR { code, msg })
+    Ok(PacketRef::ERROR { code, msg })
 }
 
-fn read_oack_packet(bytes: &[u8]) -> Result<Packet> {
+fn read_oack_packet(bytes: &[u8]) -> Result<PacketRef<'_>> {
     let strings = Strings::from(bytes);
     let options = read_options(strings);
 
-    Ok(Packet::OACK { options })
+    Ok(PacketRef::OACK { options })
 }
 
 fn rw_packet_bytes(
@@ -370,8 +496,9 @@ fn rw_packet_bytes(
 }
 
 fn data_packet_bytes(block_num: u16, data: &[u8], buf: &mut impl Write) -> Result<()> {
-    buf.write_u16::<BigEndian>(OpCode::DATA as u16)?;
-    buf.write_u16::<BigEndian>(block_num)?;
+    let mut header = [0; DATA_HEADER_LEN];
+    write_data_header(block_num, &mut header)?;
+    buf.write_all(&header)?;
     buf.write_all(data)?;
 
     Ok(())
@@ -446,6 +573,53 @@ mod tests {
         };
     }
 
+    #[test]
+    fn data_ref_borrows() {
+        let bytes = Packet::DATA {
+            block_num: 7,
+            data: vec![1, 2, 3],
+        }
+        .into_bytes()
+        .unwrap();
+        let packet = PacketRef::read(&bytes).unwrap();
+        assert_eq!(
+            packet,
+            PacketRef::DATA {
+                block_num: 7,
+                data: &[1, 2, 3],
+            }
+        );
+        if let PacketRef::DATA { data, .. } = packet {
+            assert_eq!(data.as_ptr(), bytes[DATA_HEADER_LEN..].as_ptr());
+        }
+    }
+
+    #[test]
+    fn data_built_in_place() {
+        let mut buf = vec![0; DATA_HEADER_LEN];
+        write_data_header(513, &mut buf).unwrap();
+        buf.extend_from_slice(b"abc");
+        assert_eq!(
+            Packet::read(&buf).unwrap(),
+            Packet::DATA {
+                block_num: 513,
+                data: b"abc".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn ref_converts() {
+        let packet = Packet::ERROR {
+            code: ErrorCode::FileNotFound,
+            msg: "gone".to_string(),
+        };
+        let bytes = packet.to_bytes().unwrap();
+        let packet_ref = PacketRef::read(&bytes).unwrap();
+        assert_eq!(packet_ref, PacketRef::from(&packet));
+        assert_eq!(Packet::from(packet_ref), packet);
+    }
+
     const BYTE_DATA: [u8; 512] = [123; 512];
 
     packet_enc_dec_test!(
@@ -0,0 +1,115 @@
+//! Discovery of the local address a datagram was sent to, for sockets bound to
+//! a wildcard address, so that replies come from the address the client contacted.
+//! Uses `IP_PKTINFO` and `IPV6_RECVPKTINFO` where available.
+
+use std::io;
+use std::net::{self, SocketAddr};
+
+/// Asks the kernel to report the destination address of every datagram received
+/// on `socket`. Does nothing for sockets bound to a specific address
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn enable(socket: &net::UdpSocket) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+    use std::net::IpAddr;
+
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            setsockopt(socket, sockopt::Ipv4PacketInfo, &true)?;
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            setsockopt(socket, sockopt::Ipv6RecvPacketInfo, &true)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Receives a datagram, returning its size, its source, and the local address
+/// it was sent to if the kernel reported it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+// the mio server receives requests in batches instead with the `mmsg` feature
+#[cfg_attr(
+    not(any(all(feature = "mio", not(feature = "mmsg")), feature = "tokio")),
+    allow(dead_code)
+)]
+pub fn recv_from_to<S: std::os::unix::io::AsRawFd>(
+    socket: &S,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    use nix::sys::socket::{recvmsg, MsgFlags, SockaddrStorage};
+    use std::io::IoSliceMut;
+
+    let mut cmsg_buf = cmsg_space();
+    let mut iov = [IoSliceMut::new(buf)];
+    let msg = recvmsg::<SockaddrStorage>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buf),
+        MsgFlags::empty(),
+    )?;
+    let (src, dst) = addresses(&msg)?;
+    Ok((msg.bytes, src, dst))
+}
+
+/// Returns a buffer large enough for the control messages read by `addresses`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn cmsg_space() -> Vec<u8> {
+    nix::cmsg_space!(nix::libc::in_pktinfo, nix::libc::in6_pktinfo)
+}
+
+/// Returns the source of a received datagram,
+/// and the local address it was sent to if the kernel reported it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn addresses(
+    msg: &nix::sys::socket::RecvMsg<nix::sys::socket::SockaddrStorage>,
+) -> io::Result<(SocketAddr, Option<SocketAddr>)> {
+    use nix::sys::socket::ControlMessageOwned;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    let src = msg.address.as_ref().and_then(|addr| {
+        if let Some(sin) = addr.as_sockaddr_in() {
+            Some(SocketAddr::V4(SocketAddrV4::from(*sin)))
+        } else {
+            addr.as_sockaddr_in6()
+                .map(|sin6| SocketAddr::V6(SocketAddrV6::from(*sin6)))
+        }
+    });
+    let src = src.ok_or_else(|| io::Error::other("no source address"))?;
+
+    let mut dst = None;
+    for cmsg in msg.cmsgs()? {
+        match cmsg {
+            ControlMessageOwned::Ipv4PacketInfo(info) => {
+                // the local address replies should be sent from, also correct for broadcasts
+                let ip = Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr));
+                dst = Some(SocketAddr::new(ip.into(), 0));
+            }
+            ControlMessageOwned::Ipv6PacketInfo(info) => {
+                let ip = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                // link-local addresses can only be bound to along with their interface
+                let scope_id = if ip.segments()[0] & 0xffc0 == 0xfe80 {
+                    info.ipi6_ifindex
+                } else {
+                    0
+                };
+                dst = Some(SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, scope_id)));
+            }
+            _ => {}
+        }
+    }
+    Ok((src, dst))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn enable(_: &net::UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(feature = "mio", not(any(target_os = "linux", target_os = "android"))))]
+pub fn recv_from_to(
+    socket: &mio::net::UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let (amt, src) = socket.recv_from(buf)?;
+    Ok((amt, src, None))
+}
@@ -0,0 +1,279 @@
+//! Rewriting of requested file names by an ordered list of rules, as in the map files
+//! of tftpd-hpa. Every line of a map file holds a rule: its flags, a regular expression,
+//! and for `r` rules the replacement, separated by whitespace. Lines starting with `#`
+//! are comments. The rules are tried in order on the name as rewritten so far.
+//!
+//! Flags:
+//! * `r` replaces the first match of the expression with the replacement
+//! * `g` makes `r` replace all matches
+//! * `i` matches case-insensitively
+//! * `e` ends the processing if the rule matched
+//! * `s` starts over from the first rule if the rule matched
+//! * `a` denies the request if the rule matched
+//! * `G` applies the rule to reads only, `P` to writes only
+//!
+//! In replacements, `\0` stands for the whole match, `\1` to `\9` for its groups,
+//! `\i` for the client IP address, `\x` for the client IP address in hexadecimal,
+//! as PXELINUX names its config files, and `\\` for a backslash:
+//!
+//! ```text
+//! # Windows boot loaders use backslashes
+//! rg \\ /
+//! # some clients ask for absolute paths
+//! r ^/tftpboot/(.*) \1
+//! # per-client configs
+//! rG ^pxelinux.cfg/default$ pxelinux.cfg/\x
+//! # no uploads of boot images
+//! aP \.(efi|com|0)$
+//! ```
+
+use regex::{Captures, Regex, RegexBuilder};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// The most times the rules are started over for a request, after which it is denied
+const MAX_PASSES: usize = 32;
+
+/// An ordered list of rules rewriting requested file names, empty by default
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    regex: Regex,
+    /// What matches are replaced with, for `r` rules
+    replacement: Option<String>,
+    global: bool,
+    end: bool,
+    restart: bool,
+    deny: bool,
+    reads: bool,
+    writes: bool,
+}
+
+impl Rules {
+    /// Parses the rules in the contents of a map file
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut rules = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let invalid = |msg: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", i + 1, msg),
+                )
+            };
+            let mut fields = line.split_whitespace();
+            let flags = match fields.next() {
+                Some(flags) if !flags.starts_with('#') => flags,
+                _ => continue,
+            };
+            let pattern = fields
+                .next()
+                .ok_or_else(|| invalid("missing regular expression".into()))?;
+
+            let (mut replace, mut global, mut icase) = (false, false, false);
+            let (mut end, mut restart, mut deny) = (false, false, false);
+            let (mut reads, mut writes) = (true, true);
+            for flag in flags.chars() {
+                match flag {
+                    'r' => replace = true,
+                    'g' => global = true,
+                    'i' => icase = true,
+                    'e' => end = true,
+                    's' => restart = true,
+                    'a' => deny = true,
+                    'G' => writes = false,
+                    'P' => reads = false,
+                    _ => return Err(invalid(format!("unknown flag '{}'", flag))),
+                }
+            }
+            let regex = RegexBuilder::new(pattern)
+                .case_insensitive(icase)
+                .build()
+                .map_err(|e| invalid(e.to_string()))?;
+            let replacement = if replace {
+                let replacement = fields
+                    .next()
+                    .ok_or_else(|| invalid("missing replacement".into()))?;
+                Some(replacement.to_owned())
+            } else {
+                None
+            };
+            if fields.next().is_some() {
+                return Err(invalid("too many fields".into()));
+            }
+            rules.push(Rule {
+                regex,
+                replacement,
+                global,
+                end,
+                restart,
+                deny,
+                reads,
+                writes,
+            });
+        }
+        Ok(Rules { rules })
+    }
+
+    /// Reads the rules from a map file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Returns the name of the file to transfer for a request, or `None` if it is denied
+    pub fn apply(&self, filename: &str, is_write: bool, client: Option<IpAddr>) -> Option<String> {
+        let mut name = filename.to_owned();
+        let mut passes = 1;
+        let mut next = 0;
+        while let Some(rule) = self.rules.get(next) {
+            next += 1;
+            let applies = if is_write { rule.writes } else { rule.reads };
+            if !applies || !rule.regex.is_match(&name) {
+                continue;
+            }
+            if rule.deny {
+                return None;
+            }
+            if let Some(ref replacement) = rule.replacement {
+                name = rule.replace(&name, replacement, client);
+            }
+            if rule.end {
+                break;
+            }
+            if rule.restart {
+                passes += 1;
+                if passes > MAX_PASSES {
+                    return None;
+                }
+                next = 0;
+            }
+        }
+        Some(name)
+    }
+}
+
+impl Rule {
+    fn replace(&self, name: &str, replacement: &str, client: Option<IpAddr>) -> String {
+        let mut out = String::new();
+        let mut last = 0;
+        for caps in self.regex.captures_iter(name) {
+            let whole = caps.get(0).unwrap();
+            out.push_str(&name[last..whole.start()]);
+            expand(&mut out, replacement, &caps, client);
+            last = whole.end();
+            if !self.global {
+                break;
+            }
+        }
+        out.push_str(&name[last..]);
+        out
+    }
+}
+
+/// Appends the replacement to `out`, substituting its escapes
+fn expand(out: &mut String, replacement: &str, caps: &Captures, client: Option<IpAddr>) {
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(digit @ '0'..='9') => {
+                let group = digit.to_digit(10).unwrap() as usize;
+                out.extend(caps.get(group).map(|group| group.as_str()));
+            }
+            Some('i') => out.extend(client.map(|ip| ip.to_string())),
+            Some('x') => {
+                let octets = match client {
+                    Some(IpAddr::V4(ip)) => ip.octets().to_vec(),
+                    Some(IpAddr::V6(ip)) => ip.octets().to_vec(),
+                    None => vec![],
+                };
+                out.extend(octets.iter().map(|byte| format!("{:02X}", byte)));
+            }
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 0, 10)));
+
+    #[test]
+    fn replace() {
+        let rules = Rules::parse(
+            r"
+            # comment
+            rg \\ /
+            ri ^/boot/(.*) \1
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            rules.apply(r"\Boot\x64\wdsnbp.com", false, CLIENT),
+            Some("x64/wdsnbp.com".into())
+        );
+        assert_eq!(
+            rules.apply("pxelinux.0", false, CLIENT),
+            Some("pxelinux.0".into())
+        );
+    }
+
+    #[test]
+    fn client_address() {
+        let rules = Rules::parse(r"r ^cfg$ cfg/\x-\i").unwrap();
+        assert_eq!(
+            rules.apply("cfg", false, CLIENT),
+            Some("cfg/C0A8000A-192.168.0.10".into())
+        );
+        assert_eq!(rules.apply("cfg", false, None), Some("cfg/-".into()));
+    }
+
+    #[test]
+    fn deny_by_request_type() {
+        let rules = Rules::parse(r"aP \.efi$").unwrap();
+        assert_eq!(rules.apply("boot.efi", true, CLIENT), None);
+        assert_eq!(
+            rules.apply("boot.efi", false, CLIENT),
+            Some("boot.efi".into())
+        );
+        assert_eq!(rules.apply("log.txt", true, CLIENT), Some("log.txt".into()));
+    }
+
+    #[test]
+    fn end_and_restart() {
+        let rules = Rules::parse(
+            r"
+            re ^a b
+            rs ^b c
+            r ^c$ d
+            ",
+        )
+        .unwrap();
+        assert_eq!(rules.apply("a", false, None), Some("b".into()));
+        // b -> c, then the rules start over and c -> d
+        assert_eq!(rules.apply("b", false, None), Some("d".into()));
+
+        let looping = Rules::parse(r"rs ^x x").unwrap();
+        assert_eq!(looping.apply("x", false, None), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Rules::parse("r ^a").is_err());
+        assert!(Rules::parse("q ^a b").is_err());
+        assert!(Rules::parse("r ^(a b").is_err());
+        assert!(Rules::parse("a ^a b").is_err());
+        assert!(Rules::parse("\n  # only comments\n").is_ok());
+    }
+}
@@ -0,0 +1,32 @@
+//! Binding several sockets to the same address with `SO_REUSEPORT`,
+//! so that the kernel spreads the datagrams sent there among them.
+//! Only Linux balances unicast datagrams this way, other systems deliver them to one socket.
+
+use std::io;
+use std::net::{self, SocketAddr};
+
+/// Binds a UDP socket that shares `addr` with the other ones bound this way
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bind(addr: SocketAddr) -> io::Result<net::UdpSocket> {
+    use nix::sys::socket::{
+        bind, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType, SockaddrStorage,
+    };
+    use std::os::unix::io::AsRawFd;
+
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    setsockopt(&fd, sockopt::ReusePort, &true)?;
+    bind(fd.as_raw_fd(), &SockaddrStorage::from(addr))?;
+    Ok(net::UdpSocket::from(fd))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn bind(_: SocketAddr) -> io::Result<net::UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "multiple workers need SO_REUSEPORT",
+    ))
+}
@@ -0,0 +1,127 @@
+//! Round-trip time estimation for computing retransmission timeouts,
+//! following [RFC 6298](https://tools.ietf.org/html/rfc6298)
+//! (Jacobson's algorithm, with Karn's backoff).
+
+use std::time::Duration;
+
+/// The lowest timeout ever used, so that scheduling jitter doesn't cause spurious resends
+const MIN_RTO: Duration = Duration::from_millis(50);
+/// Clock granularity term used when the variation is tiny
+const GRANULARITY: Duration = Duration::from_millis(10);
+
+/// Keeps smoothed round-trip time statistics for a single connection
+#[derive(Debug, Default)]
+pub struct RttEstimator {
+    /// The smoothed round-trip time, once a first sample has been taken
+    srtt: Option<Duration>,
+    /// The round-trip time variation
+    rttvar: Duration,
+    /// How many times the timeout has been doubled since the last sample
+    backoff: u32,
+}
+
+impl RttEstimator {
+    /// Adds a round-trip time measurement.
+    /// Per Karn's algorithm, this must not be called for replies to retransmitted packets
+    pub fn sample(&mut self, rtt: Duration) {
+        self.backoff = 0;
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let err = srtt.abs_diff(rtt);
+                // alpha = 1/8, beta = 1/4
+                self.rttvar = self.rttvar * 3 / 4 + err / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+
+    /// Doubles the timeout, to be called when it expires without a reply
+    pub fn backoff(&mut self) {
+        self.backoff = self.backoff.saturating_add(1);
+    }
+
+    /// Returns the smoothed round-trip time, if any samples were taken
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Returns the retransmission timeout, never larger than `max`,
+    /// or `None` if there are no samples yet
+    pub fn rto(&self, max: Duration) -> Option<Duration> {
+        let srtt = self.srtt?;
+        let rto = (srtt + (self.rttvar * 4).max(GRANULARITY)).max(MIN_RTO);
+        let factor = 1u32.checked_shl(self.backoff).unwrap_or(u32::MAX);
+        Some(rto.checked_mul(factor).map_or(max, |rto| rto.min(max)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: Duration = Duration::from_secs(3);
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn no_samples() {
+        let rtt = RttEstimator::default();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.rto(MAX), None);
+    }
+
+    #[test]
+    fn first_sample() {
+        let mut rtt = RttEstimator::default();
+        rtt.sample(ms(100));
+        assert_eq!(rtt.srtt(), Some(ms(100)));
+        // srtt + 4 * srtt / 2
+        assert_eq!(rtt.rto(MAX), Some(ms(300)));
+    }
+
+    #[test]
+    fn converges_on_stable_rtt() {
+        let mut rtt = RttEstimator::default();
+        rtt.sample(ms(400));
+        for _ in 0..100 {
+            rtt.sample(ms(20));
+        }
+        let srtt = rtt.srtt().unwrap();
+        assert!(srtt >= ms(20) && srtt < ms(21), "srtt {:?}", srtt);
+        assert!(rtt.rto(MAX).unwrap() < ms(60));
+    }
+
+    #[test]
+    fn minimum_rto() {
+        let mut rtt = RttEstimator::default();
+        for _ in 0..10 {
+            rtt.sample(Duration::from_micros(50));
+        }
+        assert_eq!(rtt.rto(MAX), Some(MIN_RTO));
+    }
+
+    #[test]
+    fn backoff_until_max() {
+        let mut rtt = RttEstimator::default();
+        rtt.sample(ms(400));
+        assert_eq!(rtt.rto(MAX), Some(ms(1200)));
+        rtt.backoff();
+        assert_eq!(rtt.rto(MAX), Some(ms(2400)));
+        rtt.backoff();
+        assert_eq!(rtt.rto(MAX), Some(MAX));
+        for _ in 0..40 {
+            rtt.backoff();
+        }
+        assert_eq!(rtt.rto(MAX), Some(MAX));
+
+        // a new sample ends the backoff
+        rtt.sample(ms(400));
+        assert!(rtt.rto(MAX).unwrap() < ms(1200));
+    }
+}
@@ -0,0 +1,997 @@
+//! The server driving its sockets and timers with a mio event loop per worker thread.
+//! Available with the `mio` cargo feature, enabled by default.
+
+use super::{
+    bind_server_sockets, bind_shared_socket, bind_socket, log_error, proto_handler, Result,
+    ServerConfig, TftpError,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::connection::ConnectionState;
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+use crate::mmsg;
+use crate::multicast::{self, GroupPool, MulticastSession};
+use crate::offload::OffloadAdapter;
+use crate::packet::{ErrorCode, Packet, PacketRef, MAX_PACKET_SIZE};
+#[cfg(not(all(feature = "mmsg", any(target_os = "linux", target_os = "android"))))]
+use crate::pktinfo;
+use crate::tftp_proto::{self, *};
+use log::*;
+use mio::net::UdpSocket;
+use mio::*;
+use mio_more::channel::{self, Receiver, Sender};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::result;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The token used by the channel receiving `Stop` requests.
+const CONTROL: Token = Token(0);
+
+/// The token signalled when transfers blocked on file I/O may resume,
+/// or when the clock was moved.
+const WAKER: Token = Token(1);
+
+/// The largest RRQ or WRQ packet, with a 512 bytes body
+const MAX_REQUEST_SIZE: usize = 514;
+
+/// A connection with a client, corresponding to a single read/write transfer
+struct Connection<IO: IOAdapter, C: Clock> {
+    /// The UDP socket for the connection that receives ACK, DATA, or ERROR packets.
+    socket: UdpSocket,
+    state: ConnectionState<IO, C>,
+    /// The deadline under which the connection is in the server's `timers`
+    scheduled: Instant,
+}
+
+/// A multicast read, with the socket sending to the group and receiving ACKs from the clients
+struct Session<IO: IOAdapter, C: Clock> {
+    socket: UdpSocket,
+    state: MulticastSession<IO, C>,
+    /// The deadline under which the session is in the server's `timers`
+    scheduled: Instant,
+}
+
+/// A request waiting for the IOAdapter to open its file, handled again once woken up
+struct BlockedRequest {
+    packet: Packet,
+    src: SocketAddr,
+    local_addr: SocketAddr,
+    ctx: RequestCtx,
+}
+
+/// A socket on which the server receives RRQ and WRQ packets
+struct ServerSocket {
+    socket: UdpSocket,
+    /// Restrictions for the transfers requested via this socket
+    ctx: RequestCtx,
+}
+
+pub type TftpServer = TftpServerImpl<FSAdapter>;
+
+/// A server doing its file I/O on worker threads, see `OffloadAdapter`
+pub type OffloadTftpServer = TftpServerImpl<OffloadAdapter<FSAdapter>>;
+
+/// The ways in which a server can be stopped
+#[derive(Clone, Copy, Debug)]
+enum Stop {
+    /// Stop accepting new transfers, and wait at most the given duration
+    /// for the ongoing ones to complete
+    Soft(Duration),
+    /// Abort all transfers immediately
+    Hard,
+}
+
+/// A handle used to stop a running server, possibly from another thread.
+///
+/// Obtained via `TftpServerImpl::handle`, and can be cloned freely.
+/// Once the server stops, `TftpServerImpl::run` returns `Ok(())`.
+/// Requests made after the server has stopped are ignored.
+#[derive(Clone)]
+pub struct ServerHandle {
+    /// One for each worker thread
+    txs: Vec<Sender<Stop>>,
+}
+
+impl ServerHandle {
+    /// Requests a graceful stop: the server stops accepting new transfers
+    /// and returns once all ongoing transfers are complete.
+    /// Transfers still running after `deadline` has elapsed are aborted.
+    pub fn stop_soft(&self, deadline: Duration) {
+        self.send(Stop::Soft(deadline));
+    }
+
+    /// Requests an immediate stop, aborting all ongoing transfers
+    pub fn stop_hard(&self) {
+        self.send(Stop::Hard);
+    }
+
+    fn send(&self, stop: Stop) {
+        for tx in &self.txs {
+            let _ = tx.send(stop);
+        }
+    }
+}
+
+pub struct TftpServerImpl<IO: IOAdapter, C: Clock = SystemClock> {
+    /// The ID of a new token used for generating different tokens.
+    new_token: Token,
+    /// The event loop for handling async events.
+    poll: Poll,
+    /// The connection timeout
+    timeout: Duration,
+    /// The main server socket that receives RRQ and WRQ packets
+    /// and creates a new separate UDP connection.
+    server_sockets: HashMap<Token, ServerSocket>,
+    /// The separate UDP connections for handling multiple requests.
+    connections: HashMap<Token, Connection<IO, C>>,
+    /// The multicast reads, each with its own socket
+    sessions: HashMap<Token, Session<IO, C>>,
+    /// The deadlines of the connections and sessions, earliest first
+    timers: BTreeSet<(Instant, Token)>,
+    /// The requests whose file is still being opened
+    blocked_requests: Vec<BlockedRequest>,
+    /// The multicast groups not used by any session
+    groups: GroupPool,
+    /// The TFTP protocol state machine and filesystem accessor
+    proto_handler: TftpServerProto<IO>,
+    /// Receives stop requests from `ServerHandle`s
+    control_rx: Receiver<Stop>,
+    /// Sender cloned into each `ServerHandle`
+    control_tx: Sender<Stop>,
+    /// Signalled by the IOAdapter when transfers blocked on file I/O may resume,
+    /// and by the clock when its time changes
+    waker: Registration,
+    waker_readiness: SetReadiness,
+    /// Set once a soft stop is requested, to the time when remaining transfers get aborted
+    stop_deadline: Option<Instant>,
+    /// Set once the server must stop running
+    stopped: bool,
+    /// The source of time for timeouts
+    clock: C,
+    /// The servers run by the other worker threads, sharing the listening addresses
+    workers: Vec<TftpServerImpl<IO, C>>,
+    /// Receive buffers for batches of requests
+    #[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+    request_bufs: Vec<Vec<u8>>,
+}
+
+impl<IO: IOAdapter + Default> TftpServerImpl<IO> {
+    /// Creates a new TFTP server from a random open UDP port.
+    pub fn new() -> Result<Self> {
+        Self::with_cfg(&Default::default())
+    }
+
+    /// Creates a new TFTP server from the provided config
+    pub fn with_cfg(cfg: &ServerConfig) -> Result<Self> {
+        Self::with_clock(cfg, SystemClock)
+    }
+}
+
+impl<IO: IOAdapter + Default, C: Clock> TftpServerImpl<IO, C> {
+    /// Creates a new TFTP server from the provided config,
+    /// with its timeouts following the given clock
+    pub fn with_clock(cfg: &ServerConfig, clock: C) -> Result<Self> {
+        let shared = cfg.workers > 1;
+        if shared && !cfg.multicast.is_empty() {
+            // sessions could only be joined through the worker that started them
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "multicast needs a single worker",
+            )
+            .into());
+        }
+        let sockets = bind_server_sockets(cfg, shared)?;
+        info!(
+            "Server listening on {:?} with {} worker(s)",
+            sockets
+                .iter()
+                .map(|(socket, ctx)| format!(
+                    "{} (max blocksize {})",
+                    socket.local_addr().unwrap(),
+                    ctx.max_blocksize
+                ))
+                .collect::<Vec<_>>(),
+            cfg.workers.max(1)
+        );
+
+        // the others bind to the ports picked for the first one
+        let mut workers = vec![];
+        for _ in 1..cfg.workers {
+            let sockets = sockets
+                .iter()
+                .map(|(socket, ctx)| Ok((bind_shared_socket(socket.local_addr()?)?, ctx.clone())))
+                .collect::<Result<Vec<_>>>()?;
+            workers.push(Self::with_sockets(cfg, clock.clone(), sockets)?);
+        }
+
+        let mut server = Self::with_sockets(cfg, clock, sockets)?;
+        server.workers = workers;
+        Ok(server)
+    }
+
+    /// Creates the server run by a single thread
+    fn with_sockets(
+        cfg: &ServerConfig,
+        clock: C,
+        sockets: Vec<(net::UdpSocket, RequestCtx)>,
+    ) -> Result<Self> {
+        let poll = Poll::new()?;
+
+        let (control_tx, control_rx) = channel::channel();
+        poll.register(&control_rx, CONTROL, Ready::readable(), PollOpt::edge())?;
+
+        let mut server_sockets = HashMap::new();
+        let (waker, waker_readiness) = Registration::new2();
+        poll.register(&waker, WAKER, Ready::readable(), PollOpt::edge())?;
+        let mut proto_handler = proto_handler(cfg);
+        let readiness = waker_readiness.clone();
+        let wake: IOWaker = Arc::new(move || {
+            let _ = readiness.set_readiness(Ready::readable());
+        });
+        proto_handler.set_waker(wake.clone());
+        proto_handler.set_clock(clock.clone());
+        clock.set_waker(wake);
+
+        let mut new_token = Token(2); // skip the control and waker tokens
+        for (socket, ctx) in sockets {
+            let socket = UdpSocket::from_socket(socket)?;
+            poll.register(
+                &socket,
+                new_token,
+                Ready::readable(),
+                PollOpt::edge() | PollOpt::level(),
+            )?;
+            server_sockets.insert(new_token, ServerSocket { socket, ctx });
+            new_token.0 += 1;
+        }
+
+        Ok(Self {
+            new_token,
+            poll,
+            timeout: cfg.timeout,
+            server_sockets,
+            connections: HashMap::new(),
+            sessions: HashMap::new(),
+            timers: BTreeSet::new(),
+            blocked_requests: vec![],
+            groups: GroupPool::new(&cfg.multicast),
+            proto_handler,
+            control_rx,
+            control_tx,
+            waker,
+            waker_readiness,
+            stop_deadline: None,
+            stopped: false,
+            clock,
+            workers: vec![],
+            #[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+            request_bufs: vec![vec![0; MAX_REQUEST_SIZE]; mmsg::RECV_BATCH],
+        })
+    }
+
+    /// Returns a handle that can be used to stop the server while it is running
+    pub fn handle(&self) -> ServerHandle {
+        self.handle_of(&self.workers)
+    }
+
+    /// Returns a handle stopping this server and the given workers
+    fn handle_of(&self, workers: &[Self]) -> ServerHandle {
+        let workers = workers.iter().map(|w| w.control_tx.clone());
+        ServerHandle {
+            txs: Some(self.control_tx.clone())
+                .into_iter()
+                .chain(workers)
+                .collect(),
+        }
+    }
+
+    /// Returns a new token created from incrementing a counter.
+    fn generate_token(&mut self) -> Token {
+        if self
+            .connections
+            .len()
+            .saturating_add(self.sessions.len())
+            .saturating_add(self.server_sockets.len())
+            .saturating_add(2 /* control and waker tokens */)
+            == usize::MAX
+        {
+            panic!("no more tokens, but impressive amount of memory");
+        }
+        while self.new_token == CONTROL
+            || self.new_token == WAKER
+            || self.server_sockets.contains_key(&self.new_token)
+            || self.connections.contains_key(&self.new_token)
+            || self.sessions.contains_key(&self.new_token)
+        {
+            self.new_token.0 = self.new_token.0.wrapping_add(1);
+        }
+        self.new_token
+    }
+
+    /// Cancels a connection given the connection's token.
+    /// It deregisters the connection's socket from the event loop.
+    fn cancel_connection(&mut self, token: Token) -> Result<()> {
+        if let Some(conn) = self.connections.remove(&token) {
+            self.timers.remove(&(conn.scheduled, token));
+            info!(
+                "Closing connection with token {:?} after {} retransmissions, estimated RTT {:?}",
+                token,
+                conn.state.retries,
+                conn.state.srtt()
+            );
+            self.poll.deregister(&conn.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Closes a multicast session, making its group available again
+    fn close_session(&mut self, token: Token) -> Result<()> {
+        if let Some(session) = self.sessions.remove(&token) {
+            self.timers.remove(&(session.scheduled, token));
+            info!(
+                "Closing multicast session with token {:?} to {}",
+                token, session.state.group
+            );
+            self.groups.release(session.state.group);
+            self.poll.deregister(&session.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a new UDP connection from the provided arguments
+    fn create_connection(
+        &mut self,
+        token: Token,
+        socket: UdpSocket,
+        transfer: Transfer<IO>,
+        packet: &[u8],
+        remote: SocketAddr,
+    ) -> Result<()> {
+        self.poll.register(
+            &socket,
+            token,
+            Ready::readable(),
+            PollOpt::edge() | PollOpt::level(),
+        )?;
+
+        let state =
+            ConnectionState::with_clock(transfer, packet, remote, self.timeout, self.clock.clone());
+        let scheduled = state.deadline;
+        self.timers.insert((scheduled, token));
+        self.connections.insert(
+            token,
+            Connection {
+                socket,
+                state,
+                scheduled,
+            },
+        );
+
+        info!("Created connection with token: {:?}", token);
+
+        Ok(())
+    }
+
+    /// Returns the earliest time at which a connection times out
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().next().map(|&(deadline, _)| deadline)
+    }
+
+    /// Moves the entry of a connection or session in `timers` to its current deadline
+    fn reschedule(&mut self, token: Token) {
+        let (scheduled, deadline) = if let Some(conn) = self.connections.get_mut(&token) {
+            (&mut conn.scheduled, conn.state.deadline)
+        } else if let Some(session) = self.sessions.get_mut(&token) {
+            (&mut session.scheduled, session.state.deadline)
+        } else {
+            return;
+        };
+        if *scheduled != deadline {
+            self.timers.remove(&(*scheduled, token));
+            self.timers.insert((deadline, token));
+            *scheduled = deadline;
+        }
+    }
+
+    /// Handles the connections whose timeout expired.
+    /// For each, it resends the last packets sent from the connection.
+    /// If the transfer associated with that connection is over,
+    /// it instead kills the connection.
+    fn process_timeouts(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let tokens = self
+            .timers
+            .iter()
+            .take_while(|&&(deadline, _)| deadline <= now)
+            .map(|&(_, token)| token)
+            .collect::<Vec<_>>();
+
+        for token in tokens {
+            let result = self.expire(token);
+            self.reschedule(token);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Handles the expired timeout of a connection or session
+    fn expire(&mut self, token: Token) -> Result<()> {
+        if let Some(conn) = self.connections.get_mut(&token) {
+            let remote = conn.state.remote;
+            match conn.state.expire()? {
+                Some(packets) => send_packets(&conn.socket, packets, remote),
+                None => self.cancel_connection(token),
+            }
+        } else if let Some(session) = self.sessions.get_mut(&token) {
+            match session.state.expire()? {
+                Some(packets) => send_datagrams(&session.socket, &packets),
+                None => self.close_session(token),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Handles stop requests received from `ServerHandle`s
+    fn process_control(&mut self, buf: &mut [u8]) -> Result<()> {
+        while let Ok(stop) = self.control_rx.try_recv() {
+            match stop {
+                Stop::Soft(deadline) => {
+                    info!("Soft stop requested, waiting up to {:?}", deadline);
+                    for (_, server_socket) in self.server_sockets.drain() {
+                        log_stop_error(self.poll.deregister(&server_socket.socket));
+                    }
+                    let deadline = self.clock.now() + deadline;
+                    self.stop_deadline = Some(match self.stop_deadline {
+                        Some(earlier) if earlier < deadline => earlier,
+                        _ => deadline,
+                    });
+                }
+                Stop::Hard => {
+                    info!("Hard stop requested");
+                    self.stop_all(buf);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Aborts all remaining connections and marks the server as stopped.
+    /// Failing to release a socket is only logged, so that the server stops anyway
+    fn stop_all(&mut self, buf: &mut [u8]) {
+        for (_, server_socket) in self.server_sockets.drain() {
+            log_stop_error(self.poll.deregister(&server_socket.socket));
+        }
+        log_stop_error(self.poll.deregister(&self.waker));
+        self.blocked_requests.clear();
+        let tokens = self.connections.keys().cloned().collect::<Vec<_>>();
+        for token in tokens {
+            if let Some(conn) = self.connections.get(&token) {
+                if !conn.state.transfer.is_done() {
+                    let packet = Packet::ERROR {
+                        code: ErrorCode::NotDefined,
+                        msg: "Server shutting down".to_owned(),
+                    };
+                    // the transfer is dropped anyway, so sending the error is best-effort
+                    if let Ok(amt) = packet.write_to_slice(buf) {
+                        let _ = conn.socket.send_to(&buf[..amt], &conn.state.remote);
+                    }
+                }
+            }
+            log_stop_error(self.cancel_connection(token));
+        }
+        let tokens = self.sessions.keys().cloned().collect::<Vec<_>>();
+        for token in tokens {
+            if let Some(session) = self.sessions.get(&token) {
+                let packet = Packet::ERROR {
+                    code: ErrorCode::NotDefined,
+                    msg: "Server shutting down".to_owned(),
+                };
+                // best-effort as well, every client listens to the group
+                if let Ok(amt) = packet.write_to_slice(buf) {
+                    let _ = session.socket.send_to(&buf[..amt], &session.state.group);
+                }
+            }
+            log_stop_error(self.close_session(token));
+        }
+        self.stopped = true;
+    }
+
+    /// Resumes the transfers waiting for file I/O, sending what they couldn't before
+    fn resume_blocked(&mut self, buf: &mut [u8]) -> Result<()> {
+        // before resuming, so that I/O completing meanwhile wakes the loop again
+        self.waker_readiness.set_readiness(Ready::empty())?;
+        for request in mem::take(&mut self.blocked_requests) {
+            let result = self.handle_request(
+                request.packet,
+                request.src,
+                request.local_addr,
+                &request.ctx,
+                buf,
+            );
+            log_error(result);
+        }
+        let connections = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.state.transfer.is_blocked())
+            .map(|(&token, _)| token);
+        let sessions = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.state.is_blocked())
+            .map(|(&token, _)| token);
+        let tokens = connections.chain(sessions).collect::<Vec<_>>();
+        // a failing transfer must not keep the others waiting
+        for token in tokens {
+            let result = self.resume(token);
+            self.reschedule(token);
+            log_error(result);
+        }
+        Ok(())
+    }
+
+    /// Resumes a connection or session waiting for file I/O
+    fn resume(&mut self, token: Token) -> Result<()> {
+        if let Some(conn) = self.connections.get_mut(&token) {
+            let remote = conn.state.remote;
+            send_packets(&conn.socket, conn.state.resume()?, remote)
+        } else if let Some(session) = self.sessions.get_mut(&token) {
+            send_datagrams(&session.socket, &session.state.resume()?)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Called to process an available I/O event for a token.
+    /// Normally these correspond to packets received on a socket or to a timeout
+    fn handle_token(&mut self, token: Token, buf: &mut [u8]) -> Result<()> {
+        match token {
+            CONTROL => self.process_control(buf),
+            // deadlines are checked after every poll, so a moved clock needs nothing more
+            WAKER => self.resume_blocked(buf),
+            _ if self.server_sockets.contains_key(&token) => self.handle_server_packet(token, buf),
+            _ => {
+                let result = if self.sessions.contains_key(&token) {
+                    self.handle_session_packet(token, buf)
+                } else {
+                    self.handle_connection_packet(token, buf)
+                };
+                self.reschedule(token);
+                result
+            }
+        }
+    }
+
+    fn handle_server_packet(&mut self, token: Token, buf: &mut [u8]) -> Result<()> {
+        let server_socket = match self.server_sockets.get(&token) {
+            Some(server_socket) => server_socket,
+            None => {
+                error!("Invalid server token");
+                return Ok(());
+            }
+        };
+        let ctx = server_socket.ctx.clone();
+        #[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+        let requests = recv_requests(&server_socket.socket, &mut self.request_bufs)?;
+        #[cfg(not(all(feature = "mmsg", any(target_os = "linux", target_os = "android"))))]
+        let requests = recv_request(&server_socket.socket, buf)?;
+
+        for (packet, src, local_addr) in requests {
+            let result = packet.and_then(|packet| match packet {
+                Some(packet) => self.handle_request(packet, src, local_addr, &ctx, buf),
+                None => refuse_request(src, local_addr, buf),
+            });
+            log_error(result);
+        }
+        Ok(())
+    }
+
+    /// Replies to a RRQ or WRQ packet, creating a connection for the transfer it requests
+    fn handle_request(
+        &mut self,
+        packet: Packet,
+        src: SocketAddr,
+        local_addr: SocketAddr,
+        ctx: &RequestCtx,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let ctx = &RequestCtx {
+            client: Some(src.ip()),
+            ..ctx.clone()
+        };
+        if multicast::requested(&packet) {
+            let proto = &self.proto_handler;
+            let session = self.sessions.iter_mut().find(|(_, session)| {
+                session.state.accepts(proto, &packet, ctx)
+                    && session.socket.local_addr().ok().map(|addr| addr.ip())
+                        == Some(local_addr.ip())
+            });
+            if let Some((&token, session)) = session {
+                let result = session
+                    .state
+                    .join(src)
+                    .map_err(TftpError::from)
+                    .and_then(|packets| send_datagrams(&session.socket, &packets));
+                if result.is_ok() {
+                    info!(
+                        "{} joined the multicast session to {}, now with {} clients",
+                        src,
+                        session.state.group,
+                        session.state.clients()
+                    );
+                }
+                self.reschedule(token);
+                return result;
+            }
+            if let Some(group) = self.groups.take(local_addr.ip()) {
+                let result = self.start_session(packet, src, local_addr, ctx, group);
+                if let Err(e) = result {
+                    self.groups.release(group);
+                    return Err(e);
+                }
+                return Ok(());
+            }
+            // all groups are in use, so the option is ignored
+        }
+
+        let new_conn_token = self.generate_token();
+        let (xfer, res) = self.proto_handler.rx_initial_with_ctx(packet.clone(), ctx);
+        let reply_packet = match res {
+            Err(tftp_proto::TftpError::WouldBlock) => {
+                self.block_request(packet, src, local_addr, ctx);
+                return Ok(());
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                return Ok(());
+            }
+            Ok(packet) => packet,
+        };
+
+        let socket = make_bound_socket(local_addr)?;
+
+        // send packet back for all cases
+        let amt = reply_packet.write_to_slice(buf)?;
+        socket.send_to(&buf[..amt], &src)?;
+
+        if let Some(xfer) = xfer {
+            self.create_connection(new_conn_token, socket, xfer, &buf[..amt], src)?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps a request until the IOAdapter opened its file
+    fn block_request(
+        &mut self,
+        packet: Packet,
+        src: SocketAddr,
+        local_addr: SocketAddr,
+        ctx: &RequestCtx,
+    ) {
+        debug!("Request from {} waits for its file to be opened", src);
+        self.blocked_requests.push(BlockedRequest {
+            packet,
+            src,
+            local_addr,
+            ctx: ctx.clone(),
+        });
+    }
+
+    /// Starts sending a file to a multicast group, with `src` as the master client.
+    /// The group is released by the caller on error
+    fn start_session(
+        &mut self,
+        packet: Packet,
+        src: SocketAddr,
+        local_addr: SocketAddr,
+        ctx: &RequestCtx,
+        group: SocketAddr,
+    ) -> Result<()> {
+        let socket = make_bound_socket(local_addr)?;
+        let (state, reply) = MulticastSession::with_clock(
+            &mut self.proto_handler,
+            packet.clone(),
+            ctx,
+            group,
+            src,
+            self.timeout,
+            self.clock.clone(),
+        );
+        let reply = match reply {
+            Err(tftp_proto::TftpError::WouldBlock) => {
+                // a group is taken again when the request is handled again
+                self.groups.release(group);
+                self.block_request(packet, src, local_addr, ctx);
+                return Ok(());
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                self.groups.release(group);
+                return Ok(());
+            }
+            Ok(reply) => reply,
+        };
+        socket.send_to(&reply.into_bytes()?, &src)?;
+
+        match state {
+            Some(state) => {
+                let token = self.generate_token();
+                self.poll.register(
+                    &socket,
+                    token,
+                    Ready::readable(),
+                    PollOpt::edge() | PollOpt::level(),
+                )?;
+                let scheduled = state.deadline;
+                self.timers.insert((scheduled, token));
+                self.sessions.insert(
+                    token,
+                    Session {
+                        socket,
+                        state,
+                        scheduled,
+                    },
+                );
+                info!(
+                    "Created multicast session with token {:?} to {}",
+                    token, group
+                );
+            }
+            None => self.groups.release(group),
+        }
+        Ok(())
+    }
+
+    fn handle_session_packet(&mut self, token: Token, buf: &mut [u8]) -> Result<()> {
+        let session = match self.sessions.get_mut(&token) {
+            Some(session) => session,
+            None => {
+                error!("No multicast session with token {:?}", token);
+                return Ok(());
+            }
+        };
+        let (amt, src) = session.socket.recv_from(buf)?;
+        let packet = PacketRef::read(&buf[..amt])?;
+        let packets = session
+            .state
+            .receive(&mut self.proto_handler, src, packet)?;
+        send_datagrams(&session.socket, &packets)?;
+        if session.state.is_done() {
+            self.close_session(token)?;
+        }
+        Ok(())
+    }
+
+    fn handle_connection_packet(&mut self, token: Token, buf: &mut [u8]) -> Result<()> {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => {
+                error!("No connection with token {:?}", token);
+                return Ok(());
+            }
+        };
+        let (amt, src) = conn.socket.recv_from(buf)?;
+
+        if conn.state.remote != src {
+            // packet from somehere else, reply with error
+            let amt = Packet::from(ErrorCode::UnknownID).write_to_slice(buf)?;
+            conn.socket.send_to(&buf[..amt], &src)?;
+            return Ok(());
+        }
+        // only the client shows it is still there
+        conn.state.reset_timeout();
+        let packet = PacketRef::read(&buf[..amt])?;
+        send_packets(&conn.socket, conn.state.receive(packet)?, src)
+    }
+
+    /// Runs the server's event loop on the calling thread.
+    ///
+    /// Returns `Ok(())` once the server is stopped via a `ServerHandle`.
+    /// A stopped server no longer listens on its addresses and cannot be run again.
+    /// Servers with several `workers` fail here, and are run by `run_workers` instead.
+    pub fn run(&mut self) -> Result<()> {
+        if !self.workers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "servers with several workers are run by run_workers",
+            )
+            .into());
+        }
+        self.run_worker()
+    }
+
+    /// Runs the event loop of a single worker, until it is stopped
+    fn run_worker(&mut self) -> Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let mut scratch_buf = vec![0; MAX_PACKET_SIZE];
+
+        while !self.stopped {
+            let deadline = match (self.stop_deadline, self.next_deadline()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            let poll_timeout = deadline.and_then(|deadline| self.clock.wait_until(deadline));
+            self.poll.poll(&mut events, poll_timeout)?;
+
+            for event in events.iter() {
+                let result = self.handle_token(event.token(), &mut scratch_buf);
+                log_error(result);
+                if self.stopped {
+                    break;
+                }
+            }
+            if self.stopped {
+                break;
+            }
+            let result = self.process_timeouts();
+            log_error(result);
+
+            if let Some(deadline) = self.stop_deadline {
+                let drained = self
+                    .connections
+                    .values()
+                    .all(|c| c.state.transfer.is_done())
+                    && self.sessions.is_empty()
+                    && self.blocked_requests.is_empty();
+                if drained || self.clock.now() >= deadline {
+                    self.stop_all(&mut scratch_buf);
+                }
+            }
+        }
+        info!("Server stopped");
+        Ok(())
+    }
+
+    /// Stores the local addresses in the provided vec
+    pub fn get_local_addrs(&self, bag: &mut Vec<SocketAddr>) -> Result<()> {
+        for server_socket in self.server_sockets.values() {
+            bag.push(server_socket.socket.local_addr()?);
+        }
+        Ok(())
+    }
+}
+
+impl<IO, C> TftpServerImpl<IO, C>
+where
+    IO: IOAdapter + Default + Send,
+    IO::R: Send,
+    IO::W: Send,
+    C: Clock,
+{
+    /// Like `run`, also running the event loops of the other workers on their own threads
+    pub fn run_workers(&mut self) -> Result<()> {
+        let mut workers = mem::take(&mut self.workers);
+        let handle = self.handle_of(&workers);
+        let result = thread::scope(|scope| {
+            let threads = workers
+                .iter_mut()
+                .enumerate()
+                .map(|(i, worker)| {
+                    thread::Builder::new()
+                        .name(format!("tftp-worker-{}", i + 1))
+                        .spawn_scoped(scope, move || worker.run_worker())
+                })
+                .collect::<io::Result<Vec<_>>>();
+            let threads = match threads {
+                Ok(threads) => threads,
+                Err(e) => {
+                    // the workers already started must not keep the scope waiting
+                    handle.stop_hard();
+                    return Err(e.into());
+                }
+            };
+
+            let result = self.run_worker();
+            for thread in threads {
+                let worker_result = thread.join().expect("worker thread panicked");
+                log_error(worker_result);
+            }
+            result
+        });
+        self.workers = workers;
+        result
+    }
+}
+
+/// Logs an error met while stopping, which must not keep the server running
+fn log_stop_error<E: Into<TftpError>>(result: result::Result<(), E>) {
+    if let Err(e) = result {
+        warn!("Error while stopping: {:?}", e.into());
+    }
+}
+
+/// A RRQ or WRQ packet received on a server socket, or `None` if it was too long,
+/// its source, and the local address to reply from
+type Request = (Result<Option<Packet>>, SocketAddr, SocketAddr);
+
+/// Parses a request, unless it is longer than any request may be
+fn read_request(bytes: &[u8]) -> Result<Option<Packet>> {
+    if bytes.len() > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+    Ok(Some(Packet::read(bytes)?))
+}
+
+/// Answers a request that was too long with an error, rather than ignoring it
+fn refuse_request(src: SocketAddr, local_addr: SocketAddr, buf: &mut [u8]) -> Result<()> {
+    debug!("Request from {} is too long", src);
+    let packet = Packet::ERROR {
+        code: ErrorCode::IllegalTFTP,
+        msg: "Request too long".to_owned(),
+    };
+    let amt = packet.write_to_slice(buf)?;
+    make_bound_socket(local_addr)?.send_to(&buf[..amt], &src)?;
+    Ok(())
+}
+
+/// Returns the address to reply from for a request sent to `dst`
+fn reply_addr(socket: &UdpSocket, dst: Option<SocketAddr>) -> Result<SocketAddr> {
+    // when bound to a wildcard address, reply from the one the client contacted
+    Ok(match dst {
+        Some(dst) => dst,
+        None => SocketAddr::new(socket.local_addr()?.ip(), 0),
+    })
+}
+
+#[cfg(not(all(feature = "mmsg", any(target_os = "linux", target_os = "android"))))]
+fn recv_request(socket: &UdpSocket, buf: &mut [u8]) -> Result<Vec<Request>> {
+    let (amt, src, dst) = pktinfo::recv_from_to(socket, buf)?;
+    Ok(vec![(
+        read_request(&buf[..amt]),
+        src,
+        reply_addr(socket, dst)?,
+    )])
+}
+
+/// Receives all queued requests, with as few system calls as possible
+#[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+fn recv_requests(socket: &UdpSocket, bufs: &mut [Vec<u8>]) -> Result<Vec<Request>> {
+    let mut requests = vec![];
+    loop {
+        let received = match mmsg::recv_batch(socket, bufs) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && !requests.is_empty() => {
+                return Ok(requests)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let full = received.len() == bufs.len();
+        for ((amt, src, dst), buf) in received.into_iter().zip(bufs.iter()) {
+            // truncated, so too long as well
+            let packet = amt.map_or(Ok(None), |amt| read_request(&buf[..amt]));
+            requests.push((packet, src, reply_addr(socket, dst)?));
+        }
+        if !full {
+            return Ok(requests);
+        }
+    }
+}
+
+/// Sends the packets of a connection, batching them if supported
+fn send_packets(socket: &UdpSocket, packets: &[Vec<u8>], remote: SocketAddr) -> Result<()> {
+    #[cfg(all(feature = "mmsg", any(target_os = "linux", target_os = "android")))]
+    mmsg::send_all(socket, packets, remote)?;
+    #[cfg(not(all(feature = "mmsg", any(target_os = "linux", target_os = "android"))))]
+    for pkt in packets {
+        socket.send_to(pkt, &remote)?;
+    }
+    Ok(())
+}
+
+/// Sends packets to their own destinations
+fn send_datagrams(socket: &UdpSocket, datagrams: &[(SocketAddr, Vec<u8>)]) -> Result<()> {
+    for (remote, pkt) in datagrams {
+        socket.send_to(pkt, remote)?;
+    }
+    Ok(())
+}
+
+fn make_bound_socket(addr: SocketAddr) -> Result<UdpSocket> {
+    Ok(UdpSocket::from_socket(bind_socket(addr)?)?)
+}
@@ -1,28 +1,256 @@
-use crate::packet::{ErrorCode, Packet, TftpOption};
-use sna::SerialNumber;
+//! The TFTP protocol state machine, without any networking or timers ("sans-IO"),
+//! for serving files from a custom event loop or network stack.
+//!
+//! A `TftpServerProto` accepts RRQ and WRQ packets via `rx_initial`, which returns
+//! the reply to send and, when the request is accepted, the `Transfer` handling every
+//! later packet from that client. Replies come as a `Response`, a sequence of
+//! `ResponseItem`s: packets to send, repetitions of previously sent ones, or the end
+//! of the transfer. DATA packets come serialized, read into the buffers given back
+//! through `Transfer::recycle`. Files are accessed through an `IOAdapter`.
+//!
+//! `connection::ConnectionState` keeps the serialized packets for retransmission
+//! and computes the deadline of every transfer, so that the caller only has to
+//! move datagrams and wait until the deadline:
+//!
+//! ```
+//! use std::time::Duration;
+//! use tftp_server::connection::ConnectionState;
+//! use tftp_server::packet::{Packet, PacketRef, TransferMode};
+//! use tftp_server::tftp_proto::{FSAdapter, TftpServerProto};
+//!
+//! let mut proto = TftpServerProto::new(FSAdapter, Default::default());
+//! let request = Packet::RRQ {
+//!     filename: "files/hello.txt".into(),
+//!     mode: TransferMode::Octet,
+//!     options: vec![],
+//! };
+//! let (transfer, reply) = proto.rx_initial(request);
+//! // always sent back to the client, from a new port dedicated to the transfer
+//! let reply = reply.unwrap().into_bytes().unwrap();
+//!
+//! let client = "127.0.0.1:5000".parse().unwrap();
+//! let timeout = Duration::from_secs(3);
+//! let mut conn = ConnectionState::new(transfer.unwrap(), &reply, client, timeout);
+//! let mut sent = vec![reply];
+//! while !conn.transfer.is_done() {
+//!     // the client acknowledges every DATA packet before `conn.deadline`,
+//!     // otherwise `conn.expire` returns what to resend
+//!     let ack = match PacketRef::read(sent.last().unwrap()).unwrap() {
+//!         PacketRef::DATA { block_num, .. } => PacketRef::ACK(block_num),
+//!         p => panic!("unexpected {:?}", p),
+//!     };
+//!     sent = conn.receive(ack).unwrap().to_vec();
+//! }
+//! // the connection closes once its last deadline passes
+//! ```
+
+use crate::clock::Clock;
+use crate::netascii::{ModeReader, ModeWriter, NetasciiReader, NetasciiWriter};
+use crate::packet::{
+    write_data_header, ErrorCode, MulticastGroup, Packet, PacketRef, TftpOption, TransferMode,
+    DATA_HEADER_LEN, MAX_BLOCKSIZE,
+};
+use crate::remap::Rules;
+use std::borrow::Cow;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Component, Path, PathBuf};
-use std::time::Duration;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// Errors for packets the protocol cannot handle at all,
+/// as opposed to those answered with an ERROR packet
 #[derive(Debug, PartialEq)]
 pub enum TftpError {
-    /// The is already running and cannot be restarted
+    /// The transfer is already running and cannot be restarted
     TransferAlreadyRunning,
 
     /// The received packet type cannot be used to initiate a transfer
     NotInitiatingPacket,
+
+    /// The IOAdapter is still opening the requested file, so the request
+    /// must be handled again once its waker was called
+    WouldBlock,
 }
 
 /// Trait used to inject filesystem IO handling into a server.
 /// A trivial default implementation is provided by `FSAdapter`.
 /// If you want to employ things like buffered IO, it can be done by providing
 /// an implementation for this trait and passing the implementing type to the server.
+///
+/// Readers and writers may fail with `io::ErrorKind::WouldBlock` instead of blocking,
+/// in which case the transfer waits until the waker passed to `set_waker` is called.
+/// A writer doing so must not have accepted any of the data it was given,
+/// and the first read of a file must not fail this way.
+/// Opening or creating a file may fail this way too, after which the request
+/// is handled again, for the same path, once the waker was called.
+///
+/// Writers are committed once the whole file was received, see `Commit`.
 pub trait IOAdapter {
     type R: Read + Sized;
-    type W: Write + Sized;
+    type W: Commit + Sized;
+    /// Opens a file for reading, returning its size if known
     fn open_read(&self, file: &Path) -> io::Result<(Self::R, Option<u64>)>;
+    /// Creates a file that doesn't exist yet, given the size announced by the client
     fn create_new(&mut self, file: &Path, len: Option<u64>) -> io::Result<Self::W>;
+    /// Creates a file, replacing an existing one only as far as `policy` allows.
+    /// Adapters that can't replace files keep the default, which never does
+    fn create(
+        &mut self,
+        file: &Path,
+        len: Option<u64>,
+        _policy: WritePolicy,
+    ) -> io::Result<Self::W> {
+        self.create_new(file, len)
+    }
+    /// Returns the size a file has once translated to netascii, or `None` if it can't
+    /// be read. This needs a pass over the whole file, which may fail with
+    /// `io::ErrorKind::WouldBlock` like opening it
+    fn netascii_size(&self, file: &Path) -> io::Result<Option<u64>> {
+        Ok(match self.open_read(file) {
+            Ok((fread, _)) => io::copy(&mut NetasciiReader::new(fread), &mut io::sink()).ok(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            Err(_) => None,
+        })
+    }
+    /// Returns the names of the entries in a directory, to find requested files
+    /// regardless of case (see `IOPolicyCfg::case_insensitive`)
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect()
+    }
+    /// Returns the path of `file` within `dir`, with each of its components replaced
+    /// by the entry of `list_dir` matching it regardless of case, if any.
+    /// An exact match wins, several others fail the lookup.
+    /// This may fail with `io::ErrorKind::WouldBlock` like opening a file
+    fn find_ignoring_case(&self, dir: &Path, file: &Path) -> io::Result<PathBuf> {
+        let mut full = dir.to_owned();
+        for component in file.components() {
+            if let Component::Normal(name) = component {
+                let listed = if full.as_os_str().is_empty() {
+                    self.list_dir(Path::new("."))
+                } else {
+                    self.list_dir(&full)
+                };
+                let found = match listed {
+                    Ok(entries) => find_entry(name, entries)?,
+                    Err(_) => None,
+                };
+                full.push(found.as_deref().unwrap_or(name));
+            }
+        }
+        Ok(full)
+    }
+    /// Receives the function to call whenever a reader or writer that failed with
+    /// `io::ErrorKind::WouldBlock` may be able to make progress
+    fn set_waker(&mut self, _waker: IOWaker) {}
+}
+
+/// Wakes up a server waiting for non-blocking file I/O, see `IOAdapter::set_waker`
+pub type IOWaker = Arc<dyn Fn() + Send + Sync>;
+
+/// Told about the end of every transfer, see `TftpServerProto::set_observer`.
+/// The path is that of the file in the served directory
+pub trait TransferObserver: Send + Sync {
+    /// Called once a transfer succeeded, with the number of data bytes sent or received,
+    /// and the time since it was requested. Uploads are already committed by then
+    fn on_complete(&self, _file: &Path, _is_write: bool, _bytes: u64, _duration: Duration) {}
+    /// Called once a transfer failed
+    fn on_abort(&self, _file: &Path, _is_write: bool, _reason: &AbortReason) {}
+}
+
+/// Why a transfer failed, see `TransferObserver::on_abort`
+#[derive(Clone, Debug, PartialEq)]
+pub enum AbortReason {
+    /// The client stopped replying
+    Timeout,
+    /// The client sent an ERROR packet
+    ClientError(ErrorCode, String),
+    /// The server sent an ERROR packet, e.g. because the file could not be read or written
+    ServerError(ErrorCode, String),
+    /// The transfer was dropped before it was over, e.g. by a server shutting down
+    Dropped,
+}
+
+/// Reports the end of a transfer to the observer, as a failure if it is dropped first
+struct Lifecycle {
+    observer: Arc<dyn TransferObserver>,
+    file: PathBuf,
+    is_write: bool,
+    now: Now,
+    started: Instant,
+    reported: bool,
+}
+
+impl Lifecycle {
+    fn complete(&mut self, bytes: u64) {
+        self.reported = true;
+        let duration = (self.now)().saturating_duration_since(self.started);
+        self.observer
+            .on_complete(&self.file, self.is_write, bytes, duration);
+    }
+
+    fn abort(&mut self, reason: AbortReason) {
+        self.reported = true;
+        self.observer.on_abort(&self.file, self.is_write, &reason);
+    }
+}
+
+impl fmt::Debug for Lifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lifecycle")
+            .field("file", &self.file)
+            .field("started", &self.started)
+            .finish()
+    }
+}
+
+impl Drop for Lifecycle {
+    fn drop(&mut self) {
+        if !self.reported {
+            self.abort(AbortReason::Dropped);
+        }
+    }
+}
+
+/// The writer of an uploaded file, told when the upload succeeded.
+/// Writers dropped without being committed belong to failed uploads
+pub trait Commit: Write {
+    /// Called once all the data was written and flushed, before the last block is
+    /// acknowledged. Failing, or `io::ErrorKind::WouldBlock`, is handled like for writes
+    fn commit(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Commit for File {}
+
+/// What write requests do with files that already exist
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WritePolicy {
+    /// Refuse to replace them
+    #[default]
+    Never,
+    /// Replace them
+    Overwrite,
+    /// Replace only those writable by all users, as in tftpd-hpa
+    OverwriteWorldWritable,
+    /// Replace them, keeping the previous versions with a number appended:
+    /// `name.1`, `name.2`...
+    KeepNumbered,
+    /// Replace them, keeping the previous versions with their modification time
+    /// appended, e.g. `name.20240131T235959Z` (in UTC)
+    KeepTimestamped,
 }
 
 /// Provides a simple, default implementation for `IOAdapter`.
@@ -30,44 +258,408 @@ pub struct FSAdapter;
 
 impl IOAdapter for FSAdapter {
     type R = File;
-    type W = File;
+    type W = AtomicFile;
     fn open_read(&self, file: &Path) -> io::Result<(File, Option<u64>)> {
+        if is_upload_temp(file) {
+            // incomplete until renamed
+            return Err(io::ErrorKind::NotFound.into());
+        }
         let f = File::open(file)?;
         let len = f.metadata().ok().map(|meta| meta.len());
         Ok((f, len))
     }
-    fn create_new(&mut self, file: &Path, len: Option<u64>) -> io::Result<File> {
-        let f = fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(file)?;
+    fn create_new(&mut self, file: &Path, len: Option<u64>) -> io::Result<AtomicFile> {
+        self.create(file, len, WritePolicy::Never)
+    }
+    fn create(
+        &mut self,
+        file: &Path,
+        len: Option<u64>,
+        policy: WritePolicy,
+    ) -> io::Result<AtomicFile> {
+        if let Ok(meta) = file.symlink_metadata() {
+            if !may_replace(&meta, policy) {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+        }
+        let f = AtomicFile::create(file, policy)?;
         if let Some(l) = len {
-            f.set_len(l)?;
+            f.file.set_len(l)?;
         }
         Ok(f)
     }
 }
 
+/// Distinguishes the temporary files of concurrent uploads
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether an existing file may be replaced under the given policy
+fn may_replace(meta: &fs::Metadata, policy: WritePolicy) -> bool {
+    match policy {
+        _ if !meta.is_file() => false,
+        WritePolicy::Never => false,
+        WritePolicy::OverwriteWorldWritable => world_writable(meta),
+        _ => true,
+    }
+}
+
+#[cfg(unix)]
+fn world_writable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o002 != 0
+}
+
+#[cfg(not(unix))]
+fn world_writable(meta: &fs::Metadata) -> bool {
+    !meta.permissions().readonly()
+}
+
+/// A file written under a hidden temporary name in the same directory,
+/// which only gets its actual name once committed, and is removed otherwise
+pub struct AtomicFile {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    policy: WritePolicy,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Creates the temporary file for `path`, which replaces an existing file
+    /// when committed as far as `policy` allows
+    pub fn create(path: &Path, policy: WritePolicy) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let count = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+        let mut temp_name = OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".{}-{}.part", process::id(), count));
+        let temp = path.with_file_name(temp_name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        Ok(AtomicFile {
+            file,
+            temp,
+            path: path.to_owned(),
+            policy,
+            committed: false,
+        })
+    }
+
+    /// Moves the file over the existing one, which gets a new name first if it is kept,
+    /// and whose permissions are preserved
+    fn replace(&self, old: &fs::Metadata) -> io::Result<()> {
+        if !may_replace(old, self.policy) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        fs::set_permissions(&self.temp, old.permissions())?;
+        let suffix = match self.policy {
+            WritePolicy::KeepNumbered => Some(String::new()),
+            WritePolicy::KeepTimestamped => Some(timestamp(old.modified()?)),
+            _ => None,
+        };
+        if let Some(suffix) = suffix {
+            // the file stays in place until the new one replaces it
+            loop {
+                let kept = self.version_path(&suffix);
+                match fs::hard_link(&self.path, &kept) {
+                    Ok(()) => break,
+                    // another upload kept its version under that name meanwhile
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                    // the file was removed meanwhile, so there is nothing left to keep
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                    Err(e) if links_unsupported(&e) => match fs::copy(&self.path, &kept) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => break,
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        fs::rename(&self.temp, &self.path)
+    }
+
+    /// Returns a free name for a previous version of the file: the name followed by
+    /// `.suffix` if not taken, and by the lowest number not taken otherwise
+    fn version_path(&self, suffix: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        if !suffix.is_empty() {
+            name.push(".");
+            name.push(suffix);
+            let path = self.path.with_file_name(&name);
+            if path.symlink_metadata().is_err() {
+                return path;
+            }
+        }
+        (1..)
+            .map(|n| {
+                let mut numbered = name.clone();
+                numbered.push(format!(".{}", n));
+                self.path.with_file_name(numbered)
+            })
+            .find(|path| path.symlink_metadata().is_err())
+            .unwrap()
+    }
+}
+
+/// Formats a time as `YYYYMMDDTHHMMSSZ`, in UTC
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = ((secs / 86_400) as i64, secs % 86_400);
+    // the civil calendar date of a day number, as computed by Howard Hinnant
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Whether a file is named like the temporary file of an upload, see `AtomicFile::create`
+fn is_upload_temp(path: &Path) -> bool {
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .and_then(|name| name.strip_prefix('.')?.strip_suffix(".part"))
+        .and_then(|name| name.rsplit_once('.'))
+        .and_then(|(_, id)| id.split_once('-'))
+        .is_some_and(|(pid, count)| is_number(pid) && is_number(count))
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Commit for AtomicFile {
+    /// Moves the file into place, unless another one got there first
+    /// which the write policy doesn't allow replacing
+    fn commit(&mut self) -> io::Result<()> {
+        // the data must be on disk before the name points to it
+        self.file.sync_all()?;
+        if self.policy != WritePolicy::Never {
+            if let Ok(old) = self.path.symlink_metadata() {
+                self.replace(&old)?;
+                self.committed = true;
+                return sync_parent(&self.path);
+            }
+        }
+        match fs::hard_link(&self.temp, &self.path) {
+            Ok(()) => fs::remove_file(&self.temp)?,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+            Err(e) if !links_unsupported(&e) => return Err(e),
+            Err(_) if self.path.symlink_metadata().is_ok() => {
+                return Err(io::ErrorKind::AlreadyExists.into())
+            }
+            Err(_) => fs::rename(&self.temp, &self.path)?,
+        }
+        self.committed = true;
+        sync_parent(&self.path)
+    }
+}
+
+/// Tells whether linking failed because the filesystem doesn't support links,
+/// as FAT for one reports with a permission error
+fn links_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// Makes the names in the directory of `path` durable
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for syncing elsewhere, so names are as durable as they get
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
 impl Default for FSAdapter {
     fn default() -> Self {
         FSAdapter
     }
 }
 
+/// Settings applied to every transfer started by a `TftpServerProto`
+#[derive(Clone, Debug)]
+pub struct TransferCfg {
+    /// The number of consecutive retransmissions allowed before a transfer is abandoned
+    pub max_retries: u32,
+    /// If set, the retransmission timeout doubles after every retry,
+    /// but never grows beyond this interval
+    pub max_backoff: Option<Duration>,
+    /// Whether reads with a negotiated `windowsize` start with a window of a single block,
+    /// growing it on acknowledged windows and shrinking it on loss.
+    /// Relies on clients acknowledging incomplete windows after their timeout, as RFC 7440 requires
+    pub congestion_control: bool,
+    /// What follows block number 65535, unless the client negotiates the `rollover` option
+    pub rollover: Rollover,
+}
+
+impl Default for TransferCfg {
+    fn default() -> Self {
+        TransferCfg {
+            max_retries: 1,
+            max_backoff: None,
+            congestion_control: false,
+            rollover: Rollover::ToZero,
+        }
+    }
+}
+
+/// The block number following 65535, for files of more blocks than that
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rollover {
+    /// Block 0, as most clients expect
+    ToZero,
+    /// Block 1, since block 0 is otherwise only used to acknowledge a request
+    ToOne,
+    /// None, so transfers of more than 65535 blocks fail
+    Forbidden,
+}
+
+impl Rollover {
+    /// The value negotiated via the `rollover` option
+    fn from_option(value: u8) -> Self {
+        if value == 1 {
+            Rollover::ToOne
+        } else {
+            Rollover::ToZero
+        }
+    }
+}
+
+/// Details about the circumstances in which a transfer is requested
+#[derive(Clone, Debug)]
+pub struct RequestCtx {
+    /// The largest block size that may be negotiated,
+    /// usually so that DATA packets fit in the MTU of the receiving interface
+    pub max_blocksize: u16,
+    /// The multicast group offered to read requests with the `multicast` option,
+    /// see the `multicast` module. Without one, the option is ignored
+    pub multicast: Option<SocketAddr>,
+    /// The address of the client, for the rules rewriting file names
+    pub client: Option<IpAddr>,
+}
+
+impl Default for RequestCtx {
+    fn default() -> Self {
+        RequestCtx {
+            max_blocksize: MAX_BLOCKSIZE,
+            multicast: None,
+            client: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct TransferMeta {
     blocksize: u16,
-    timeout: Option<u8>,
-    timed_out: bool,
+    timeout: Option<Duration>,
+    /// Retransmissions since the last packet that advanced the transfer
+    retries: u32,
     window_size: u16,
+    rollover: Rollover,
+    cfg: TransferCfg,
+    /// Reports the end of the transfer, if anyone is interested
+    lifecycle: Option<Lifecycle>,
+}
+
+impl TransferMeta {
+    /// Returns the block number following `block`
+    fn next_block(&self, block: u16) -> u16 {
+        self.add_blocks(block, 1)
+    }
+
+    /// Returns the block number `count` blocks after `block`
+    fn add_blocks(&self, block: u16, count: u16) -> u16 {
+        let sum = block.wrapping_add(count);
+        if self.rollover == Rollover::ToOne && sum < block {
+            // block 0 is skipped
+            sum + 1
+        } else {
+            sum
+        }
+    }
+
+    /// Returns how many blocks `later` comes after `block`, wrapping past 65535.
+    /// The result is larger than any window if `later` actually comes before `block`
+    fn blocks_between(&self, block: u16, later: u16) -> u16 {
+        let diff = later.wrapping_sub(block);
+        if self.rollover == Rollover::ToOne && later < block {
+            diff - 1
+        } else {
+            diff
+        }
+    }
+
+    /// Checks whether a file of the given size needs more block numbers than allowed
+    fn too_large(&self, size: u64) -> bool {
+        self.rollover == Rollover::Forbidden
+            && size / u64::from(self.blocksize) >= u64::from(u16::MAX)
+    }
+}
+
+/// The error ending transfers that need more than 65535 blocks without rollover
+fn rollover_error() -> Packet {
+    Packet::ERROR {
+        code: ErrorCode::NotDefined,
+        msg: "File too large for the block numbers".to_owned(),
+    }
 }
 
 /// The TFTP protocol and filesystem usage implementation,
 /// used as backend for a TFTP server
 pub struct TftpServerProto<IO: IOAdapter> {
     io_proxy: IOPolicyProxy<IO>,
+    xfer_cfg: TransferCfg,
+    observer: Option<Arc<dyn TransferObserver>>,
+    /// The time source for the durations reported to the observer
+    now: Now,
 }
 
+/// Reads the current time from a `Clock`
+type Now = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+/// The items to handle in reply to an event, in order
 #[derive(Debug)]
 pub struct Response {
     p: Vec<ResponseItem>,
@@ -95,31 +687,86 @@ impl From<Vec<ResponseItem>> for Response {
     }
 }
 
+/// A single action in reply to an event
 #[derive(Debug, PartialEq)]
 pub enum ResponseItem {
+    /// A packet to send to the client
     Packet(Packet),
+    /// A DATA packet to send to the client, already serialized into a buffer
+    /// given to the transfer through `Transfer::recycle`, or a new one
+    Data(Vec<u8>),
+    /// The transfer is over, and nothing further is sent
     Done,
+    /// Resend the given number of packets, the last ones sent
     RepeatLast(usize),
 }
 
 impl<IO: IOAdapter> TftpServerProto<IO> {
     /// Creates a new instance with the provided IOAdapter
     pub fn new(io: IO, cfg: IOPolicyCfg) -> Self {
+        Self::with_transfer_cfg(io, cfg, Default::default())
+    }
+
+    /// Creates a new instance with the provided IOAdapter,
+    /// starting all transfers with the given settings
+    pub fn with_transfer_cfg(io: IO, cfg: IOPolicyCfg, xfer_cfg: TransferCfg) -> Self {
         TftpServerProto {
             io_proxy: IOPolicyProxy::new(io, cfg),
+            xfer_cfg,
+            observer: None,
+            now: Arc::new(Instant::now),
         }
     }
 
+    /// Returns the settings every transfer starts with
+    pub(crate) fn transfer_cfg(&self) -> &TransferCfg {
+        &self.xfer_cfg
+    }
+
+    /// Applies the remapping rules to a requested path, returning `None` if it is denied
+    pub(crate) fn remap(
+        &self,
+        filename: &str,
+        is_write: bool,
+        client: Option<IpAddr>,
+    ) -> Option<String> {
+        self.io_proxy.remap(filename, is_write, client)
+    }
+
+    /// Passes the waker to the IOAdapter, to be called when blocked transfers can resume
+    pub fn set_waker(&mut self, waker: IOWaker) {
+        self.io_proxy.set_waker(waker);
+    }
+
+    /// Makes every transfer started afterwards report to `observer` when it ends
+    pub fn set_observer(&mut self, observer: Arc<dyn TransferObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Measures the durations reported to the observer with `clock` instead of the system one
+    pub fn set_clock<C: Clock>(&mut self, clock: C) {
+        self.now = Arc::new(move || clock.now());
+    }
+
     /// Signals the receipt of a transfer-initiating packet (either RRQ or WRQ).
     /// If a `Transfer` is returned in the first tuple member, that must be used to
     /// handle all future packets from the same client via `Transfer::rx`
     /// If a 'Transfer' is not returned, then a transfer cannot be started from the
     /// received packet
     ///
-    /// In both cases the packet contained in the `Result` should be sent back to the client
+    /// In both cases the packet contained in the `Result` should be sent back to the client.
     pub fn rx_initial(
         &mut self,
         packet: Packet,
+    ) -> (Option<Transfer<IO>>, Result<Packet, TftpError>) {
+        self.rx_initial_with_ctx(packet, &Default::default())
+    }
+
+    /// Same as `rx_initial`, with the transfer restricted according to `ctx`
+    pub fn rx_initial_with_ctx(
+        &mut self,
+        packet: Packet,
+        ctx: &RequestCtx,
     ) -> (Option<Transfer<IO>>, Result<Packet, TftpError>) {
         let (filename, mode, mut options, is_write) = match packet {
             Packet::RRQ {
@@ -134,28 +781,45 @@ impl<IO: IOAdapter> TftpServerProto<IO> {
             } => (filename, mode, options, true),
             _ => return (None, Err(TftpError::NotInitiatingPacket)),
         };
-        use crate::packet::TransferMode;
-        match mode {
-            TransferMode::Octet => {}
+        let netascii = match mode {
+            TransferMode::Octet => false,
+            TransferMode::Netascii => true,
             TransferMode::Mail => return (None, Ok(ErrorCode::NoUser.into())),
-            _ => return (None, Ok(ErrorCode::NotDefined.into())),
-        }
+        };
+        let filename = match self.io_proxy.remap(&filename, is_write, ctx.client) {
+            Some(filename) => filename,
+            None => return (None, Ok(ErrorCode::AccessViolation.into())),
+        };
         let file = Path::new(&filename);
 
         let mut meta = TransferMeta {
             blocksize: 512,
             timeout: None,
-            timed_out: false,
+            retries: 0,
             window_size: 1,
+            rollover: self.xfer_cfg.rollover,
+            cfg: self.xfer_cfg.clone(),
+            lifecycle: None,
         };
         let mut tsize = None;
+        let mut utimeout = None;
 
         let mut options = options
             .drain(..)
             .filter_map(|opt| {
                 match opt {
-                    TftpOption::Blocksize(size) => meta.blocksize = size,
-                    TftpOption::TimeoutSecs(secs) => meta.timeout = Some(secs),
+                    TftpOption::Blocksize(size) => {
+                        // answer with the clamped value, which the client must accept
+                        let size = size.min(ctx.max_blocksize);
+                        meta.blocksize = size;
+                        return Some(TftpOption::Blocksize(size));
+                    }
+                    TftpOption::TimeoutSecs(secs) => {
+                        meta.timeout = Some(Duration::from_secs(u64::from(secs)))
+                    }
+                    TftpOption::TimeoutMicros(micros) => {
+                        utimeout = Some(Duration::from_micros(u64::from(micros)))
+                    }
                     TftpOption::TransferSize(size) => {
                         tsize = Some(size);
                         if !is_write {
@@ -164,31 +828,124 @@ impl<IO: IOAdapter> TftpServerProto<IO> {
                         }
                     }
                     TftpOption::WindowSize(size) => meta.window_size = size,
+                    TftpOption::Rollover(value) => meta.rollover = Rollover::from_option(value),
+                    TftpOption::Multicast(_) => {
+                        // the first client of a group is its master
+                        return match ctx.multicast {
+                            Some(addr) if !is_write => {
+                                let group = MulticastGroup { addr, master: true };
+                                Some(TftpOption::Multicast(Some(group)))
+                            }
+                            _ => None,
+                        };
+                    }
                 }
                 Some(opt)
             })
             .collect::<Vec<_>>();
+        // the finer timeout wins if both are given
+        if utimeout.is_some() {
+            meta.timeout = utimeout;
+        }
 
-        let (xfer, packet) = if is_write {
-            let fwrite = match self.io_proxy.create_new(file, tsize) {
+        let (xfer, packet, path) = if is_write {
+            // the announced size is that of the netascii data, not of the decoded file
+            let len_hint = if netascii { None } else { tsize };
+            if tsize.is_some_and(|size| meta.too_large(size)) {
+                return (None, Ok(rollover_error()));
+            }
+            let path = match self.io_proxy.locate(file, true) {
+                Ok(path) => path,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return (None, Err(TftpError::WouldBlock))
+                }
+                Err(ref e) if AmbiguousPath::is(e) => return (None, Ok(AmbiguousPath.into())),
+                _ => return (None, Ok(ErrorCode::FileExists.into())),
+            };
+            let fwrite = match self.io_proxy.create_new(file, len_hint) {
                 Ok(f) => f,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return (None, Err(TftpError::WouldBlock))
+                }
+                Err(ref e) if AmbiguousPath::is(e) => return (None, Ok(AmbiguousPath.into())),
                 _ => return (None, Ok(ErrorCode::FileExists.into())),
             };
 
-            Transfer::<IO>::new_write(fwrite, meta, options)
+            let fwrite = if netascii {
+                ModeWriter::Netascii(NetasciiWriter::new(fwrite))
+            } else {
+                ModeWriter::Octet(fwrite)
+            };
+            let (xfer, packet) = Transfer::<IO>::new_write(fwrite, meta, tsize, options);
+            (xfer, packet, path)
         } else {
+            // looked up on its own, so that the IOAdapter can do it in the background
+            let path = match self.io_proxy.locate(file, false) {
+                Ok(path) => path,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return (None, Err(TftpError::WouldBlock))
+                }
+                Err(ref e) if AmbiguousPath::is(e) => return (None, Ok(AmbiguousPath.into())),
+                _ => return (None, Ok(ErrorCode::FileNotFound.into())),
+            };
+            // before opening, since the file is only opened by the last attempt
+            // of a request the IOAdapter handles in the background
+            let netascii_size = if netascii && tsize.is_some() {
+                match self.io_proxy.netascii_size(file) {
+                    Ok(size) => size,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return (None, Err(TftpError::WouldBlock))
+                    }
+                    Err(_) => None,
+                }
+            } else {
+                None
+            };
             let (fread, len) = match self.io_proxy.open_read(file) {
                 Ok(f) => f,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return (None, Err(TftpError::WouldBlock))
+                }
+                Err(ref e) if AmbiguousPath::is(e) => return (None, Ok(AmbiguousPath.into())),
                 _ => return (None, Ok(ErrorCode::FileNotFound.into())),
             };
+            // the netascii data is at least as large as the file
+            if len.is_some_and(|len| meta.too_large(len)) {
+                return (None, Ok(rollover_error()));
+            }
 
-            if let (Some(_), Some(file_size)) = (tsize, len) {
-                options.push(TftpOption::TransferSize(file_size));
+            if tsize.is_some() {
+                let file_size = if netascii { netascii_size } else { len };
+                if let Some(file_size) = file_size {
+                    options.push(TftpOption::TransferSize(file_size));
+                }
             }
 
-            Transfer::<IO>::new_read(fread, meta, options)
+            let fread = if netascii {
+                ModeReader::Netascii(NetasciiReader::new(fread))
+            } else {
+                ModeReader::Octet(fread)
+            };
+            let (xfer, packet) = Transfer::<IO>::new_read(fread, meta, options);
+            (xfer, packet, path)
         };
 
+        let xfer = match (xfer, &self.observer) {
+            (Some(mut xfer), Some(observer)) => {
+                if let Some(meta) = xfer.meta_mut() {
+                    meta.lifecycle = Some(Lifecycle {
+                        observer: Arc::clone(observer),
+                        file: path,
+                        is_write,
+                        now: Arc::clone(&self.now),
+                        started: (self.now)(),
+                        reported: false,
+                    });
+                }
+                Some(xfer)
+            }
+            (xfer, _) => xfer,
+        };
         (xfer, Ok(packet))
     }
 }
@@ -196,42 +953,106 @@ impl<IO: IOAdapter> TftpServerProto<IO> {
 /// The state of an ongoing transfer with one client
 #[derive(Debug)]
 pub enum Transfer<IO: IOAdapter> {
+    /// A write request, receiving the file from the client
     Rx(TransferRx<IO::W>),
+    /// A read request, sending the file to the client
     Tx(TransferTx<IO::R>),
+    /// The transfer is over
     Complete,
 }
 
+/// The state of a transfer receiving a file
 #[derive(Debug)]
-pub struct TransferRx<W: Write> {
-    fwrite: W,
-    expected_block: SerialNumber<u16>,
-    last_recv: SerialNumber<u16>,
+pub struct TransferRx<W: Commit> {
+    fwrite: ModeWriter<W>,
+    expected_block: u16,
+    last_recv: u16,
+    /// The amount of data bytes received so far
+    received: u64,
+    /// The transfer size announced by the client, if any
+    tsize: Option<u64>,
+    /// A received block that is still being written, because the writer would block
+    pending: Option<PendingData>,
     meta: TransferMeta,
 }
 
+/// A block of data that is only acknowledged once it is completely written
+#[derive(Debug)]
+struct PendingData {
+    block: u16,
+    /// The part of the data not written yet
+    data: Vec<u8>,
+    /// The size of the whole block
+    len: u64,
+    is_final: bool,
+}
+
+/// The state of a transfer sending a file
 #[derive(Debug)]
 pub struct TransferTx<R: Read> {
-    fread: R,
-    expected_block: SerialNumber<u16>,
+    fread: ModeReader<R>,
+    expected_block: u16,
     sent_final: bool,
+    /// The effective window size, at most the negotiated one
+    cwnd: u16,
+    /// The window size above which `cwnd` grows linearly instead of doubling
+    ssthresh: u16,
+    /// The number of new blocks sent in the last window
+    in_flight: u16,
+    /// The number of blocks sent since the last acknowledged one, resent ones included
+    unacked: u16,
+    /// The next DATA packet, with the start of its block read before the reader would block
+    partial: Vec<u8>,
+    /// Buffers of acknowledged packets, reused for the next ones
+    spare: Vec<Vec<u8>>,
+    /// The number of blocks still to send in the window, once the reader is ready
+    blocked: Option<u16>,
+    /// The number of bytes to discard before the next block, when starting mid-file
+    skip: u64,
+    /// The amount of data bytes sent so far
+    sent: u64,
     meta: TransferMeta,
 }
 
 impl<IO: IOAdapter> Transfer<IO> {
     fn new_read(
-        fread: IO::R,
+        fread: ModeReader<IO::R>,
         meta: TransferMeta,
         options: Vec<TftpOption>,
     ) -> (Option<Transfer<IO>>, Packet) {
+        let cwnd = if meta.cfg.congestion_control {
+            1
+        } else {
+            meta.window_size
+        };
         let mut xfer = TransferTx {
             fread,
-            expected_block: 0.into(),
+            expected_block: 0,
             sent_final: false,
+            cwnd,
+            ssthresh: meta.window_size,
+            in_flight: 0,
+            // the OACK or the first block
+            unacked: 1,
+            partial: vec![],
+            spare: vec![],
+            blocked: None,
+            skip: 0,
+            sent: 0,
             meta,
         };
 
         let packet = if options.is_empty() {
-            xfer.read_step()
+            xfer.in_flight = 1;
+            match xfer.read_step() {
+                Ok(Some(bytes)) => Ok(Packet::DATA {
+                    block_num: xfer.expected_block,
+                    data: bytes[DATA_HEADER_LEN..].to_vec(),
+                }),
+                // nothing to send until the reader is ready, which the IOAdapter must avoid
+                Ok(None) => Err(ErrorCode::NotDefined.into()),
+                Err(packet) => Err(packet),
+            }
         } else {
             Ok(Packet::OACK { options })
         };
@@ -242,14 +1063,18 @@ impl<IO: IOAdapter> Transfer<IO> {
     }
 
     fn new_write(
-        fwrite: IO::W,
+        fwrite: ModeWriter<IO::W>,
         meta: TransferMeta,
+        tsize: Option<u64>,
         options: Vec<TftpOption>,
     ) -> (Option<Transfer<IO>>, Packet) {
         let xfer = TransferRx {
             fwrite,
-            expected_block: meta.window_size.into(),
-            last_recv: 0.into(),
+            expected_block: meta.add_blocks(0, meta.window_size),
+            last_recv: 0,
+            received: 0,
+            tsize,
+            pending: None,
             meta,
         };
 
@@ -261,45 +1086,121 @@ impl<IO: IOAdapter> Transfer<IO> {
         (Some(Transfer::Rx(xfer)), packet)
     }
 
+    fn meta_mut(&mut self) -> Option<&mut TransferMeta> {
+        match *self {
+            Transfer::Rx(TransferRx { ref mut meta, .. })
+            | Transfer::Tx(TransferTx { ref mut meta, .. }) => Some(meta),
+            Transfer::Complete => None,
+        }
+    }
+
+    /// Ends the transfer after `response`, caused by an ERROR packet from the client if
+    /// `client_error` is set
+    fn finish(&mut self, response: &Response, client_error: Option<AbortReason>) {
+        let bytes = match *self {
+            Transfer::Rx(ref rx) => rx.received,
+            Transfer::Tx(ref tx) => tx.sent,
+            Transfer::Complete => return,
+        };
+        if let Some(lifecycle) = self.meta_mut().and_then(|meta| meta.lifecycle.as_mut()) {
+            let error = response.p.iter().find_map(|item| match *item {
+                ResponseItem::Packet(Packet::ERROR { code, ref msg }) => Some((code, msg)),
+                _ => None,
+            });
+            match (error, client_error) {
+                (Some((code, msg)), _) => {
+                    lifecycle.abort(AbortReason::ServerError(code, msg.clone()))
+                }
+                (None, Some(reason)) => lifecycle.abort(reason),
+                (None, None) => lifecycle.complete(bytes),
+            }
+        }
+        *self = Transfer::Complete;
+    }
+
+    /// Ends the transfer without any further packet, reporting `reason` to the observer
+    pub(crate) fn abort(&mut self, reason: AbortReason) {
+        if let Some(lifecycle) = self.meta_mut().and_then(|meta| meta.lifecycle.as_mut()) {
+            lifecycle.abort(reason);
+        }
+        *self = Transfer::Complete;
+    }
+
     /// Checks to see if the transfer has completed
     pub fn is_done(&self) -> bool {
+        matches!(*self, Transfer::Complete)
+    }
+
+    /// Checks whether the transfer is waiting for its reader or writer,
+    /// and must be resumed via `resume` once the IOAdapter's waker is called
+    pub fn is_blocked(&self) -> bool {
         match *self {
-            Transfer::Complete => true,
-            _ => false,
+            Transfer::Rx(ref rx) => rx.pending.is_some(),
+            Transfer::Tx(ref tx) => tx.blocked.is_some(),
+            Transfer::Complete => false,
+        }
+    }
+
+    /// Retries the file I/O that would have blocked, returning the packets
+    /// that could not be sent before. Does nothing if the transfer isn't blocked
+    pub fn resume(&mut self) -> Response {
+        let response = match *self {
+            Transfer::Rx(ref mut rx) => rx.complete_data(),
+            Transfer::Tx(ref mut tx) => tx.resume(),
+            Transfer::Complete => return vec![].into(),
+        };
+        if response.p.contains(&ResponseItem::Done) {
+            self.finish(&response, None);
+        }
+        response
+    }
+
+    /// Takes the buffers of acknowledged packets, to read the next DATA packets into.
+    /// Transfers that don't send a file leave them to the caller
+    pub fn recycle(&mut self, buffers: &mut Vec<Vec<u8>>) {
+        if let Transfer::Tx(ref mut tx) = *self {
+            tx.spare.append(buffers);
         }
     }
 
     /// Call this to indicate that the timeout since the last received packet has expired
     /// This may return some packets to (re)send or may terminate the transfer
     pub fn timeout_expired(&mut self) -> ResponseItem {
-        let result = match *self {
+        let exhausted = match *self {
+            Transfer::Rx(TransferRx { ref mut meta, .. })
+            | Transfer::Tx(TransferTx { ref mut meta, .. }) => {
+                meta.retries += 1;
+                meta.retries > meta.cfg.max_retries
+            }
+            _ => true,
+        };
+        if exhausted {
+            self.abort(AbortReason::Timeout);
+            ResponseItem::Done
+        } else {
+            self.retransmit()
+        }
+    }
+
+    /// Returns the packets to resend for the current step of the transfer,
+    /// without counting this as a retry.
+    /// Used for early retransmissions, before the peer is considered idle
+    pub fn retransmit(&mut self) -> ResponseItem {
+        match *self {
             Transfer::Rx(ref mut rx) => {
-                if rx.meta.timed_out {
-                    ResponseItem::Done
+                if rx.meta.next_block(rx.last_recv) != rx.expected_block {
+                    rx.expected_block = rx.meta.add_blocks(rx.last_recv, rx.meta.window_size);
+                    ResponseItem::Packet(Packet::ACK(rx.last_recv))
                 } else {
-                    rx.meta.timed_out = true;
-                    if rx.last_recv + 1 != rx.expected_block {
-                        rx.expected_block = rx.last_recv + rx.meta.window_size;
-                        ResponseItem::Packet(Packet::ACK(rx.last_recv.0))
-                    } else {
-                        ResponseItem::RepeatLast(1)
-                    }
+                    ResponseItem::RepeatLast(1)
                 }
             }
-            Transfer::Tx(TransferTx { ref mut meta, .. }) => {
-                if meta.timed_out {
-                    ResponseItem::Done
-                } else {
-                    meta.timed_out = true;
-                    ResponseItem::RepeatLast(meta.window_size as usize)
-                }
+            Transfer::Tx(ref mut tx) => {
+                tx.congestion();
+                ResponseItem::RepeatLast(tx.unacked as usize)
             }
-            _ => ResponseItem::Done,
-        };
-        if let ResponseItem::Done = result {
-            *self = Transfer::Complete;
-        };
-        result
+            Transfer::Complete => ResponseItem::Done,
+        }
     }
 
     /// Returns the timeout negotiated via option for this transfer,
@@ -307,9 +1208,58 @@ impl<IO: IOAdapter> Transfer<IO> {
     pub fn timeout(&self) -> Option<Duration> {
         match *self {
             Transfer::Rx(TransferRx { ref meta, .. })
-            | Transfer::Tx(TransferTx { ref meta, .. }) => {
-                meta.timeout.map(|s| Duration::from_secs(u64::from(s)))
+            | Transfer::Tx(TransferTx { ref meta, .. }) => meta.timeout,
+            _ => None,
+        }
+    }
+
+    /// Returns how long to wait for the next packet, given the base timeout
+    /// (the negotiated one, or the server default).
+    /// With backoff enabled, the base timeout is doubled for every retry already made
+    pub fn retry_timeout(&self, base: Duration) -> Duration {
+        let meta = match *self {
+            Transfer::Rx(TransferRx { ref meta, .. })
+            | Transfer::Tx(TransferTx { ref meta, .. }) => meta,
+            _ => return base,
+        };
+        match meta.cfg.max_backoff {
+            Some(max) => {
+                let factor = 1u32.checked_shl(meta.retries).unwrap_or(u32::MAX);
+                base.checked_mul(factor)
+                    .map_or(max, |t| t.min(max))
+                    .max(base)
             }
+            None => base,
+        }
+    }
+
+    /// Checks whether an ACK for `block` is expected by a read,
+    /// i.e. it acknowledges a block of the last window or the OACK
+    pub(crate) fn expects_ack(&self, block: u16) -> bool {
+        match *self {
+            Transfer::Tx(ref tx) => tx.meta.blocks_between(block, tx.expected_block) <= tx.unacked,
+            _ => false,
+        }
+    }
+
+    /// Makes a read that hasn't sent any data yet continue after the given block,
+    /// as if the client had acknowledged it
+    pub(crate) fn skip_to(&mut self, block: u16) {
+        if let Transfer::Tx(ref mut tx) = *self {
+            tx.expected_block = block;
+            tx.unacked = 0;
+            tx.skip = u64::from(block) * u64::from(tx.meta.blocksize);
+            // right away if possible, to know whether that block is the final one.
+            // Otherwise the rest is skipped, and errors reported, when reading the next block
+            let _ = tx.discard_skipped();
+        }
+    }
+
+    /// Returns the number of blocks currently sent per window when reading,
+    /// which is lower than the negotiated window size while congestion control limits it
+    pub fn effective_window(&self) -> Option<u16> {
+        match *self {
+            Transfer::Tx(ref tx) => Some(tx.cwnd),
             _ => None,
         }
     }
@@ -320,19 +1270,26 @@ impl<IO: IOAdapter> Transfer<IO> {
     ///
     /// Transfer completion can be checked via `Transfer::is_done()`
     pub fn rx(&mut self, packet: Packet) -> Result<Response, TftpError> {
+        self.rx_ref(PacketRef::from(&packet))
+    }
+
+    /// Like `rx`, for a packet borrowed from the buffer it was received in
+    pub fn rx_ref(&mut self, packet: PacketRef) -> Result<Response, TftpError> {
         if self.is_done() {
             return Ok(ResponseItem::Done.into());
         }
+        let client_error = match packet {
+            PacketRef::ERROR { code, msg } => Some(AbortReason::ClientError(code, msg.to_owned())),
+            _ => None,
+        };
         let result = match (packet, &mut *self) {
-            (Packet::ACK(ack_block), &mut Transfer::Tx(ref mut tx)) => Ok(tx.handle_ack(ack_block)),
-            (
-                Packet::DATA {
-                    block_num,
-                    ref data,
-                },
-                &mut Transfer::Rx(ref mut rx),
-            ) => Ok(rx.handle_data(block_num, data)),
-            (Packet::DATA { .. }, _) | (Packet::ACK(_), _) => {
+            (PacketRef::ACK(ack_block), &mut Transfer::Tx(ref mut tx)) => {
+                Ok(tx.handle_ack(ack_block))
+            }
+            (PacketRef::DATA { block_num, data }, &mut Transfer::Rx(ref mut rx)) => {
+                Ok(rx.handle_data(block_num, data))
+            }
+            (PacketRef::DATA { .. }, _) | (PacketRef::ACK(_), _) => {
                 // wrong kind of packet, kill transfer
                 Ok(vec![
                     ResponseItem::Packet(ErrorCode::IllegalTFTP.into()),
@@ -341,15 +1298,17 @@ impl<IO: IOAdapter> Transfer<IO> {
                 .into())
             }
 
-            (Packet::ERROR { .. }, _) => {
+            (PacketRef::ERROR { .. }, _) => {
                 // receiving an error kills the transfer
                 Ok(ResponseItem::Done.into())
             }
             _ => Err(TftpError::TransferAlreadyRunning),
         };
 
-        if let Ok(true) = result.as_ref().map(|r| r.p.contains(&ResponseItem::Done)) {
-            *self = Transfer::Complete;
+        if let Ok(ref response) = result {
+            if response.p.contains(&ResponseItem::Done) {
+                self.finish(response, client_error);
+            }
         }
         result
     }
@@ -358,15 +1317,12 @@ impl<IO: IOAdapter> Transfer<IO> {
 impl<R: Read> TransferTx<R> {
     fn handle_ack(&mut self, ack_block: u16) -> Response {
         use self::ResponseItem::RepeatLast;
-        let ack_block = SerialNumber(ack_block);
-
         if self.sent_final && ack_block == self.expected_block {
             return ResponseItem::Done.into();
         }
 
-        if ack_block > self.expected_block
-            || ack_block + self.meta.window_size < self.expected_block
-        {
+        let window_start = self.meta.blocks_between(ack_block, self.expected_block);
+        if window_start > self.meta.window_size {
             // ack block outside of possible window, error and kill transfer
             return vec![
                 ResponseItem::Packet(Packet::ERROR {
@@ -377,53 +1333,155 @@ impl<R: Read> TransferTx<R> {
             ]
             .into();
         }
+        if window_start > self.unacked {
+            // acknowledges a block of an earlier window, delayed or duplicated on the way
+            return vec![].into();
+        }
 
-        let window_start = self.expected_block.0.wrapping_sub(ack_block.0);
         let mut v = vec![];
         if window_start != 0 {
             v.push(RepeatLast(window_start as usize));
+            self.congestion();
+        } else if self.in_flight == self.cwnd {
+            // the whole window arrived
+            self.cwnd = if self.cwnd < self.ssthresh {
+                self.cwnd.saturating_mul(2)
+            } else {
+                self.cwnd.saturating_add(1)
+            }
+            .min(self.meta.window_size);
         }
 
-        self.meta.timed_out = false;
-        for _ in window_start..self.meta.window_size {
+        self.meta.retries = 0;
+        // the unacknowledged blocks are resent even if they exceed the window
+        let new_blocks = if self.sent_final {
+            0
+        } else {
+            self.cwnd.saturating_sub(window_start)
+        };
+        self.in_flight = 0;
+        self.unacked = window_start;
+        self.blocked = None;
+        self.send_blocks(new_blocks, &mut v);
+        v.into()
+    }
+
+    /// Sends the rest of the window once the reader no longer blocks
+    fn resume(&mut self) -> Response {
+        let mut v = vec![];
+        if let Some(count) = self.blocked.take() {
+            self.send_blocks(count, &mut v);
+        }
+        v.into()
+    }
+
+    /// Reads up to `count` new blocks into `v`, stopping after the final one.
+    /// If the reader would block, the remaining ones are sent by `resume`
+    fn send_blocks(&mut self, count: u16, v: &mut Vec<ResponseItem>) {
+        for sent in 0..count {
             match self.read_step() {
-                Ok(p) => v.push(ResponseItem::Packet(p)),
+                Ok(Some(bytes)) => v.push(ResponseItem::Data(bytes)),
+                Ok(None) => {
+                    self.blocked = Some(count - sent);
+                    return;
+                }
                 Err(p) => {
-                    return vec![ResponseItem::Packet(p), ResponseItem::Done].into();
+                    *v = vec![ResponseItem::Packet(p), ResponseItem::Done];
+                    return;
                 }
             }
+            self.in_flight += 1;
+            self.unacked += 1;
             if self.sent_final {
-                break;
+                return;
             }
         }
-        v.into()
     }
 
-    fn read_step(&mut self) -> Result<Packet, Packet> {
-        let mut v = Vec::with_capacity(self.meta.blocksize as usize);
-        if self
+    /// Shrinks the effective window after loss
+    fn congestion(&mut self) {
+        if self.meta.cfg.congestion_control {
+            self.cwnd = (self.cwnd / 2).max(1);
+            self.ssthresh = self.cwnd;
+        }
+    }
+
+    /// Discards the bytes to skip, until done or the reader would block
+    fn discard_skipped(&mut self) -> Result<(), Packet> {
+        let mut scratch = [0; 4096];
+        while self.skip > 0 {
+            let len = scratch.len().min(self.skip as usize);
+            match self.fread.read(&mut scratch[..len]) {
+                Ok(0) => {
+                    // the file ends within the skipped blocks
+                    self.skip = 0;
+                    self.sent_final = true;
+                }
+                Ok(n) => self.skip -= n as u64,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(_) => return Err(ErrorCode::NotDefined.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next block right after the header of the DATA packet carrying it,
+    /// or returns `None` if the reader would block
+    fn read_step(&mut self) -> Result<Option<Vec<u8>>, Packet> {
+        if self.skip > 0 {
+            self.discard_skipped()?;
+            if self.skip > 0 {
+                return Ok(None);
+            }
+        }
+        if self.expected_block == u16::MAX && self.meta.rollover == Rollover::Forbidden {
+            return Err(rollover_error());
+        }
+        let blocksize = self.meta.blocksize as usize;
+        if self.partial.is_empty() {
+            self.partial = self.spare.pop().unwrap_or_default();
+            self.partial.clear();
+            // written once the block number is known
+            self.partial.resize(DATA_HEADER_LEN, 0);
+        }
+        let missing = (DATA_HEADER_LEN + blocksize).saturating_sub(self.partial.len());
+        // on errors, whatever was read so far is still appended
+        match self
             .fread
             .by_ref()
-            .take(u64::from(self.meta.blocksize))
-            .read_to_end(&mut v)
-            .is_err()
+            .take(missing as u64)
+            .read_to_end(&mut self.partial)
         {
-            return Err(ErrorCode::NotDefined.into());
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(_) => return Err(ErrorCode::NotDefined.into()),
         }
+        let mut bytes = mem::take(&mut self.partial);
+        let len = bytes.len() - DATA_HEADER_LEN;
 
-        self.sent_final = v.len() < self.meta.blocksize as usize;
-        self.expected_block += 1;
-        Ok(Packet::DATA {
-            block_num: self.expected_block.0,
-            data: v,
-        })
+        self.sent_final = len < blocksize;
+        self.sent += len as u64;
+        self.expected_block = self.meta.next_block(self.expected_block);
+        write_data_header(self.expected_block, &mut bytes)
+            .map_err(|_| Packet::from(ErrorCode::NotDefined))?;
+        Ok(Some(bytes))
     }
 }
 
-impl<W: Write> TransferRx<W> {
+impl<W: Commit> TransferRx<W> {
     fn handle_data(&mut self, block: u16, data: &[u8]) -> Response {
-        let block = SerialNumber(block);
-        if block > self.expected_block || block + self.meta.window_size < self.expected_block {
+        if self.pending.is_some() {
+            // can't be acknowledged until the previous block is written, the client will resend
+            return vec![].into();
+        }
+        if self.last_recv == u16::MAX
+            && block != u16::MAX
+            && self.meta.rollover == Rollover::Forbidden
+        {
+            return vec![ResponseItem::Packet(rollover_error()), ResponseItem::Done].into();
+        }
+        if self.meta.blocks_between(block, self.expected_block) > self.meta.window_size {
             // data block outside of possible window, error and kill transfer
             vec![
                 ResponseItem::Packet(Packet::ERROR {
@@ -434,48 +1492,161 @@ impl<W: Write> TransferRx<W> {
             ]
             .into()
         } else {
-            if self.last_recv + 1 != block {
+            if self.meta.next_block(self.last_recv) != block {
                 // out of sequence
                 // reset window
-                self.expected_block = self.last_recv + self.meta.window_size;
+                self.expected_block = self.meta.add_blocks(self.last_recv, self.meta.window_size);
                 // ack last block to signal that's what we got
-                return ResponseItem::Packet(Packet::ACK(self.last_recv.0)).into();
+                return ResponseItem::Packet(Packet::ACK(self.last_recv)).into();
             }
-            self.meta.timed_out = false;
-            self.last_recv = block;
-            if self.fwrite.write_all(data).is_err() {
+            self.meta.retries = 0;
+            let received = self.received + data.len() as u64;
+            let is_final = data.len() < self.meta.blocksize as usize;
+            match self.tsize {
+                Some(tsize) if received > tsize => {
+                    return vec![
+                        ResponseItem::Packet(Packet::ERROR {
+                            code: ErrorCode::DiskFull,
+                            msg: "Received more data than announced by tsize".to_owned(),
+                        }),
+                        ResponseItem::Done,
+                    ]
+                    .into();
+                }
+                Some(tsize) if is_final && received < tsize => {
+                    return vec![
+                        ResponseItem::Packet(Packet::ERROR {
+                            code: ErrorCode::NotDefined,
+                            msg: "Received less data than announced by tsize".to_owned(),
+                        }),
+                        ResponseItem::Done,
+                    ]
+                    .into();
+                }
+                _ => {}
+            }
+            self.pending = Some(PendingData {
+                block,
+                data: data.to_vec(),
+                len: data.len() as u64,
+                is_final,
+            });
+            self.complete_data()
+        }
+    }
+
+    /// Writes the pending block, acknowledging it if it was completely written
+    fn complete_data(&mut self) -> Response {
+        let pending = match self.pending {
+            Some(ref mut pending) => pending,
+            None => return vec![].into(),
+        };
+        let finished = match write_nonblocking(&mut self.fwrite, &pending.data) {
+            Ok(written) => {
+                pending.data.drain(..written);
+                if pending.data.is_empty() && pending.is_final {
+                    let fwrite = &mut self.fwrite;
+                    fwrite.finish().and_then(|_| fwrite.get_mut().commit())
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => Err(e),
+        };
+        match finished {
+            Ok(()) if pending.data.is_empty() => {}
+            Ok(()) => return vec![].into(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return vec![].into(),
+            Err(_) => {
                 return vec![
                     ResponseItem::Packet(ErrorCode::NotDefined.into()),
                     ResponseItem::Done,
                 ]
-                .into();
-            }
-            if data.len() < self.meta.blocksize as usize {
-                vec![
-                    ResponseItem::Packet(Packet::ACK(block.0)),
-                    ResponseItem::Done,
-                ]
                 .into()
-            } else if block == self.expected_block {
-                self.expected_block += self.meta.window_size;
-                ResponseItem::Packet(Packet::ACK(block.0)).into()
-            } else {
-                vec![].into()
             }
         }
+
+        let PendingData {
+            block,
+            len,
+            is_final,
+            ..
+        } = self.pending.take().unwrap();
+        self.last_recv = block;
+        self.received += len;
+        if is_final {
+            vec![ResponseItem::Packet(Packet::ACK(block)), ResponseItem::Done].into()
+        } else if block == self.expected_block {
+            self.expected_block = self.meta.add_blocks(block, self.meta.window_size);
+            ResponseItem::Packet(Packet::ACK(block)).into()
+        } else {
+            vec![].into()
+        }
+    }
+}
+
+/// Writes as much of `data` as possible without blocking, returning how much was written
+fn write_nonblocking<W: Write>(w: &mut W, mut data: &[u8]) -> io::Result<usize> {
+    let len = data.len();
+    while !data.is_empty() {
+        match w.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
     }
+    Ok(len - data.len())
 }
 
+/// Restrictions on the files transfers may access
+#[derive(Default)]
 pub struct IOPolicyCfg {
+    /// Whether all write requests are rejected
     pub readonly: bool,
+    /// The directory requested paths are relative to, instead of the current one
     pub path: Option<PathBuf>,
+    /// The rules rewriting or denying requested paths, before any other restriction
+    pub remap: Rules,
+    /// Whether backslashes in requested paths are separators, as sent by Windows clients
+    pub backslashes: bool,
+    /// Whether each component of requested paths matches files of the served directory
+    /// regardless of case. An exact match wins, several others fail the request
+    pub case_insensitive: bool,
+    /// What write requests do with files that already exist
+    pub write_policy: WritePolicy,
 }
 
-impl Default for IOPolicyCfg {
-    fn default() -> Self {
-        Self {
-            readonly: false,
-            path: None,
+/// The error for paths matching several files once case is ignored
+#[derive(Debug)]
+struct AmbiguousPath;
+
+impl AmbiguousPath {
+    fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|inner| inner.is::<AmbiguousPath>())
+    }
+}
+
+impl fmt::Display for AmbiguousPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ambiguous file name")
+    }
+}
+
+impl Error for AmbiguousPath {}
+
+impl From<AmbiguousPath> for io::Error {
+    fn from(e: AmbiguousPath) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+impl From<AmbiguousPath> for Packet {
+    fn from(e: AmbiguousPath) -> Self {
+        Packet::ERROR {
+            code: ErrorCode::NotDefined,
+            msg: e.to_string(),
         }
     }
 }
@@ -489,47 +1660,104 @@ impl<IO: IOAdapter> IOPolicyProxy<IO> {
     pub(crate) fn new(io: IO, cfg: IOPolicyCfg) -> Self {
         Self { io, policy: cfg }
     }
+
+    /// Applies the remapping rules to a requested path, returning `None` if it is denied
+    pub(crate) fn remap(
+        &self,
+        filename: &str,
+        is_write: bool,
+        client: Option<IpAddr>,
+    ) -> Option<String> {
+        self.policy.remap.apply(filename, is_write, client)
+    }
+
+    /// Turns backslashes into separators, if enabled
+    fn normalize<'a>(&self, file: &'a Path) -> Cow<'a, Path> {
+        match file.to_str() {
+            Some(name) if self.policy.backslashes && name.contains('\\') => {
+                Cow::Owned(name.replace('\\', "/").into())
+            }
+            _ => Cow::Borrowed(file),
+        }
+    }
+
+    /// Returns the path of a requested file within the served directory,
+    /// if it may be read, or written if `is_write`
+    pub(crate) fn locate(&self, file: &Path, is_write: bool) -> io::Result<PathBuf> {
+        let file = self.normalize(file);
+        if (is_write && self.policy.readonly)
+            || file.is_absolute()
+            || file
+                .components()
+                .any(|c| matches!(c, Component::RootDir | Component::ParentDir))
+        {
+            let msg = if is_write {
+                "cannot write"
+            } else {
+                "cannot read"
+            };
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+        }
+        let dir = self.policy.path.clone().unwrap_or_default();
+        if self.policy.case_insensitive {
+            self.io.find_ignoring_case(&dir, &file)
+        } else {
+            Ok(dir.join(file))
+        }
+    }
+}
+
+/// Finds the entry named `name` regardless of case.
+/// Fails if there are several such entries but none matches exactly
+fn find_entry(name: &OsStr, entries: Vec<OsString>) -> io::Result<Option<OsString>> {
+    let lowercase = match name.to_str() {
+        Some(name) => name.to_lowercase(),
+        None => return Ok(None),
+    };
+    if entries.iter().any(|entry| entry == name) {
+        return Ok(Some(name.to_owned()));
+    }
+    let mut found = None;
+    for entry in entries {
+        if entry.to_str().map(str::to_lowercase).as_deref() == Some(&lowercase) {
+            if found.is_some() {
+                return Err(AmbiguousPath.into());
+            }
+            found = Some(entry);
+        }
+    }
+    Ok(found)
 }
 
 impl<IO: IOAdapter> IOAdapter for IOPolicyProxy<IO> {
     type R = IO::R;
     type W = IO::W;
     fn open_read(&self, file: &Path) -> io::Result<(Self::R, Option<u64>)> {
-        if file.is_absolute()
-            || file.components().any(|c| match c {
-                Component::RootDir | Component::ParentDir => true,
-                _ => false,
-            })
-        {
-            Err(io::Error::new(
-                io::ErrorKind::PermissionDenied,
-                "cannot read",
-            ))
-        } else if let Some(ref path) = self.policy.path {
-            let full = path.clone().join(file);
-            self.io.open_read(&full)
-        } else {
-            self.io.open_read(file)
+        self.io.open_read(&self.locate(file, false)?)
+    }
+
+    fn netascii_size(&self, file: &Path) -> io::Result<Option<u64>> {
+        match self.locate(file, false) {
+            Ok(full) => self.io.netascii_size(&full),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
+            Err(_) => Ok(None),
         }
     }
 
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<OsString>> {
+        self.io.list_dir(dir)
+    }
+
+    fn find_ignoring_case(&self, dir: &Path, file: &Path) -> io::Result<PathBuf> {
+        self.io.find_ignoring_case(dir, file)
+    }
+
+    fn set_waker(&mut self, waker: IOWaker) {
+        self.io.set_waker(waker);
+    }
+
     fn create_new(&mut self, file: &Path, len: Option<u64>) -> io::Result<Self::W> {
-        if self.policy.readonly
-            || file.is_absolute()
-            || file.components().any(|c| match c {
-                Component::RootDir | Component::ParentDir => true,
-                _ => false,
-            })
-        {
-            Err(io::Error::new(
-                io::ErrorKind::PermissionDenied,
-                "cannot write",
-            ))
-        } else if let Some(ref path) = self.policy.path {
-            let full = path.clone().join(file);
-            self.io.create_new(&full, len)
-        } else {
-            self.io.create_new(file, len)
-        }
+        let full = self.locate(file, true)?;
+        self.io.create(&full, len, self.policy.write_policy)
     }
 }
@@ -1,11 +1,19 @@
 use assert_matches::*;
 
-use crate::packet::{ErrorCode, Packet, TftpOption};
+use crate::clock::ManualClock;
+use crate::multicast::{Datagrams, MulticastSession};
+use crate::packet::{ErrorCode, MulticastGroup, Packet, TftpOption};
+use crate::remap::Rules;
 use crate::tftp_proto::*;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::io::{self, Read, Write};
 use std::iter::Take;
-use std::path::Path;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::time::Duration;
 
 use crate::packet::TransferMode::*;
@@ -13,7 +21,7 @@ use crate::packet::TransferMode::*;
 macro_rules! assert_packets {
     ( $e:expr => [ $($list:tt)* ] ) => {
         if let Ok(mut packs) = $e {
-            assert_packets_list!(packs.next(), $($list)*);
+            assert_packets_list!(packs.next().map(parsed), $($list)*);
             assert_eq!(packs.next(), None);
         } else {
             panic!("assertion failed: `{:?}` does not match `{}`",
@@ -29,6 +37,14 @@ macro_rules! assert_packets {
     };
 }
 
+/// Parses DATA packets serialized by transfers, so that they compare to `Packet::DATA`
+fn parsed(item: ResponseItem) -> ResponseItem {
+    match item {
+        ResponseItem::Data(bytes) => ResponseItem::Packet(Packet::read(&bytes).unwrap()),
+        item => item,
+    }
+}
+
 macro_rules! assert_packets_list {
     ( $code:expr, match $pat:pat, $($tail:tt)* ) => {
         assert_matches!($code, Some($pat));
@@ -102,39 +118,101 @@ fn rrq_mail_gets_error() {
 }
 
 #[test]
-fn rrq_netascii_gets_error() {
-    let (mut server, file, _) = rrq_fixture(132);
+fn rrq_netascii() {
+    let mut io = MemIO::default();
+    io.add("text", b"line 1\nline\r2\n");
+    let mut server = TftpServerProto::new(io, Default::default());
     let (xfer, res) = server.rx_initial(Packet::RRQ {
-        filename: file,
+        filename: "text".into(),
         mode: Netascii,
         options: vec![],
     });
-    assert_matches!(
+    assert_eq!(
         res,
-        Ok(Packet::ERROR {
-            code: ErrorCode::NotDefined,
-            ..
+        Ok(Packet::DATA {
+            block_num: 1,
+            data: b"line 1\r\nline\r\x002\r\n".to_vec(),
         })
     );
-    assert!(xfer.is_none());
+    let mut xfer = xfer.unwrap();
+    assert_packets!(xfer.rx(Packet::ACK(1)) => [ResponseItem::Done,]);
+}
+
+#[test]
+fn rrq_netascii_sequence_split_across_blocks() {
+    let mut io = MemIO::default();
+    // translates to 17 bytes, with the CR LF for the second newline split over the blocks
+    io.add("text", b"abc\nabcde\nabc\r");
+    let mut server = TftpServerProto::new(io, Default::default());
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: "text".into(),
+        mode: Netascii,
+        options: vec![
+            TftpOption::Blocksize(11),
+            TftpOption::WindowSize(2),
+            TftpOption::TransferSize(0),
+        ],
+    });
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![
+                TftpOption::Blocksize(11),
+                TftpOption::WindowSize(2),
+                TftpOption::TransferSize(17),
+            ],
+        })
+    );
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::ACK(0)) => [
+            ResponseItem::Packet(Packet::DATA { block_num: 1, data: b"abc\r\nabcde\r".to_vec(), }),
+            ResponseItem::Packet(Packet::DATA { block_num: 2, data: b"\nabc\r\x00".to_vec(), }),
+        ]
+    );
+    assert_packets!(xfer.rx(Packet::ACK(2)) => [ResponseItem::Done,]);
 }
 
 #[test]
-fn wrq_netascii_gets_error() {
-    let (mut server, file, _) = wrq_fixture(132);
+fn wrq_netascii() {
+    let io = MemIO::default();
+    let files = io.files.clone();
+    let mut server = TftpServerProto::new(io, Default::default());
     let (xfer, res) = server.rx_initial(Packet::WRQ {
-        filename: file,
+        filename: "text".into(),
         mode: Netascii,
-        options: vec![],
+        options: vec![TftpOption::Blocksize(8), TftpOption::TransferSize(18)],
     });
-    assert_matches!(
+    assert_eq!(
         res,
-        Ok(Packet::ERROR {
-            code: ErrorCode::NotDefined,
-            ..
+        Ok(Packet::OACK {
+            options: vec![TftpOption::Blocksize(8), TftpOption::TransferSize(18)],
         })
     );
-    assert!(xfer.is_none());
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::DATA {
+            block_num: 1,
+            data: b"line 1\r\n".to_vec(),
+        }) => [ResponseItem::Packet(Packet::ACK(1)),]
+    );
+    // CR LF split between blocks
+    assert_packets!(
+        xfer.rx(Packet::DATA {
+            block_num: 2,
+            data: b"x\r\x00yz\r\n\r".to_vec(),
+        }) => [ResponseItem::Packet(Packet::ACK(2)),]
+    );
+    assert_packets!(
+        xfer.rx(Packet::DATA {
+            block_num: 3,
+            data: b"\n\r".to_vec(),
+        }) => [
+            ResponseItem::Packet(Packet::ACK(3)),
+            ResponseItem::Done,
+        ]
+    );
+    assert_eq!(files.borrow()["text"], b"line 1\nx\ryz\n\n\r");
 }
 
 fn rrq_fixture(file_size: usize) -> (TftpServerProto<TestIoFactory>, String, ByteGen) {
@@ -298,6 +376,45 @@ fn rrq_2_blocks_ok() {
     assert_packets!(xfer.rx(Packet::ACK(2)) => [ResponseItem::Done,]);
 }
 
+#[test]
+fn rrq_reads_into_recycled_buffers() {
+    let (mut server, file, mut file_bytes) = rrq_fixture(1100);
+    let (xfer, _) = server.rx_initial(Packet::RRQ {
+        filename: file.clone(),
+        mode: Octet,
+        options: vec![],
+    });
+    let mut xfer = xfer.unwrap();
+    file_bytes.gen(512);
+    let block_2 = match xfer.rx(Packet::ACK(1)).unwrap().next() {
+        Some(ResponseItem::Data(bytes)) => bytes,
+        item => panic!("unexpected {:?}", item),
+    };
+    assert_eq!(
+        Packet::read(&block_2).unwrap(),
+        Packet::DATA {
+            block_num: 2,
+            data: file_bytes.gen(512),
+        }
+    );
+
+    let buffer = block_2.as_ptr();
+    xfer.recycle(&mut vec![block_2]);
+    match xfer.rx(Packet::ACK(2)).unwrap().next() {
+        Some(ResponseItem::Data(bytes)) => {
+            assert_eq!(bytes.as_ptr(), buffer);
+            assert_eq!(
+                Packet::read(&bytes).unwrap(),
+                Packet::DATA {
+                    block_num: 3,
+                    data: file_bytes.gen(76),
+                }
+            );
+        }
+        item => panic!("unexpected {:?}", item),
+    }
+}
+
 #[test]
 fn rrq_2_blocks_second_lost_ack_repeat_ok() {
     let (mut server, file, mut file_bytes) = rrq_fixture(612);
@@ -632,6 +749,70 @@ fn rrq_blocksize() {
     assert_packets!(xfer.rx(Packet::ACK(2)) => [ResponseItem::Done,]);
 }
 
+#[test]
+fn rrq_blocksize_clamped() {
+    let (mut server, file, mut file_bytes) = rrq_fixture(1000);
+    let ctx = RequestCtx {
+        max_blocksize: 600,
+        ..Default::default()
+    };
+    let (xfer, res) = server.rx_initial_with_ctx(
+        Packet::RRQ {
+            filename: file,
+            mode: Octet,
+            options: vec![TftpOption::Blocksize(1400)],
+        },
+        &ctx,
+    );
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![TftpOption::Blocksize(600)],
+        })
+    );
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::ACK(0)) => [
+            ResponseItem::Packet(Packet::DATA { block_num: 1, data: file_bytes.gen(600) }),
+        ]
+    );
+}
+
+#[test]
+fn wrq_blocksize_clamped() {
+    let (mut server, file, mut file_bytes) = wrq_fixture(700);
+    let ctx = RequestCtx {
+        max_blocksize: 600,
+        ..Default::default()
+    };
+    let (xfer, res) = server.rx_initial_with_ctx(
+        Packet::WRQ {
+            filename: file,
+            mode: Octet,
+            options: vec![TftpOption::Blocksize(1400)],
+        },
+        &ctx,
+    );
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![TftpOption::Blocksize(600)],
+        })
+    );
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 1, data: file_bytes.gen(600) }) => [
+            ResponseItem::Packet(Packet::ACK(1)),
+        ]
+    );
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 2, data: file_bytes.gen(100) }) => [
+            ResponseItem::Packet(Packet::ACK(2)),
+            ResponseItem::Done,
+        ]
+    );
+}
+
 #[test]
 fn wrq_blocksize() {
     let (mut server, file, mut file_bytes) = wrq_fixture(1234 + 1233);
@@ -671,7 +852,7 @@ struct Failer {
 impl Read for Failer {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if self.bytes == 0 {
-            Err(io::Error::new(io::ErrorKind::Other, "testing read fail"))
+            Err(io::Error::other("testing read fail"))
         } else {
             let amt = ::std::cmp::min(buf.len(), self.bytes);
             self.bytes -= amt;
@@ -682,7 +863,7 @@ impl Read for Failer {
 impl Write for Failer {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if self.bytes == 0 {
-            Err(io::Error::new(io::ErrorKind::Other, "testing write fail"))
+            Err(io::Error::other("testing write fail"))
         } else {
             self.bytes = self.bytes.saturating_sub(buf.len());
             Ok(buf.len()) // pretend we wrote stuff
@@ -692,6 +873,7 @@ impl Write for Failer {
         Ok(())
     }
 }
+impl Commit for Failer {}
 
 #[derive(Debug)]
 struct FailIO {
@@ -777,6 +959,7 @@ fn policy_readonly() {
         IOPolicyCfg {
             readonly: true,
             path: None,
+            ..Default::default()
         },
     );
     let mut v = vec![];
@@ -792,7 +975,7 @@ fn policy_readonly() {
     assert_eq!(v.len(), amt);
 
     let mut proxy = proxy;
-    if let Ok(_) = proxy.create_new(file_b.as_ref(), None) {
+    if proxy.create_new(file_b.as_ref(), None).is_ok() {
         panic!("create should not succeed");
     }
 }
@@ -814,6 +997,7 @@ fn policy_remap_directory() {
         IOPolicyCfg {
             readonly: false,
             path: Some("the_new_path".into()),
+            ..Default::default()
         },
     );
     assert!(proxy.open_read("the_new_path/file_a".as_ref()).is_err());
@@ -841,6 +1025,7 @@ fn policy_refuse_file_read_outside_cwd() {
         IOPolicyCfg {
             readonly: false,
             path: None,
+            ..Default::default()
         },
     );
 
@@ -869,6 +1054,7 @@ fn policy_refuse_file_write_outside_cwd() {
         IOPolicyCfg {
             readonly: false,
             path: None,
+            ..Default::default()
         },
     );
 
@@ -882,6 +1068,343 @@ fn policy_refuse_file_write_outside_cwd() {
     );
 }
 
+#[test]
+fn policy_remap_rules() {
+    let mut iof = TestIoFactory::new();
+    iof.possible_files.insert("boot/192.168.0.10".into(), 100);
+    iof.server_present_files.insert("boot/192.168.0.10".into());
+    iof.possible_files.insert("upload".into(), 100);
+    let remap = Rules::parse(
+        r"
+        rg \\ /
+        rG ^/boot/cfg$ boot/\i
+        a ^upload$
+        ",
+    )
+    .unwrap();
+    let mut server = TftpServerProto::new(
+        iof,
+        IOPolicyCfg {
+            remap,
+            ..Default::default()
+        },
+    );
+    let ctx = RequestCtx {
+        client: Some([192, 168, 0, 10].into()),
+        ..Default::default()
+    };
+
+    let (xfer, res) = server.rx_initial_with_ctx(
+        Packet::RRQ {
+            filename: r"\boot\cfg".into(),
+            mode: Octet,
+            options: vec![],
+        },
+        &ctx,
+    );
+    assert_matches!(res, Ok(Packet::DATA { block_num: 1, .. }));
+    assert!(xfer.is_some());
+
+    let (xfer, res) = server.rx_initial_with_ctx(
+        Packet::WRQ {
+            filename: "upload".into(),
+            mode: Octet,
+            options: vec![],
+        },
+        &ctx,
+    );
+    assert_eq!(res, Ok(ErrorCode::AccessViolation.into()));
+    assert!(xfer.is_none());
+}
+
+#[test]
+fn policy_backslashes() {
+    let mut iof = TestIoFactory::new();
+    iof.possible_files.insert("boot/x64/wdsnbp.com".into(), 100);
+    iof.server_present_files
+        .insert("boot/x64/wdsnbp.com".into());
+    iof.possible_files.insert("../secret".into(), 100);
+    iof.server_present_files.insert("../secret".into());
+
+    let proxy = IOPolicyProxy::new(
+        iof,
+        IOPolicyCfg {
+            backslashes: true,
+            ..Default::default()
+        },
+    );
+    assert!(proxy.open_read(r"boot\x64\wdsnbp.com".as_ref()).is_ok());
+    assert_matches!(
+        proxy.open_read(r"..\secret".as_ref()),
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied
+    );
+    assert_matches!(
+        proxy.open_read(r"\boot\x64\wdsnbp.com".as_ref()),
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn policy_case_insensitive() {
+    let mut io = MemIO::default();
+    io.add("tftp/Boot/x64/wdsnbp.com", b"boot");
+    // can't be told apart on a case-insensitive filesystem
+    io.add("tftp/Dup.txt", b"dup");
+    io.add("tftp/dup.TXT", b"dup");
+
+    let mut server = TftpServerProto::new(
+        io,
+        IOPolicyCfg {
+            path: Some("tftp".into()),
+            backslashes: true,
+            case_insensitive: true,
+            ..Default::default()
+        },
+    );
+    let mut read = |filename: &str| {
+        let packet = Packet::RRQ {
+            filename: filename.into(),
+            mode: Octet,
+            options: vec![],
+        };
+        server.rx_initial(packet).1
+    };
+    assert_matches!(
+        read(r"BOOT\X64\WdsNbp.com"),
+        Ok(Packet::DATA { block_num: 1, ref data }) if data == b"boot"
+    );
+    assert_matches!(
+        read("dup.txt"),
+        Ok(Packet::ERROR {
+            code: ErrorCode::NotDefined,
+            ..
+        })
+    );
+    assert_matches!(read("Dup.txt"), Ok(Packet::DATA { block_num: 1, .. }));
+    assert_matches!(
+        read("missing"),
+        Ok(Packet::ERROR {
+            code: ErrorCode::FileNotFound,
+            ..
+        })
+    );
+}
+
+/// A directory of its own under the system's temporary one, removed on drop
+/// so that failing tests don't leave it behind
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("tftp-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Lists the names in a directory, including hidden ones
+fn dir_entries(dir: &Path) -> Vec<String> {
+    let mut names = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn wrq_atomic_upload() {
+    let dir = TempDir::new("atomic");
+    let mut server = TftpServerProto::new(
+        FSAdapter,
+        IOPolicyCfg {
+            path: Some(dir.to_path_buf()),
+            ..Default::default()
+        },
+    );
+    let mut write = |filename: &str| {
+        let packet = Packet::WRQ {
+            filename: filename.into(),
+            mode: Octet,
+            options: vec![],
+        };
+        let (xfer, res) = server.rx_initial(packet);
+        assert_eq!(res, Ok(Packet::ACK(0)));
+        let mut xfer = xfer.unwrap();
+        assert_packets!(
+            xfer.rx(Packet::DATA { block_num: 1, data: vec![1; 512] }) => [
+                ResponseItem::Packet(Packet::ACK(1)),
+            ]
+        );
+        xfer
+    };
+
+    // only a hidden temporary file exists until the last block
+    let mut xfer = write("done.bin");
+    let entries = dir_entries(&dir);
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].starts_with(".done.bin."));
+    // which can't be read
+    let mut reader = TftpServerProto::new(
+        FSAdapter,
+        IOPolicyCfg {
+            path: Some(dir.to_path_buf()),
+            ..Default::default()
+        },
+    );
+    let (_, res) = reader.rx_initial(Packet::RRQ {
+        filename: entries[0].clone(),
+        mode: Octet,
+        options: vec![],
+    });
+    assert_matches!(
+        res,
+        Ok(Packet::ERROR {
+            code: ErrorCode::FileNotFound,
+            ..
+        })
+    );
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 2, data: vec![2; 10] }) => [
+            ResponseItem::Packet(Packet::ACK(2)),
+            ResponseItem::Done,
+        ]
+    );
+    assert_eq!(dir_entries(&dir), vec!["done.bin"]);
+    assert_eq!(std::fs::read(dir.join("done.bin")).unwrap().len(), 522);
+
+    // aborted uploads leave nothing behind
+    let mut xfer = write("aborted.bin");
+    assert_packets!(
+        xfer.rx(Packet::ERROR { code: ErrorCode::NotDefined, msg: "".into() }) => [
+            ResponseItem::Done,
+        ]
+    );
+    assert_eq!(dir_entries(&dir), vec!["done.bin"]);
+    drop(write("dropped.bin"));
+    assert_eq!(dir_entries(&dir), vec!["done.bin"]);
+}
+
+/// Uploads a file of a single block to a server storing it in `dir` under `policy`
+fn upload(dir: &Path, policy: WritePolicy, filename: &str, data: &[u8]) -> Option<Packet> {
+    let mut server = TftpServerProto::new(
+        FSAdapter,
+        IOPolicyCfg {
+            path: Some(dir.to_owned()),
+            write_policy: policy,
+            ..Default::default()
+        },
+    );
+    let packet = Packet::WRQ {
+        filename: filename.into(),
+        mode: Octet,
+        options: vec![],
+    };
+    match server.rx_initial(packet) {
+        (Some(mut xfer), Ok(Packet::ACK(0))) => {
+            assert_packets!(
+                xfer.rx(Packet::DATA { block_num: 1, data: data.to_vec() }) => [
+                    ResponseItem::Packet(Packet::ACK(1)),
+                    ResponseItem::Done,
+                ]
+            );
+            None
+        }
+        (_, res) => res.ok(),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn wrq_write_policies() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let dir = TempDir::new("overwrite");
+    let mode = |name: &str| {
+        std::fs::metadata(dir.join(name))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+    let read = |name: &str| std::fs::read(dir.join(name)).unwrap();
+    std::fs::write(dir.join("cfg"), b"v1").unwrap();
+    std::fs::set_permissions(dir.join("cfg"), PermissionsExt::from_mode(0o640)).unwrap();
+
+    assert_matches!(
+        upload(&dir, WritePolicy::Never, "cfg", b"v2"),
+        Some(Packet::ERROR {
+            code: ErrorCode::FileExists,
+            ..
+        })
+    );
+    assert_matches!(
+        upload(&dir, WritePolicy::OverwriteWorldWritable, "cfg", b"v2"),
+        Some(Packet::ERROR {
+            code: ErrorCode::FileExists,
+            ..
+        })
+    );
+    assert_eq!(read("cfg"), b"v1");
+
+    // replaced files keep their permissions
+    assert_eq!(upload(&dir, WritePolicy::Overwrite, "cfg", b"v2"), None);
+    assert_eq!(read("cfg"), b"v2");
+    assert_eq!(mode("cfg"), 0o640);
+
+    std::fs::set_permissions(dir.join("cfg"), PermissionsExt::from_mode(0o666)).unwrap();
+    assert_eq!(
+        upload(&dir, WritePolicy::OverwriteWorldWritable, "cfg", b"v3"),
+        None
+    );
+    assert_eq!(read("cfg"), b"v3");
+    assert_eq!(mode("cfg"), 0o666);
+    assert_eq!(
+        upload(&dir, WritePolicy::OverwriteWorldWritable, "new", b"new"),
+        None
+    );
+    std::fs::remove_file(dir.join("new")).unwrap();
+    assert_eq!(dir_entries(&dir), vec!["cfg"]);
+
+    for data in [b"v4", b"v5"] {
+        assert_eq!(upload(&dir, WritePolicy::KeepNumbered, "cfg", data), None);
+    }
+    assert_eq!(dir_entries(&dir), vec!["cfg", "cfg.1", "cfg.2"]);
+    assert_eq!(
+        (read("cfg"), read("cfg.1"), read("cfg.2")),
+        (b"v5".to_vec(), b"v3".to_vec(), b"v4".to_vec())
+    );
+
+    let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    std::fs::File::options()
+        .write(true)
+        .open(dir.join("cfg"))
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    assert_eq!(
+        upload(&dir, WritePolicy::KeepTimestamped, "cfg", b"v6"),
+        None
+    );
+    assert_eq!(read("cfg"), b"v6");
+    assert_eq!(read("cfg.20231114T221320Z"), b"v5");
+}
+
 #[test]
 fn option_timeout_rrq() {
     let (mut server, file, _) = rrq_fixture(1234);
@@ -918,6 +1441,35 @@ fn option_timeout_wrq() {
     assert_eq!(xfer.timeout(), Some(Duration::from_secs(5)));
 }
 
+#[test]
+fn option_utimeout_rrq() {
+    let (mut server, file, _) = rrq_fixture(1234);
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: file,
+        mode: Octet,
+        options: vec![TftpOption::TimeoutMicros(25000), TftpOption::TimeoutSecs(1)],
+    });
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![TftpOption::TimeoutMicros(25000), TftpOption::TimeoutSecs(1),],
+        })
+    );
+    let xfer = xfer.unwrap();
+    assert_eq!(xfer.timeout(), Some(Duration::from_micros(25000)));
+}
+
+#[test]
+fn option_utimeout_below_floor_ignored() {
+    let (mut server, file, _) = rrq_fixture(1234);
+    let mut request = vec![0, 1];
+    request.extend_from_slice(file.as_bytes());
+    request.extend_from_slice(b"\0octet\0utimeout\09999\0");
+    let (xfer, res) = server.rx_initial(Packet::read(&request).unwrap());
+    assert_matches!(res, Ok(Packet::DATA { block_num: 1, .. }));
+    assert_eq!(xfer.unwrap().timeout(), None);
+}
+
 #[test]
 fn option_tsize_rrq() {
     let (mut server, file, _) = rrq_fixture(1234);
@@ -953,6 +1505,818 @@ fn option_tsize_wrq() {
     assert_matches!(xfer.as_ref().map(Transfer::is_done), Some(false));
 }
 
+#[test]
+fn wrq_tsize_exact() {
+    let io = MemIO::default();
+    let files = io.files.clone();
+    let mut server = TftpServerProto::new(io, Default::default());
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![TftpOption::TransferSize(600)],
+    });
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![TftpOption::TransferSize(600)],
+        })
+    );
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 1, data: vec![1; 512] }) => [
+            ResponseItem::Packet(Packet::ACK(1)),
+        ]
+    );
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 2, data: vec![2; 88] }) => [
+            ResponseItem::Packet(Packet::ACK(2)),
+            ResponseItem::Done,
+        ]
+    );
+    assert_eq!(files.borrow()["file"].len(), 600);
+}
+
+#[test]
+fn wrq_tsize_exceeded() {
+    let mut server = TftpServerProto::new(MemIO::default(), Default::default());
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![TftpOption::TransferSize(600)],
+    });
+    assert_matches!(res, Ok(Packet::OACK { .. }));
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 1, data: vec![1; 512] }) => [
+            ResponseItem::Packet(Packet::ACK(1)),
+        ]
+    );
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 2, data: vec![2; 512] }) => [
+            match ResponseItem::Packet(Packet::ERROR { code: ErrorCode::DiskFull, .. }),
+            ResponseItem::Done,
+        ]
+    );
+    assert!(xfer.is_done());
+}
+
+#[test]
+fn wrq_tsize_short() {
+    let mut server = TftpServerProto::new(MemIO::default(), Default::default());
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![TftpOption::TransferSize(600)],
+    });
+    assert_matches!(res, Ok(Packet::OACK { .. }));
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 1, data: vec![1; 512] }) => [
+            ResponseItem::Packet(Packet::ACK(1)),
+        ]
+    );
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 2, data: vec![2; 87] }) => [
+            match ResponseItem::Packet(Packet::ERROR { code: ErrorCode::NotDefined, .. }),
+            ResponseItem::Done,
+        ]
+    );
+    assert!(xfer.is_done());
+}
+
+#[test]
+fn fs_incomplete_upload_removed() {
+    let dir = TempDir::new("incomplete");
+    let short = dir.join("short");
+    let mut file = FSAdapter.create_new(&short, Some(10)).unwrap();
+    file.write_all(&[1; 5]).unwrap();
+    drop(file);
+    assert!(!short.exists());
+
+    let full = dir.join("full");
+    let mut file = FSAdapter.create_new(&full, Some(10)).unwrap();
+    file.write_all(&[1; 10]).unwrap();
+    file.commit().unwrap();
+    drop(file);
+    assert_eq!(std::fs::read(&full).unwrap(), vec![1; 10]);
+}
+
+#[test]
+fn option_rollover_rrq() {
+    let size_bytes = 512 * 70_000 + 85;
+    let (mut server, file, mut file_bytes) = rrq_fixture(size_bytes);
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: file,
+        mode: Octet,
+        options: vec![TftpOption::Rollover(1)],
+    });
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![TftpOption::Rollover(1)],
+        })
+    );
+    let mut xfer = xfer.unwrap();
+
+    let mut block = 0u16;
+    for _ in 0..(size_bytes / 512) {
+        let new_block = block.checked_add(1).unwrap_or(1);
+        assert_packets!(
+            xfer.rx(Packet::ACK(block)) => [
+                ResponseItem::Packet(Packet::DATA {
+                    block_num: new_block, data: file_bytes.gen(512),
+                }),
+            ]
+        );
+        block = new_block;
+    }
+    assert_packets!(
+        xfer.rx(Packet::ACK(block)) => [
+            ResponseItem::Packet(Packet::DATA {
+                block_num: block + 1, data: file_bytes.gen(85),
+            }),
+        ]
+    );
+    assert_packets!(xfer.rx(Packet::ACK(block + 1)) => [ResponseItem::Done,]);
+}
+
+#[test]
+fn option_rollover_wrq() {
+    let size_bytes = 512 * 70_000 + 85;
+    let (mut server, file, mut file_bytes) = wrq_fixture(size_bytes);
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: file,
+        mode: Octet,
+        options: vec![TftpOption::Rollover(1)],
+    });
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![TftpOption::Rollover(1)],
+        })
+    );
+    let mut xfer = xfer.unwrap();
+
+    let mut block_num = 1u16;
+    for _ in 0..(size_bytes / 512) {
+        assert_packets!(
+            xfer.rx(Packet::DATA { block_num, data: file_bytes.gen(512), }) => [
+                ResponseItem::Packet(Packet::ACK(block_num)),
+            ]
+        );
+        block_num = block_num.checked_add(1).unwrap_or(1);
+    }
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num, data: file_bytes.gen(85), }) => [
+            ResponseItem::Packet(Packet::ACK(block_num)),
+            ResponseItem::Done,
+        ]
+    )
+}
+
+fn no_rollover_cfg() -> TransferCfg {
+    TransferCfg {
+        rollover: Rollover::Forbidden,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn rollover_forbidden_rrq_refused() {
+    let mut iof = TestIoFactory::new();
+    iof.possible_files.insert("large".into(), 512 * 65_535);
+    iof.server_present_files.insert("large".into());
+    iof.possible_files.insert("fits".into(), 512 * 65_535 - 1);
+    iof.server_present_files.insert("fits".into());
+    let mut server = TftpServerProto::with_transfer_cfg(iof, Default::default(), no_rollover_cfg());
+
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: "large".into(),
+        mode: Octet,
+        options: vec![],
+    });
+    assert_matches!(
+        res,
+        Ok(Packet::ERROR {
+            code: ErrorCode::NotDefined,
+            ..
+        })
+    );
+    assert!(xfer.is_none());
+
+    // the last block of this one is 65535
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: "fits".into(),
+        mode: Octet,
+        options: vec![],
+    });
+    assert_matches!(res, Ok(Packet::DATA { block_num: 1, .. }));
+    assert!(xfer.is_some());
+
+    // negotiating the option overrides the server default
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: "large".into(),
+        mode: Octet,
+        options: vec![TftpOption::Rollover(0)],
+    });
+    assert_matches!(res, Ok(Packet::OACK { .. }));
+    assert!(xfer.is_some());
+}
+
+#[test]
+fn rollover_forbidden_wrq() {
+    let mut iof = TestIoFactory::new();
+    iof.possible_files.insert("large".into(), 512 * 65_536);
+    iof.enforce_full_write = false;
+    let mut server = TftpServerProto::with_transfer_cfg(iof, Default::default(), no_rollover_cfg());
+
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: "large".into(),
+        mode: Octet,
+        options: vec![TftpOption::TransferSize(512 * 65_536)],
+    });
+    assert_matches!(
+        res,
+        Ok(Packet::ERROR {
+            code: ErrorCode::NotDefined,
+            ..
+        })
+    );
+    assert!(xfer.is_none());
+
+    // without the size, the transfer fails once it runs out of block numbers
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: "large".into(),
+        mode: Octet,
+        options: vec![],
+    });
+    assert_eq!(res, Ok(Packet::ACK(0)));
+    let mut xfer = xfer.unwrap();
+    let mut file_bytes = ByteGen::new("large");
+    for block_num in 1..=u16::MAX {
+        assert_packets!(
+            xfer.rx(Packet::DATA { block_num, data: file_bytes.gen(512), }) => [
+                ResponseItem::Packet(Packet::ACK(block_num)),
+            ]
+        );
+    }
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 0, data: file_bytes.gen(512), }) => [
+            match ResponseItem::Packet(Packet::ERROR { code: ErrorCode::NotDefined, .. }),
+            ResponseItem::Done,
+        ]
+    );
+    assert!(xfer.is_done());
+}
+
+/// The file, whether it was written, why it was aborted if it was, and the size transferred
+type Event = (String, bool, Option<AbortReason>, u64);
+
+/// Records the end of every transfer
+#[derive(Default)]
+struct Recorder {
+    events: std::sync::Mutex<Vec<Event>>,
+}
+impl TransferObserver for Recorder {
+    fn on_complete(&self, file: &Path, is_write: bool, bytes: u64, _: Duration) {
+        let file = file.to_str().unwrap().to_owned();
+        self.events
+            .lock()
+            .unwrap()
+            .push((file, is_write, None, bytes));
+    }
+    fn on_abort(&self, file: &Path, is_write: bool, reason: &AbortReason) {
+        let file = file.to_str().unwrap().to_owned();
+        let event = (file, is_write, Some(reason.clone()), 0);
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn observer_lifecycle() {
+    let mut io = MemIO::default();
+    io.add("small", &[1; 100]);
+    let mut server = TftpServerProto::new(io, Default::default());
+    let recorder = std::sync::Arc::new(Recorder::default());
+    server.set_observer(recorder.clone());
+    let mut start = |filename: &str, is_write| {
+        let (filename, mode, options) = (filename.into(), Octet, vec![]);
+        let packet = if is_write {
+            Packet::WRQ {
+                filename,
+                mode,
+                options,
+            }
+        } else {
+            Packet::RRQ {
+                filename,
+                mode,
+                options,
+            }
+        };
+        server.rx_initial(packet).0.unwrap()
+    };
+    let last_event = || recorder.events.lock().unwrap().pop().unwrap();
+
+    let mut xfer = start("small", false);
+    assert_packets!(xfer.rx(Packet::ACK(1)) => [ResponseItem::Done,]);
+    assert_eq!(last_event(), ("small".into(), false, None, 100));
+
+    let mut xfer = start("upload", true);
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 1, data: vec![1; 10] }) => [
+            ResponseItem::Packet(Packet::ACK(1)),
+            ResponseItem::Done,
+        ]
+    );
+    assert_eq!(last_event(), ("upload".into(), true, None, 10));
+
+    let mut xfer = start("client_error", true);
+    let error = Packet::ERROR {
+        code: ErrorCode::DiskFull,
+        msg: "full".into(),
+    };
+    assert_packets!(xfer.rx(error.clone()) => [ResponseItem::Done,]);
+    let reason = AbortReason::ClientError(ErrorCode::DiskFull, "full".into());
+    assert_eq!(last_event(), ("client_error".into(), true, Some(reason), 0));
+
+    let mut xfer = start("server_error", true);
+    assert_packets!(
+        xfer.rx(Packet::DATA { block_num: 2, data: vec![1; 10] }) => [
+            match ResponseItem::Packet(Packet::ERROR { code: ErrorCode::IllegalTFTP, .. }),
+            ResponseItem::Done,
+        ]
+    );
+    assert_matches!(
+        last_event(),
+        (
+            _,
+            true,
+            Some(AbortReason::ServerError(ErrorCode::IllegalTFTP, _)),
+            0
+        )
+    );
+
+    let mut xfer = start("small", false);
+    xfer.timeout_expired();
+    assert!(recorder.events.lock().unwrap().is_empty());
+    assert_eq!(xfer.timeout_expired(), ResponseItem::Done);
+    let reason = AbortReason::Timeout;
+    assert_eq!(last_event(), ("small".into(), false, Some(reason), 0));
+
+    drop(start("small", false));
+    let reason = AbortReason::Dropped;
+    assert_eq!(last_event(), ("small".into(), false, Some(reason), 0));
+    assert!(recorder.events.lock().unwrap().is_empty());
+}
+
+/// Records how long completed transfers took
+#[derive(Default)]
+struct Durations(std::sync::Mutex<Vec<Duration>>);
+impl TransferObserver for Durations {
+    fn on_complete(&self, _: &Path, _: bool, _: u64, duration: Duration) {
+        self.0.lock().unwrap().push(duration);
+    }
+}
+
+#[test]
+fn observer_duration_follows_clock() {
+    let mut io = MemIO::default();
+    io.add("small", &[1; 100]);
+    let mut server = TftpServerProto::new(io, Default::default());
+    let durations = std::sync::Arc::new(Durations::default());
+    server.set_observer(durations.clone());
+    let clock = ManualClock::new();
+    server.set_clock(clock.clone());
+
+    let (xfer, _) = server.rx_initial(Packet::RRQ {
+        filename: "small".into(),
+        mode: Octet,
+        options: vec![],
+    });
+    let mut xfer = xfer.unwrap();
+    clock.advance(Duration::from_secs(5));
+    assert_packets!(xfer.rx(Packet::ACK(1)) => [ResponseItem::Done,]);
+    assert_eq!(*durations.0.lock().unwrap(), vec![Duration::from_secs(5)]);
+}
+
+#[test]
+fn option_multicast_rrq() {
+    let mut io = MemIO::default();
+    io.add("image", &[7; 1300]);
+    let mut server = TftpServerProto::new(io, Default::default());
+    let request = Packet::RRQ {
+        filename: "image".into(),
+        mode: Octet,
+        options: vec![TftpOption::Multicast(None)],
+    };
+
+    // ignored unless the server offers a group
+    let (_, res) = server.rx_initial(request.clone());
+    assert_matches!(res, Ok(Packet::DATA { block_num: 1, .. }));
+
+    let group = "239.255.0.1:1758".parse().unwrap();
+    let ctx = RequestCtx {
+        multicast: Some(group),
+        ..Default::default()
+    };
+    let (xfer, res) = server.rx_initial_with_ctx(request, &ctx);
+    assert_eq!(
+        res,
+        Ok(Packet::OACK {
+            options: vec![TftpOption::Multicast(Some(MulticastGroup {
+                addr: group,
+                master: true,
+            }))],
+        })
+    );
+    assert_matches!(xfer.as_ref().map(Transfer::is_done), Some(false));
+}
+
+#[test]
+fn option_multicast_wrq_ignored() {
+    let (mut server, file, _) = wrq_fixture_early_termination(1234);
+    let ctx = RequestCtx {
+        multicast: Some("239.255.0.1:1758".parse().unwrap()),
+        ..Default::default()
+    };
+    let (_, res) = server.rx_initial_with_ctx(
+        Packet::WRQ {
+            filename: file,
+            mode: Octet,
+            options: vec![TftpOption::Multicast(None)],
+        },
+        &ctx,
+    );
+    assert_eq!(res, Ok(Packet::ACK(0)));
+}
+
+/// Decodes the packets sent by a multicast session
+fn decode(datagrams: Datagrams) -> Vec<(SocketAddr, Packet)> {
+    datagrams
+        .iter()
+        .map(|(addr, bytes)| (*addr, Packet::read(bytes).unwrap()))
+        .collect()
+}
+
+#[test]
+fn multicast_master_handover() {
+    let contents = (0..1300).map(|i| i as u8).collect::<Vec<_>>();
+    let mut io = MemIO::default();
+    io.add("image", &contents);
+    let mut server = TftpServerProto::new(io, Default::default());
+    let request = Packet::RRQ {
+        filename: "image".into(),
+        mode: Octet,
+        options: vec![TftpOption::Multicast(None)],
+    };
+    let group: SocketAddr = "239.255.0.1:1758".parse().unwrap();
+    let (a, b) = (
+        "127.0.0.1:5000".parse().unwrap(),
+        "127.0.0.1:5001".parse().unwrap(),
+    );
+    let oack = |master| Packet::OACK {
+        options: vec![TftpOption::Multicast(Some(MulticastGroup {
+            addr: group,
+            master,
+        }))],
+    };
+    let data = |block_num: u16| {
+        let start = (block_num as usize - 1) * 512;
+        Packet::DATA {
+            block_num,
+            data: contents[start..contents.len().min(start + 512)].to_vec(),
+        }
+    };
+
+    let (session, res) = MulticastSession::new(
+        &mut server,
+        request.clone(),
+        &Default::default(),
+        group,
+        a,
+        Duration::from_secs(3),
+    );
+    assert_eq!(res, Ok(oack(true)));
+    let mut session = session.unwrap();
+    assert!(session.accepts(&server, &request, &Default::default()));
+
+    let mut ack = |session: &mut MulticastSession<_>, from, block| {
+        let packet = Packet::ACK(block);
+        decode(
+            session
+                .receive(&mut server, from, (&packet).into())
+                .unwrap(),
+        )
+    };
+    assert_eq!(ack(&mut session, a, 0), vec![(group, data(1))]);
+    assert_eq!(decode(session.join(b).unwrap()), vec![(b, oack(false))]);
+    // only the master client drives the transfer
+    assert_eq!(ack(&mut session, b, 0), vec![]);
+    assert_eq!(ack(&mut session, a, 1), vec![(group, data(2))]);
+    assert_eq!(ack(&mut session, a, 2), vec![(group, data(3))]);
+    assert_eq!(ack(&mut session, a, 3), vec![(b, oack(true))]);
+    assert_eq!(session.clients(), 1);
+
+    // the new master client only missed the first block
+    assert_eq!(ack(&mut session, b, 0), vec![(group, data(1))]);
+    assert_eq!(ack(&mut session, b, 3), vec![]);
+    assert!(session.is_done());
+}
+
+#[test]
+fn multicast_sessions_match_remapped_paths() {
+    let mut io = MemIO::default();
+    io.add("image", &[7; 1300]);
+    io.add("cfg/10.0.0.1", &[1; 100]);
+    io.add("cfg/10.0.0.2", &[2; 100]);
+    let remap = Rules::parse(
+        r"
+        r ^alias$ image
+        r ^cfg$ cfg/\i
+        ",
+    )
+    .unwrap();
+    let mut server = TftpServerProto::new(
+        io,
+        IOPolicyCfg {
+            remap,
+            ..Default::default()
+        },
+    );
+    let request = |filename: &str| Packet::RRQ {
+        filename: filename.into(),
+        mode: Octet,
+        options: vec![TftpOption::Multicast(None)],
+    };
+    let ctx = |client: [u8; 4]| RequestCtx {
+        client: Some(client.into()),
+        ..Default::default()
+    };
+    let start = |server: &mut TftpServerProto<_>, filename| {
+        let (session, res) = MulticastSession::new(
+            server,
+            request(filename),
+            &ctx([10, 0, 0, 1]),
+            "239.255.0.1:1758".parse().unwrap(),
+            "10.0.0.1:5000".parse().unwrap(),
+            Duration::from_secs(3),
+        );
+        assert_matches!(res, Ok(Packet::OACK { .. }));
+        session.unwrap()
+    };
+
+    // different names for the same file
+    let session = start(&mut server, "image");
+    assert!(session.accepts(&server, &request("alias"), &ctx([10, 0, 0, 2])));
+    assert!(!session.accepts(&server, &request("cfg"), &ctx([10, 0, 0, 2])));
+
+    // the same name for different files
+    let session = start(&mut server, "cfg");
+    assert!(session.accepts(&server, &request("cfg"), &ctx([10, 0, 0, 1])));
+    assert!(!session.accepts(&server, &request("cfg"), &ctx([10, 0, 0, 2])));
+}
+
+#[test]
+fn multicast_master_timeout() {
+    let mut io = MemIO::default();
+    io.add("image", &[7; 1300]);
+    let mut server = TftpServerProto::new(io, Default::default());
+    let request = Packet::RRQ {
+        filename: "image".into(),
+        mode: Octet,
+        options: vec![TftpOption::Multicast(None)],
+    };
+    let group: SocketAddr = "239.255.0.1:1758".parse().unwrap();
+    let (a, b) = (
+        "127.0.0.1:5000".parse().unwrap(),
+        "127.0.0.1:5001".parse().unwrap(),
+    );
+    let (session, _) = MulticastSession::new(
+        &mut server,
+        request,
+        &Default::default(),
+        group,
+        a,
+        Duration::from_secs(3),
+    );
+    let mut session = session.unwrap();
+    session.join(b).unwrap();
+
+    // the OACK is resent once, then the master client is replaced
+    let resent = decode(session.expire().unwrap().unwrap());
+    assert_matches!(resent[..], [(addr, Packet::OACK { .. })] if addr == a);
+    let resent = decode(session.expire().unwrap().unwrap());
+    assert_matches!(resent[..], [(addr, Packet::OACK { .. })] if addr == b);
+    assert_eq!(session.clients(), 1);
+
+    // leaving ends the session
+    let error = Packet::from(ErrorCode::NotDefined);
+    let sent = session.receive(&mut server, b, (&error).into()).unwrap();
+    assert!(sent.is_empty());
+    assert!(session.is_done());
+}
+
+#[test]
+fn multicast_master_changes_reported() {
+    let mut io = MemIO::default();
+    io.add("image", &[7; 1300]);
+    let mut server = TftpServerProto::new(io, Default::default());
+    let recorder = std::sync::Arc::new(Recorder::default());
+    server.set_observer(recorder.clone());
+    let request = Packet::RRQ {
+        filename: "image".into(),
+        mode: Octet,
+        options: vec![TftpOption::Multicast(None)],
+    };
+    let group: SocketAddr = "239.255.0.1:1758".parse().unwrap();
+    let (a, b, c) = (
+        "127.0.0.1:5000".parse().unwrap(),
+        "127.0.0.1:5001".parse().unwrap(),
+        "127.0.0.1:5002".parse().unwrap(),
+    );
+    let (session, _) = MulticastSession::new(
+        &mut server,
+        request,
+        &Default::default(),
+        group,
+        a,
+        Duration::from_secs(3),
+    );
+    let mut session = session.unwrap();
+    session.join(b).unwrap();
+    session.join(c).unwrap();
+    let mut receive = |session: &mut MulticastSession<_>, from, packet: Packet| {
+        session
+            .receive(&mut server, from, (&packet).into())
+            .unwrap();
+    };
+    let events = || mem::take(&mut *recorder.events.lock().unwrap());
+
+    // every master client leaving mid-file ends its read
+    receive(&mut session, a, Packet::ACK(0));
+    session.expire().unwrap();
+    session.expire().unwrap();
+    let reason = AbortReason::Timeout;
+    assert_eq!(events(), vec![("image".into(), false, Some(reason), 0)]);
+
+    receive(&mut session, b, Packet::ACK(0));
+    let error = Packet::ERROR {
+        code: ErrorCode::DiskFull,
+        msg: "full".into(),
+    };
+    receive(&mut session, b, error);
+    let reason = AbortReason::ClientError(ErrorCode::DiskFull, "full".into());
+    assert_eq!(events(), vec![("image".into(), false, Some(reason), 0)]);
+
+    // and the last one reads it again from the start
+    for block in 0..3 {
+        receive(&mut session, c, Packet::ACK(block));
+    }
+    assert!(events().is_empty());
+    receive(&mut session, c, Packet::ACK(3));
+    assert_eq!(events(), vec![("image".into(), false, None, 1300)]);
+    assert!(session.is_done());
+}
+
+/// Keeps files in memory, for checking the exact contents of transferred files
+#[derive(Default)]
+struct MemIO {
+    files: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+}
+impl MemIO {
+    fn add(&mut self, name: &str, contents: &[u8]) {
+        self.files
+            .borrow_mut()
+            .insert(name.to_owned(), contents.to_vec());
+    }
+}
+impl IOAdapter for MemIO {
+    type R = io::Cursor<Vec<u8>>;
+    type W = MemWriter;
+    fn open_read(&self, file: &Path) -> io::Result<(Self::R, Option<u64>)> {
+        let filename = file.to_str().expect("not a valid string");
+        match self.files.borrow().get(filename) {
+            Some(v) => Ok((io::Cursor::new(v.clone()), Some(v.len() as u64))),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+    fn create_new(&mut self, file: &Path, _: Option<u64>) -> io::Result<Self::W> {
+        let filename = file.to_str().expect("not a valid string").to_owned();
+        let mut files = self.files.borrow_mut();
+        if files.contains_key(&filename) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        files.insert(filename.clone(), vec![]);
+        Ok(MemWriter {
+            files: self.files.clone(),
+            filename,
+        })
+    }
+    fn list_dir(&self, dir: &Path) -> io::Result<Vec<OsString>> {
+        // directories only exist as the parents of files
+        let names = self
+            .files
+            .borrow()
+            .keys()
+            .filter_map(|file| {
+                let first = Path::new(file)
+                    .strip_prefix(dir)
+                    .ok()?
+                    .components()
+                    .next()?;
+                Some(first.as_os_str().to_owned())
+            })
+            .collect::<HashSet<_>>();
+        Ok(names.into_iter().collect())
+    }
+}
+
+#[derive(Debug)]
+struct MemWriter {
+    files: Rc<RefCell<HashMap<String, Vec<u8>>>>,
+    filename: String,
+}
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut files = self.files.borrow_mut();
+        files
+            .get_mut(&self.filename)
+            .unwrap()
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Commit for MemWriter {}
+
+/// Wraps `MemIO`, making reads and writes fail with `WouldBlock` beyond a byte budget
+#[derive(Default)]
+struct ThrottledIO {
+    mem: MemIO,
+    budget: Rc<Cell<usize>>,
+}
+impl ThrottledIO {
+    fn new(budget: usize) -> Self {
+        let io = Self::default();
+        io.budget.set(budget);
+        io
+    }
+}
+impl IOAdapter for ThrottledIO {
+    type R = Throttled<io::Cursor<Vec<u8>>>;
+    type W = Throttled<MemWriter>;
+    fn open_read(&self, file: &Path) -> io::Result<(Self::R, Option<u64>)> {
+        let (inner, len) = self.mem.open_read(file)?;
+        let budget = self.budget.clone();
+        Ok((Throttled { inner, budget }, len))
+    }
+    fn create_new(&mut self, file: &Path, len: Option<u64>) -> io::Result<Self::W> {
+        let inner = self.mem.create_new(file, len)?;
+        let budget = self.budget.clone();
+        Ok(Throttled { inner, budget })
+    }
+}
+
+#[derive(Debug)]
+struct Throttled<T> {
+    inner: T,
+    budget: Rc<Cell<usize>>,
+}
+impl<T> Throttled<T> {
+    fn allowed(&self, len: usize) -> io::Result<usize> {
+        match self.budget.get().min(len) {
+            0 if len > 0 => Err(io::ErrorKind::WouldBlock.into()),
+            n => {
+                self.budget.set(self.budget.get() - n);
+                Ok(n)
+            }
+        }
+    }
+}
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.allowed(buf.len())?;
+        self.inner.read(&mut buf[..n])
+    }
+}
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.allowed(buf.len())?;
+        self.inner.write(&buf[..n])
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<W: Commit> Commit for Throttled<W> {
+    fn commit(&mut self) -> io::Result<()> {
+        self.inner.commit()
+    }
+}
+
 // TODO: maybe switch tests to use paths ?
 struct TestIoFactory {
     server_present_files: HashSet<String>,
@@ -1077,6 +2441,100 @@ fn wrq_timeout_repeat_ack_repeat() {
     assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(1));
 }
 
+#[test]
+fn rrq_timeout_max_retries() {
+    let mut io = MemIO::default();
+    io.add("file", &[7; 700]);
+    let mut server = TftpServerProto::with_transfer_cfg(
+        io,
+        Default::default(),
+        TransferCfg {
+            max_retries: 3,
+            ..Default::default()
+        },
+    );
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![],
+    });
+    assert_matches!(res, Ok(Packet::DATA { block_num: 1, .. }));
+    let mut xfer = xfer.unwrap();
+    assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(1));
+    assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(1));
+
+    // progress resets the retry count
+    assert_packets!(
+        xfer.rx(Packet::ACK(1)) => [
+            ResponseItem::Packet(Packet::DATA { block_num: 2, data: vec![7; 188] }),
+        ]
+    );
+    for _ in 0..3 {
+        assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(1));
+    }
+    assert_eq!(xfer.timeout_expired(), ResponseItem::Done);
+    assert!(xfer.is_done());
+}
+
+#[test]
+fn wrq_timeout_backoff() {
+    let mut server = TftpServerProto::with_transfer_cfg(
+        MemIO::default(),
+        Default::default(),
+        TransferCfg {
+            max_retries: 5,
+            max_backoff: Some(Duration::from_secs(10)),
+            ..Default::default()
+        },
+    );
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![],
+    });
+    assert_eq!(res, Ok(Packet::ACK(0)));
+    let mut xfer = xfer.unwrap();
+    let base = Duration::from_secs(2);
+    assert_eq!(xfer.retry_timeout(base), base);
+    let mut expected = vec![4, 8, 10, 10, 10].into_iter();
+    for _ in 0..5 {
+        assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(1));
+        let secs = expected.next().unwrap();
+        assert_eq!(xfer.retry_timeout(base), Duration::from_secs(secs));
+    }
+    assert_eq!(xfer.timeout_expired(), ResponseItem::Done);
+}
+
+#[test]
+fn retransmit_is_not_a_retry() {
+    let (mut server, file, _) = rrq_fixture(100);
+    let (xfer, _) = server.rx_initial(Packet::RRQ {
+        filename: file,
+        mode: Octet,
+        options: vec![],
+    });
+    let mut xfer = xfer.unwrap();
+    for _ in 0..5 {
+        assert_eq!(xfer.retransmit(), ResponseItem::RepeatLast(1));
+    }
+    assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(1));
+    assert_eq!(xfer.timeout_expired(), ResponseItem::Done);
+}
+
+#[test]
+fn timeout_without_backoff_is_constant() {
+    let (mut server, file, _) = rrq_fixture(100);
+    let (xfer, _) = server.rx_initial(Packet::RRQ {
+        filename: file,
+        mode: Octet,
+        options: vec![],
+    });
+    let mut xfer = xfer.unwrap();
+    let base = Duration::from_secs(3);
+    assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(1));
+    assert_eq!(xfer.retry_timeout(base), base);
+}
+
 #[test]
 fn rrq_windowsize_2_ok() {
     let (mut server, file, mut file_bytes) = rrq_fixture(512 * 3 + 123 /*4 blocks*/);
@@ -1183,6 +2641,99 @@ fn rrq_windowsize_partial_resume() {
     assert!(xfer.is_done());
 }
 
+#[test]
+fn rrq_blocked_read_resumes() {
+    let contents = (0..40).collect::<Vec<u8>>();
+    let mut io = ThrottledIO::new(12);
+    io.mem.add("file", &contents);
+    let budget = io.budget.clone();
+    let mut server = TftpServerProto::new(io, Default::default());
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![TftpOption::Blocksize(8), TftpOption::WindowSize(3)],
+    });
+    assert_matches!(res, Ok(Packet::OACK { .. }));
+    let mut xfer = xfer.unwrap();
+
+    // the second block is only half read before blocking
+    assert_packets!(
+        xfer.rx(Packet::ACK(0)) => [
+            ResponseItem::Packet(Packet::DATA { block_num: 1, data: contents[..8].to_vec(), }),
+        ]
+    );
+    assert!(xfer.is_blocked());
+    assert_eq!(xfer.resume().next(), None);
+
+    budget.set(usize::MAX);
+    let mut resumed = xfer.resume();
+    assert_eq!(
+        resumed.next().map(parsed),
+        Some(ResponseItem::Packet(Packet::DATA {
+            block_num: 2,
+            data: contents[8..16].to_vec()
+        }))
+    );
+    assert_eq!(
+        resumed.next().map(parsed),
+        Some(ResponseItem::Packet(Packet::DATA {
+            block_num: 3,
+            data: contents[16..24].to_vec()
+        }))
+    );
+    assert_eq!(resumed.next(), None);
+    assert!(!xfer.is_blocked());
+
+    assert_packets!(
+        xfer.rx(Packet::ACK(3)) => [
+            ResponseItem::Packet(Packet::DATA { block_num: 4, data: contents[24..32].to_vec(), }),
+            ResponseItem::Packet(Packet::DATA { block_num: 5, data: contents[32..].to_vec(), }),
+            ResponseItem::Packet(Packet::DATA { block_num: 6, data: vec![], }),
+        ]
+    );
+}
+
+#[test]
+fn wrq_blocked_write_acks_once_written() {
+    let io = ThrottledIO::new(0);
+    let files = io.mem.files.clone();
+    let budget = io.budget.clone();
+    let mut server = TftpServerProto::new(io, Default::default());
+    let (xfer, res) = server.rx_initial(Packet::WRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![],
+    });
+    assert_eq!(res, Ok(Packet::ACK(0)));
+    let mut xfer = xfer.unwrap();
+
+    let block = vec![7; 512];
+    let data = |block_num, data: &[u8]| Packet::DATA {
+        block_num,
+        data: data.to_vec(),
+    };
+    assert_packets!(xfer.rx(data(1, &block)) => []);
+    assert!(xfer.is_blocked());
+    // resent by the client before the write completes
+    assert_packets!(xfer.rx(data(1, &block)) => []);
+
+    budget.set(100);
+    assert_eq!(xfer.resume().next(), None);
+    budget.set(usize::MAX);
+    assert_packets!(Ok::<_, ()>(xfer.resume()) => [ResponseItem::Packet(Packet::ACK(1)),]);
+    assert!(!xfer.is_blocked());
+
+    assert_packets!(
+        xfer.rx(data(2, b"end")) => [
+            ResponseItem::Packet(Packet::ACK(2)),
+            ResponseItem::Done,
+        ]
+    );
+    let mut expected = block;
+    expected.extend_from_slice(b"end");
+    assert_eq!(files.borrow()["file"], expected);
+}
+
 #[test]
 fn rrq_windowsize_3_timeout_reset() {
     let (mut server, file, mut file_bytes) = rrq_fixture(512 * 3 + 123 /*4 blocks*/);
@@ -1210,6 +2761,125 @@ fn rrq_windowsize_3_timeout_reset() {
     assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(3));
 }
 
+#[test]
+fn rrq_windowsize_congestion_control() {
+    let content = (0..512 * 12 + 10).map(|i| i as u8).collect::<Vec<_>>();
+    let block = |n: u16| Packet::DATA {
+        block_num: n,
+        data: content.chunks(512).nth(n as usize - 1).unwrap().to_vec(),
+    };
+    let mut io = MemIO::default();
+    io.add("file", &content);
+    let mut server = TftpServerProto::with_transfer_cfg(
+        io,
+        Default::default(),
+        TransferCfg {
+            congestion_control: true,
+            ..Default::default()
+        },
+    );
+    let (xfer, res) = server.rx_initial(Packet::RRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![TftpOption::WindowSize(4)],
+    });
+    assert_matches!(res, Ok(Packet::OACK { .. }));
+    let mut xfer = xfer.unwrap();
+    assert_eq!(xfer.effective_window(), Some(1));
+
+    // the window starts small, growing with every completely acknowledged one
+    assert_packets!(xfer.rx(Packet::ACK(0)) => [ResponseItem::Packet(block(1)),]);
+    assert_packets!(
+        xfer.rx(Packet::ACK(1)) => [ResponseItem::Packet(block(2)), ResponseItem::Packet(block(3)),]
+    );
+    assert_eq!(xfer.effective_window(), Some(2));
+    assert_packets!(
+        xfer.rx(Packet::ACK(3)) => [
+            ResponseItem::Packet(block(4)),
+            ResponseItem::Packet(block(5)),
+            ResponseItem::Packet(block(6)),
+            ResponseItem::Packet(block(7)),
+        ]
+    );
+    // up to the negotiated size
+    assert_packets!(
+        xfer.rx(Packet::ACK(7)) => [
+            ResponseItem::Packet(block(8)),
+            ResponseItem::Packet(block(9)),
+            ResponseItem::Packet(block(10)),
+            ResponseItem::Packet(block(11)),
+        ]
+    );
+    assert_eq!(xfer.effective_window(), Some(4));
+
+    // loss halves it
+    assert_packets!(xfer.rx(Packet::ACK(9)) => [ResponseItem::RepeatLast(2),]);
+    assert_eq!(xfer.effective_window(), Some(2));
+    assert_packets!(
+        xfer.rx(Packet::ACK(11)) => [ResponseItem::Packet(block(12)), ResponseItem::Packet(block(13)),]
+    );
+    assert_eq!(xfer.effective_window(), Some(2));
+
+    // and so does a timeout, resending the blocks in flight
+    assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(2));
+    assert_eq!(xfer.effective_window(), Some(1));
+    assert_packets!(xfer.rx(Packet::ACK(13)) => [ResponseItem::Done,]);
+}
+
+#[test]
+fn rrq_congestion_control_ignores_stale_ack() {
+    let content = (0..512 * 8 + 10).map(|i| i as u8).collect::<Vec<_>>();
+    let block = |n: u16| Packet::DATA {
+        block_num: n,
+        data: content.chunks(512).nth(n as usize - 1).unwrap().to_vec(),
+    };
+    let mut io = MemIO::default();
+    io.add("file", &content);
+    let mut server = TftpServerProto::with_transfer_cfg(
+        io,
+        Default::default(),
+        TransferCfg {
+            congestion_control: true,
+            ..Default::default()
+        },
+    );
+    let (xfer, _) = server.rx_initial(Packet::RRQ {
+        filename: "file".into(),
+        mode: Octet,
+        options: vec![TftpOption::WindowSize(4)],
+    });
+    let mut xfer = xfer.unwrap();
+    assert_packets!(xfer.rx(Packet::ACK(0)) => [ResponseItem::Packet(block(1)),]);
+    assert_packets!(
+        xfer.rx(Packet::ACK(1)) => [ResponseItem::Packet(block(2)), ResponseItem::Packet(block(3)),]
+    );
+
+    // a late ACK of the first window, within the negotiated size but not in flight
+    assert_packets!(xfer.rx(Packet::ACK(0)) => []);
+    assert_eq!(xfer.effective_window(), Some(2));
+    assert_eq!(xfer.timeout_expired(), ResponseItem::RepeatLast(2));
+}
+
+#[test]
+fn rrq_windowsize_partial_ack_after_final() {
+    let (mut server, file, mut file_bytes) = rrq_fixture(512 + 123 /*2 blocks*/);
+    let (xfer, _) = server.rx_initial(Packet::RRQ {
+        filename: file,
+        mode: Octet,
+        options: vec![TftpOption::WindowSize(4)],
+    });
+    let mut xfer = xfer.unwrap();
+    assert_packets!(
+        xfer.rx(Packet::ACK(0)) => [
+            ResponseItem::Packet(Packet::DATA { block_num: 1, data: file_bytes.gen(512), }),
+            ResponseItem::Packet(Packet::DATA { block_num: 2, data: file_bytes.gen(123), }),
+        ]
+    );
+    // the final block is resent, but nothing follows it
+    assert_packets!(xfer.rx(Packet::ACK(1)) => [ResponseItem::RepeatLast(1),]);
+    assert_packets!(xfer.rx(Packet::ACK(2)) => [ResponseItem::Done,]);
+}
+
 #[test]
 fn wrq_windowsize_2_ok() {
     let (mut server, file, mut file_bytes) = wrq_fixture(512 * 3 + 123);
@@ -1467,6 +3137,7 @@ impl Write for ExpectingWriter {
         Ok(())
     }
 }
+impl Commit for ExpectingWriter {}
 impl Drop for ExpectingWriter {
     fn drop(&mut self) {
         if self.enforce_full_write && !::std::thread::panicking() {
@@ -0,0 +1,224 @@
+use assert_matches::*;
+
+use std::fs::{self, File};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tftp_server::async_server::{AsyncTftpServer, AsyncTftpServerImpl};
+use tftp_server::client::{ClientConfig, TftpClient};
+use tftp_server::clock::ManualClock;
+use tftp_server::packet::{ErrorCode, Packet, TransferMode, MAX_PACKET_SIZE};
+use tftp_server::server::ServerConfig;
+use tftp_server::tftp_proto::FSAdapter;
+use tokio::net::UdpSocket;
+use tokio::task::{self, JoinHandle};
+use tokio::time::timeout;
+
+/// Spawns a server listening on a random port, returning its address and the task running it
+fn spawn_server() -> (SocketAddr, JoinHandle<()>) {
+    let cfg = ServerConfig {
+        addrs: vec![(IpAddr::from([127, 0, 0, 1]), None)],
+        ..Default::default()
+    };
+    let mut server = AsyncTftpServer::with_cfg(&cfg).unwrap();
+    let mut addrs = vec![];
+    server.get_local_addrs(&mut addrs).unwrap();
+    let task = tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+    (addrs[0], task)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_transfers() {
+    let (server_addr, server) = spawn_server();
+    let configs = vec![
+        ClientConfig::default(),
+        ClientConfig {
+            blocksize: Some(1400),
+            window_size: Some(8),
+            transfer_size: true,
+            ..Default::default()
+        },
+    ];
+
+    // the client blocks, so it runs apart from the runtime driving the server
+    task::spawn_blocking(move || {
+        for cfg in configs {
+            let client = TftpClient::with_cfg(server_addr, cfg);
+
+            let mut v = vec![];
+            client.get("./files/hello.txt", &mut v).unwrap();
+            assert_eq!(v, fs::read("./files/hello.txt").unwrap());
+
+            let _ = fs::remove_file("./async_put.txt");
+            let file = File::open("./files/hello.txt").unwrap();
+            let size = file.metadata().unwrap().len();
+            client.put("async_put.txt", file, Some(size)).unwrap();
+            assert_eq!(
+                fs::read("./async_put.txt").unwrap(),
+                fs::read("./files/hello.txt").unwrap()
+            );
+            assert!(fs::remove_file("./async_put.txt").is_ok());
+        }
+    })
+    .await
+    .unwrap();
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn unknown_tid_error_goes_to_source() {
+    let (server_addr, server) = spawn_server();
+    let bind = || UdpSocket::bind((IpAddr::from([127, 0, 0, 1]), 0));
+    let (socket, stray) = (bind().await.unwrap(), bind().await.unwrap());
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: TransferMode::Octet,
+        options: vec![],
+    };
+    socket
+        .send_to(&init_packet.into_bytes().unwrap(), server_addr)
+        .await
+        .unwrap();
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let wait = Duration::from_secs(3);
+    let (_, remote) = timeout(wait, socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let ack = Packet::ACK(1).into_bytes().unwrap();
+    stray.send_to(&ack, remote).await.unwrap();
+    let amt = timeout(wait, stray.recv(&mut buf)).await.unwrap().unwrap();
+    assert_matches!(
+        Packet::read(&buf[..amt]).unwrap(),
+        Packet::ERROR {
+            code: ErrorCode::UnknownID,
+            ..
+        }
+    );
+
+    // the client is not told, and its transfer goes on
+    socket.send_to(&ack, remote).await.unwrap();
+    let amt = timeout(wait, socket.recv(&mut buf)).await.unwrap().unwrap();
+    assert_matches!(
+        Packet::read(&buf[..amt]).unwrap(),
+        Packet::DATA { block_num: 2, .. }
+    );
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn cancel_aborts_transfers() {
+    let (server_addr, server) = spawn_server();
+    let socket = UdpSocket::bind((IpAddr::from([127, 0, 0, 1]), 0))
+        .await
+        .unwrap();
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: TransferMode::Octet,
+        options: vec![],
+    }
+    .into_bytes()
+    .unwrap();
+    socket.send_to(&init_packet, server_addr).await.unwrap();
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let wait = Duration::from_secs(3);
+    let amt = timeout(wait, socket.recv(&mut buf)).await.unwrap().unwrap();
+    assert_matches!(
+        Packet::read(&buf[..amt]).unwrap(),
+        Packet::DATA { block_num: 1, .. }
+    );
+
+    server.abort();
+    assert!(server.await.unwrap_err().is_cancelled());
+
+    // the aborted transfer is notified
+    let amt = timeout(wait, socket.recv(&mut buf)).await.unwrap().unwrap();
+    assert_matches!(Packet::read(&buf[..amt]).unwrap(), Packet::ERROR { .. });
+
+    // and the server no longer answers
+    socket.send_to(&init_packet, server_addr).await.unwrap();
+    assert!(timeout(Duration::from_millis(200), socket.recv(&mut buf))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn manual_clock_timeouts() {
+    let cfg = ServerConfig {
+        addrs: vec![(IpAddr::from([127, 0, 0, 1]), None)],
+        ..Default::default()
+    };
+    let clock = ManualClock::new();
+    let mut server = AsyncTftpServerImpl::<FSAdapter, _>::with_clock(&cfg, clock.clone()).unwrap();
+    let mut addrs = vec![];
+    server.get_local_addrs(&mut addrs).unwrap();
+    let server = tokio::spawn(async move {
+        server.run().await.unwrap();
+    });
+
+    let socket = UdpSocket::bind((IpAddr::from([127, 0, 0, 1]), 0))
+        .await
+        .unwrap();
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: TransferMode::Octet,
+        options: vec![],
+    };
+    socket
+        .send_to(&init_packet.into_bytes().unwrap(), addrs[0])
+        .await
+        .unwrap();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let wait = Duration::from_secs(3);
+    let (amt, remote) = timeout(wait, socket.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    let block_1 = Packet::read(&buf[..amt]).unwrap();
+    assert_matches!(block_1, Packet::DATA { block_num: 1, .. });
+
+    // nothing is resent before the timeout, however long it really takes
+    clock.advance(cfg.timeout - Duration::from_millis(1));
+    assert!(
+        timeout(Duration::from_millis(200), socket.recv(&mut buf))
+            .await
+            .is_err(),
+        "resent too early"
+    );
+    // nor does it start over for packets from anyone but the client
+    let stray = UdpSocket::bind((IpAddr::from([127, 0, 0, 1]), 0))
+        .await
+        .unwrap();
+    let ack = Packet::ACK(1).into_bytes().unwrap();
+    stray.send_to(&ack, remote).await.unwrap();
+    let amt = timeout(wait, stray.recv(&mut buf)).await.unwrap().unwrap();
+    assert_matches!(
+        Packet::read(&buf[..amt]).unwrap(),
+        Packet::ERROR {
+            code: ErrorCode::UnknownID,
+            ..
+        }
+    );
+    clock.advance(Duration::from_millis(1));
+    let amt = timeout(wait, socket.recv(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(Packet::read(&buf[..amt]).unwrap(), block_1);
+
+    // once the retries run out, the connection is closed
+    clock.advance(cfg.timeout);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let _ = socket.send_to(&ack, remote).await;
+    assert!(
+        !matches!(
+            timeout(Duration::from_millis(200), socket.recv(&mut buf)).await,
+            Ok(Ok(_))
+        ),
+        "packet received after connection should have dropped"
+    );
+
+    server.abort();
+}
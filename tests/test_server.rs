@@ -1,13 +1,24 @@
 use assert_matches::*;
 
 use std::borrow::BorrowMut;
+use std::cell::Cell;
+use std::collections::HashSet;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::thread;
-use std::time::Duration;
-use tftp_server::packet::{ErrorCode, Packet, TftpOption, MAX_PACKET_SIZE};
-use tftp_server::server::{Result, ServerConfig, TftpServer};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+use tftp_server::client::{self, ClientConfig, TftpClient};
+use tftp_server::clock::{Clock, ManualClock};
+use tftp_server::offload::OffloadAdapter;
+use tftp_server::packet::{ErrorCode, Packet, TftpOption, TransferMode, MAX_PACKET_SIZE};
+use tftp_server::server::{
+    OffloadTftpServer, Result, ServerConfig, ServerHandle, TftpError, TftpServer, TftpServerImpl,
+};
+use tftp_server::tftp_proto::{AtomicFile, Commit, FSAdapter, IOAdapter, TransferObserver};
 
 use tftp_server::packet::TransferMode::*;
 
@@ -16,8 +27,10 @@ use crate::misc_utils::*;
 
 /// Starts the server in a new thread.
 pub fn start_server() -> Result<Vec<SocketAddr>> {
-    let mut cfg: ServerConfig = Default::default();
-    cfg.addrs = vec![];
+    let mut cfg = ServerConfig {
+        addrs: vec![],
+        ..Default::default()
+    };
     assert!(
         TftpServer::with_cfg(&cfg).is_err(),
         "server creation succeeded without addresses"
@@ -40,6 +53,28 @@ pub fn start_server() -> Result<Vec<SocketAddr>> {
     Ok(addrs)
 }
 
+/// Starts a separate server in a new thread, returning its address,
+/// a handle to stop it, and the thread running it
+fn start_stoppable_server() -> (SocketAddr, ServerHandle, thread::JoinHandle<Result<()>>) {
+    start_server_with(ServerConfig::default())
+}
+
+/// Like `start_stoppable_server`, using the given config but listening on a random port
+fn start_server_with(
+    cfg: ServerConfig,
+) -> (SocketAddr, ServerHandle, thread::JoinHandle<Result<()>>) {
+    let cfg = ServerConfig {
+        addrs: vec![(IpAddr::from([127, 0, 0, 1]), None)],
+        ..cfg
+    };
+    let mut server = TftpServer::with_cfg(&cfg).unwrap();
+    let mut addrs = vec![];
+    server.get_local_addrs(&mut addrs).unwrap();
+    let handle = server.handle();
+    let thread = thread::spawn(move || server.run_workers());
+    (addrs[0], handle, thread)
+}
+
 pub fn assert_files_identical(fa: &str, fb: &str) {
     assert!(fs::metadata(fa).is_ok());
     assert!(fs::metadata(fb).is_ok());
@@ -54,36 +89,73 @@ pub fn assert_files_identical(fa: &str, fb: &str) {
     assert_eq!(buf1, buf2);
 }
 
-fn timeout_test(server_addr: &SocketAddr) -> Result<()> {
-    let socket = create_socket(None)?;
+fn timeout_test() {
+    let (server_addr, clock, handle, thread) = start_manual_clock_server();
+    let timeout = ServerConfig::default().timeout;
+    let socket = create_socket(Some(Duration::from_secs(3))).unwrap();
     let init_packet = Packet::WRQ {
-        filename: "hello.txt".into(),
+        filename: "timeout.txt".into(),
         mode: Octet,
         options: vec![],
     };
-    socket.send_to(init_packet.into_bytes()?.as_slice(), server_addr)?;
+    socket
+        .send_to(&init_packet.into_bytes().unwrap(), server_addr)
+        .unwrap();
 
     let mut buf = [0; MAX_PACKET_SIZE];
-    let amt = socket.recv(&mut buf)?;
-    let reply_packet = Packet::read(&buf[0..amt])?;
-    assert_eq!(reply_packet, Packet::ACK(0));
+    let amt = socket.recv(&mut buf).unwrap();
+    assert_eq!(Packet::read(&buf[..amt]).unwrap(), Packet::ACK(0));
+    sync_with_server(&server_addr);
 
-    let deadman = DeadmanThread::start(Duration::from_millis(3500), "timeout failed");
-    let amt = socket.recv(&mut buf)?;
-    let reply_packet = Packet::read(&buf[0..amt])?;
-    assert_eq!(reply_packet, Packet::ACK(0));
-    drop(deadman);
+    clock.advance(timeout);
+    let amt = socket.recv(&mut buf).unwrap();
+    assert_eq!(Packet::read(&buf[..amt]).unwrap(), Packet::ACK(0));
 
-    socket
-        .set_read_timeout(Some(Duration::from_millis(3200)))
-        .unwrap();
+    clock.advance(timeout);
+    sync_with_server(&server_addr);
+    socket.set_nonblocking(true).unwrap();
     assert_matches!(
-        socket.recv_from(&mut buf), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock,
+        socket.recv(&mut buf), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock,
         "packet received after connection should have dropped"
     );
 
-    assert!(fs::metadata("./hello.txt").is_ok());
-    assert!(fs::remove_file("./hello.txt").is_ok());
+    // the aborted upload was removed
+    assert!(fs::metadata("./timeout.txt").is_err());
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+}
+
+fn adaptive_timeout_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(5)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: Octet,
+        options: vec![],
+    };
+    socket.send_to(&init_packet.into_bytes()?, server_addr)?;
+
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let (amt, src) = socket.recv_from(&mut buf)?;
+    assert_matches!(
+        Packet::read(&buf[..amt])?,
+        Packet::DATA { block_num: 1, .. }
+    );
+    // a quick reply gives the server a short round-trip time estimate
+    socket.send_to(&Packet::ACK(1).into_bytes()?, src)?;
+    let amt = socket.recv(&mut buf)?;
+    let block_2 = Packet::read(&buf[..amt])?;
+    assert_matches!(block_2, Packet::DATA { block_num: 2, .. });
+
+    // so a lost ACK is followed by a retransmission well before the default 3s timeout
+    let start = Instant::now();
+    let amt = socket.recv(&mut buf)?;
+    assert_eq!(Packet::read(&buf[..amt])?, block_2);
+    assert!(
+        start.elapsed() < Duration::from_secs(1),
+        "retransmitted too late"
+    );
+
+    socket.send_to(&Packet::from(ErrorCode::NotDefined).into_bytes()?, src)?;
     Ok(())
 }
 
@@ -121,7 +193,7 @@ impl WritingTransfer {
             options,
         };
         xfer.socket
-            .send_to(init_packet.to_bytes().unwrap().as_slice(), &server_addr)
+            .send_to(init_packet.to_bytes().unwrap().as_slice(), server_addr)
             .unwrap_or_else(|_| {
                 panic!(
                     "cannot send initial packet {:?} to {:?}",
@@ -133,12 +205,16 @@ impl WritingTransfer {
 
     fn step(&mut self, rx_buf: &mut [u8]) -> Option<()> {
         let (amt, src) = self.socket.recv_from(rx_buf).expect("cannot receive");
-        if self.remote.is_some() {
-            assert_eq!(self.remote.unwrap(), src, "transfer source changed");
+        if let Some(remote) = self.remote {
+            assert_eq!(remote, src, "transfer source changed");
         } else {
             self.remote = Some(src);
         }
         let received = Packet::read(&rx_buf[0..amt]).unwrap();
+        if self.block_num > 0 && received == Packet::ACK(self.block_num - 1) {
+            // early retransmission of an ACK that was already answered
+            return Some(());
+        }
         if let Packet::OACK { .. } = received {
             assert_eq!(self.block_num, 0);
         } else {
@@ -162,7 +238,7 @@ impl WritingTransfer {
         };
 
         self.socket
-            .send_to(data_packet.to_bytes().unwrap().as_slice(), &src)
+            .send_to(data_packet.to_bytes().unwrap().as_slice(), src)
             .unwrap_or_else(|_| panic!("cannot send packet {:?} to {:?}", data_packet, src));
         Some(())
     }
@@ -175,10 +251,10 @@ fn wrq_whole_file_test(server_addr: &SocketAddr, options: Vec<TftpOption>) -> Re
     let mut scratch_buf = [0; MAX_PACKET_SIZE];
 
     let mut tx = WritingTransfer::start("./files/hello.txt", server_addr, "hello.txt", options);
-    while let Some(_) = tx.step(&mut scratch_buf) {}
+    while tx.step(&mut scratch_buf).is_some() {}
 
     // Would cause server to have an error if not handled robustly
-    tx.socket.send_to(&[1, 2, 3], &tx.remote.unwrap())?;
+    tx.socket.send_to(&[1, 2, 3], tx.remote.unwrap())?;
 
     assert_files_identical("./hello.txt", "./files/hello.txt");
     assert!(fs::remove_file("./hello.txt").is_ok());
@@ -220,7 +296,7 @@ impl ReadingTransfer {
             options,
         };
         xfer.socket
-            .send_to(init_packet.to_bytes().unwrap().as_slice(), &server_addr)
+            .send_to(init_packet.to_bytes().unwrap().as_slice(), server_addr)
             .unwrap_or_else(|_| {
                 panic!(
                     "cannot send initial packet {:?} to {:?}",
@@ -232,8 +308,8 @@ impl ReadingTransfer {
 
     fn step(&mut self, rx_buf: &mut [u8]) -> Option<()> {
         let (amt, src) = self.socket.recv_from(rx_buf).expect("cannot receive");
-        if self.remote.is_some() {
-            assert_eq!(self.remote.unwrap(), src, "transfer source changed");
+        if let Some(remote) = self.remote {
+            assert_eq!(remote, src, "transfer source changed");
         } else {
             self.remote = Some(src);
         }
@@ -244,9 +320,12 @@ impl ReadingTransfer {
                 assert_eq!(self.block_num, 1);
                 let ack_packet = Packet::ACK(0);
                 self.socket
-                    .send_to(ack_packet.to_bytes().unwrap().as_slice(), &src)
+                    .send_to(ack_packet.to_bytes().unwrap().as_slice(), src)
                     .unwrap_or_else(|_| panic!("cannot send packet {:?} to {:?}", ack_packet, src));
             }
+            Packet::DATA { block_num, .. } if block_num == self.block_num.wrapping_sub(1) => {
+                // early retransmission of a block that was already acknowledged
+            }
             Packet::DATA { block_num, data } => {
                 assert_eq!(self.block_num, block_num);
                 self.file
@@ -255,7 +334,7 @@ impl ReadingTransfer {
 
                 let ack_packet = Packet::ACK(self.block_num);
                 self.socket
-                    .send_to(ack_packet.to_bytes().unwrap().as_slice(), &src)
+                    .send_to(ack_packet.to_bytes().unwrap().as_slice(), src)
                     .unwrap_or_else(|_| panic!("cannot send packet {:?} to {:?}", ack_packet, src));
 
                 self.block_num = self.block_num.wrapping_add(1);
@@ -276,10 +355,10 @@ fn rrq_whole_file_test(server_addr: &SocketAddr, options: Vec<TftpOption>) -> Re
     let mut scratch_buf = [0; MAX_PACKET_SIZE];
 
     let mut rx = ReadingTransfer::start("./hello.txt", server_addr, "./files/hello.txt", options);
-    while let Some(_) = rx.step(&mut scratch_buf) {}
+    while rx.step(&mut scratch_buf).is_some() {}
 
     // Would cause server to have an error if not handled robustly
-    rx.socket.send_to(&[1, 2, 3], &rx.remote.unwrap())?;
+    rx.socket.send_to(&[1, 2, 3], rx.remote.unwrap())?;
 
     assert_files_identical("./hello.txt", "./files/hello.txt");
     assert!(fs::remove_file("./hello.txt").is_ok());
@@ -298,7 +377,13 @@ fn wrq_file_exists_test(server_addr: &SocketAddr) -> Result<()> {
     let mut buf = [0; MAX_PACKET_SIZE];
     let amt = socket.recv(&mut buf)?;
     let packet = Packet::read(&buf[0..amt])?;
-    assert_matches!(packet, Packet::ERROR { code: ErrorCode::FileExists , .. });
+    assert_matches!(
+        packet,
+        Packet::ERROR {
+            code: ErrorCode::FileExists,
+            ..
+        }
+    );
     Ok(())
 }
 
@@ -314,10 +399,75 @@ fn rrq_file_not_found_test(server_addr: &SocketAddr) -> Result<()> {
     let mut buf = [0; MAX_PACKET_SIZE];
     let amt = socket.recv(&mut buf)?;
     let packet = Packet::read(&buf[0..amt])?;
-    assert_matches!(packet, Packet::ERROR { code: ErrorCode::FileNotFound , .. });
+    assert_matches!(
+        packet,
+        Packet::ERROR {
+            code: ErrorCode::FileNotFound,
+            ..
+        }
+    );
     Ok(())
 }
 
+fn long_request_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(3)))?;
+    // longer than the 512 bytes allowed, by options for one
+    let init_packet = Packet::RRQ {
+        filename: "./".repeat(250) + "files/hello.txt",
+        mode: Octet,
+        options: vec![TftpOption::Blocksize(1024), TftpOption::TransferSize(0)],
+    };
+    socket.send_to(init_packet.into_bytes()?.as_slice(), server_addr)?;
+
+    // is refused rather than ignored
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf)?;
+    let packet = Packet::read(&buf[0..amt])?;
+    assert_matches!(
+        packet,
+        Packet::ERROR {
+            code: ErrorCode::IllegalTFTP,
+            ..
+        }
+    );
+    Ok(())
+}
+
+fn unknown_tid_test(server_addr: &SocketAddr) -> Result<()> {
+    let socket = create_socket(Some(Duration::from_secs(3)))?;
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: Octet,
+        options: vec![],
+    };
+    socket.send_to(init_packet.into_bytes()?.as_slice(), server_addr)?;
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let (_, remote) = socket.recv_from(&mut buf)?;
+
+    // the error goes to the stray source, and the transfer goes on
+    let stray = create_socket(Some(Duration::from_secs(3)))?;
+    stray.send_to(Packet::ACK(1).into_bytes()?.as_slice(), remote)?;
+    let amt = stray.recv(&mut buf)?;
+    assert_matches!(
+        Packet::read(&buf[..amt])?,
+        Packet::ERROR {
+            code: ErrorCode::UnknownID,
+            ..
+        }
+    );
+
+    socket.send_to(Packet::ACK(1).into_bytes()?.as_slice(), remote)?;
+    loop {
+        let amt = socket.recv(&mut buf)?;
+        match Packet::read(&buf[..amt])? {
+            Packet::DATA { block_num: 2, .. } => return Ok(()),
+            // a retransmission
+            Packet::DATA { block_num: 1, .. } => {}
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+}
+
 fn interleaved_read_read_same_file(server_addr: &SocketAddr) {
     let mut scratch_buf = [0; MAX_PACKET_SIZE];
 
@@ -329,7 +479,7 @@ fn interleaved_read_read_same_file(server_addr: &SocketAddr) {
         let res_a = read_a.step(&mut scratch_buf);
         let res_b = read_b.step(&mut scratch_buf);
         assert_eq!(res_a, res_b, "reads finished in different number of steps");
-        if res_a == None {
+        if res_a.is_none() {
             break;
         }
     }
@@ -340,6 +490,737 @@ fn interleaved_read_read_same_file(server_addr: &SocketAddr) {
     assert!(fs::remove_file("./read_b.txt").is_ok());
 }
 
+fn client_get_test(server_addr: &SocketAddr, cfg: ClientConfig) {
+    let client = TftpClient::with_cfg(*server_addr, cfg);
+    let mut v = vec![];
+    let amt = client.get("./files/hello.txt", &mut v).unwrap();
+    assert_eq!(v, fs::read("./files/hello.txt").unwrap());
+    assert!(amt >= v.len() as u64);
+}
+
+fn client_put_test(server_addr: &SocketAddr, cfg: ClientConfig) {
+    let _ = fs::remove_file("./client_put.txt");
+    let client = TftpClient::with_cfg(*server_addr, cfg);
+    let file = File::open("./files/hello.txt").unwrap();
+    let size = file.metadata().unwrap().len();
+    let amt = client.put("client_put.txt", file, Some(size)).unwrap();
+    assert!(amt >= size);
+    assert_files_identical("./client_put.txt", "./files/hello.txt");
+    assert!(fs::remove_file("./client_put.txt").is_ok());
+}
+
+fn client_errors_test(server_addr: &SocketAddr) {
+    let client = TftpClient::new(*server_addr);
+    assert_matches!(
+        client.get("./no_such_file.txt", io::sink()),
+        Err(client::TftpError::Remote {
+            code: ErrorCode::FileNotFound,
+            ..
+        })
+    );
+    assert_matches!(
+        client.put("./files/hello.txt", io::empty(), None),
+        Err(client::TftpError::Remote {
+            code: ErrorCode::FileExists,
+            ..
+        })
+    );
+
+    // nobody listening
+    let unused = create_socket(None).unwrap();
+    let client = TftpClient::with_cfg(
+        unused.local_addr().unwrap(),
+        ClientConfig {
+            timeout: Duration::from_millis(100),
+            retries: 2,
+            ..Default::default()
+        },
+    );
+    assert_matches!(
+        client.get("./files/hello.txt", io::sink()),
+        Err(client::TftpError::Timeout)
+    );
+}
+
+fn client_test(server_addr: &SocketAddr) {
+    let configs = vec![
+        ClientConfig::default(),
+        ClientConfig {
+            blocksize: Some(1400),
+            window_size: Some(8),
+            timeout_secs: Some(2),
+            transfer_size: true,
+            ..Default::default()
+        },
+        ClientConfig {
+            mode: TransferMode::Netascii,
+            blocksize: Some(700),
+            window_size: Some(3),
+            transfer_size: true,
+            ..Default::default()
+        },
+    ];
+    for cfg in configs {
+        client_get_test(server_addr, cfg.clone());
+        client_put_test(server_addr, cfg);
+    }
+    client_errors_test(server_addr);
+}
+
+fn congestion_control_test() {
+    let (server_addr, handle, thread) = start_server_with(ServerConfig {
+        congestion_control: true,
+        ..Default::default()
+    });
+    let content = (0..512 * 3 + 100).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write("./congestion.txt", &content).unwrap();
+
+    // the window grows from 1 block, with the client acknowledging incomplete ones on timeout
+    let client = TftpClient::with_cfg(
+        server_addr,
+        ClientConfig {
+            window_size: Some(4),
+            timeout_secs: Some(1),
+            ..Default::default()
+        },
+    );
+    let mut v = vec![];
+    client.get("./congestion.txt", &mut v).unwrap();
+    assert_eq!(v, content);
+
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+    assert!(fs::remove_file("./congestion.txt").is_ok());
+}
+
+fn max_blocksize_test() {
+    let (server_addr, handle, thread) = start_server_with(ServerConfig {
+        max_blocksize: Some(1000),
+        ..Default::default()
+    });
+    let socket = create_socket(Some(Duration::from_secs(3))).unwrap();
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: Octet,
+        options: vec![TftpOption::Blocksize(1400)],
+    };
+    socket
+        .send_to(&init_packet.into_bytes().unwrap(), server_addr)
+        .unwrap();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf).unwrap();
+    assert_eq!(
+        Packet::read(&buf[..amt]).unwrap(),
+        Packet::OACK {
+            options: vec![TftpOption::Blocksize(1000)]
+        }
+    );
+
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+}
+
+fn offload_test() {
+    let cfg = ServerConfig {
+        addrs: vec![(IpAddr::from([127, 0, 0, 1]), None)],
+        ..Default::default()
+    };
+    let mut server = OffloadTftpServer::with_cfg(&cfg).unwrap();
+    let mut addrs = vec![];
+    server.get_local_addrs(&mut addrs).unwrap();
+    let handle = server.handle();
+    let thread = thread::spawn(move || server.run());
+
+    let netascii = ClientConfig {
+        mode: TransferMode::Netascii,
+        window_size: Some(4),
+        ..Default::default()
+    };
+    for cfg in [ClientConfig::default(), netascii] {
+        client_get_test(&addrs[0], cfg.clone());
+        client_put_test(&addrs[0], cfg);
+    }
+
+    // large enough for reads to go through the worker threads
+    let contents = (0..300_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write("./offload.bin", &contents).unwrap();
+    let client = TftpClient::with_cfg(
+        addrs[0],
+        ClientConfig {
+            blocksize: Some(1024),
+            window_size: Some(8),
+            ..Default::default()
+        },
+    );
+    let mut v = vec![];
+    client.get("./offload.bin", &mut v).unwrap();
+    assert!(v == contents, "offloaded read differs");
+
+    // the netascii size takes another pass over the file, done by the workers as well
+    let client = TftpClient::with_cfg(
+        addrs[0],
+        ClientConfig {
+            mode: TransferMode::Netascii,
+            transfer_size: true,
+            ..Default::default()
+        },
+    );
+    let last = Cell::new((0, None));
+    client
+        .get_with_progress("./offload.bin", io::sink(), |bytes, total| {
+            last.set((bytes, total))
+        })
+        .unwrap();
+    let (bytes, total) = last.get();
+    assert_eq!(total, Some(bytes));
+    assert!(fs::remove_file("./offload.bin").is_ok());
+
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+}
+
+/// Holds up the commits of uploads while locked
+static COMMIT_GATE: Mutex<()> = Mutex::new(());
+/// Set once an upload waits for `COMMIT_GATE`
+static COMMITTING: AtomicBool = AtomicBool::new(false);
+
+/// Uses the filesystem, committing uploads only once `COMMIT_GATE` is free
+#[derive(Default)]
+struct GatedIO;
+
+impl IOAdapter for GatedIO {
+    type R = File;
+    type W = GatedFile;
+    fn open_read(&self, file: &Path) -> io::Result<(File, Option<u64>)> {
+        FSAdapter.open_read(file)
+    }
+    fn create_new(&mut self, file: &Path, len: Option<u64>) -> io::Result<GatedFile> {
+        FSAdapter.create_new(file, len).map(GatedFile)
+    }
+}
+
+struct GatedFile(AtomicFile);
+
+impl Write for GatedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Commit for GatedFile {
+    fn commit(&mut self) -> io::Result<()> {
+        COMMITTING.store(true, Ordering::SeqCst);
+        let _gate = COMMIT_GATE.lock().unwrap();
+        self.0.commit()
+    }
+}
+
+fn offload_commit_test() {
+    let cfg = ServerConfig {
+        addrs: vec![(IpAddr::from([127, 0, 0, 1]), None)],
+        ..Default::default()
+    };
+    let mut server = TftpServerImpl::<OffloadAdapter<GatedIO>>::with_cfg(&cfg).unwrap();
+    let mut addrs = vec![];
+    server.get_local_addrs(&mut addrs).unwrap();
+    let server_addr = addrs[0];
+    let handle = server.handle();
+    let thread = thread::spawn(move || server.run());
+
+    let gate = COMMIT_GATE.lock().unwrap();
+    let _ = fs::remove_file("./offload_commit.txt");
+    let upload = thread::spawn(move || {
+        TftpClient::new(server_addr).put("./offload_commit.txt", &b"committed"[..], None)
+    });
+    let start = Instant::now();
+    while !COMMITTING.load(Ordering::SeqCst) {
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "upload not committed"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // other transfers go on while the upload is committed
+    let mut v = vec![];
+    TftpClient::new(server_addr)
+        .get("./files/hello.txt", &mut v)
+        .unwrap();
+    assert_eq!(v, fs::read("./files/hello.txt").unwrap());
+    drop(gate);
+    assert_matches!(upload.join(), Ok(Ok(9)));
+    assert_eq!(fs::read("./offload_commit.txt").unwrap(), b"committed");
+    assert!(fs::remove_file("./offload_commit.txt").is_ok());
+
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+}
+
+fn wildcard_reply_address_test() {
+    let cfg = ServerConfig {
+        addrs: vec![(IpAddr::from([0; 4]), None), (IpAddr::from([0; 16]), None)],
+        ..Default::default()
+    };
+    let mut server = TftpServer::with_cfg(&cfg).unwrap();
+    let mut addrs = vec![];
+    server.get_local_addrs(&mut addrs).unwrap();
+    let handle = server.handle();
+    let thread = thread::spawn(move || server.run());
+
+    // the loopback interface accepts any 127.x.x.x address, not only the usual one
+    let contacted = IpAddr::from([127, 0, 0, 2]);
+    for addr in &addrs {
+        let socket = create_socket(Some(Duration::from_secs(3))).unwrap();
+        let init_packet = Packet::RRQ {
+            filename: "./files/hello.txt".into(),
+            mode: Octet,
+            options: vec![],
+        };
+        socket
+            .send_to(&init_packet.into_bytes().unwrap(), (contacted, addr.port()))
+            .unwrap();
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let (_, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(src.ip(), contacted, "reply via {} from wrong address", addr);
+    }
+
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+}
+
+/// Like `start_stoppable_server`, with timeouts following the returned clock
+fn start_manual_clock_server() -> (
+    SocketAddr,
+    ManualClock,
+    ServerHandle,
+    thread::JoinHandle<Result<()>>,
+) {
+    let cfg = ServerConfig {
+        addrs: vec![(IpAddr::from([127, 0, 0, 1]), None)],
+        ..Default::default()
+    };
+    let clock = ManualClock::new();
+    let mut server = TftpServerImpl::<FSAdapter, _>::with_clock(&cfg, clock.clone()).unwrap();
+    let mut addrs = vec![];
+    server.get_local_addrs(&mut addrs).unwrap();
+    let handle = server.handle();
+    let thread = thread::spawn(move || server.run());
+    (addrs[0], clock, handle, thread)
+}
+
+/// Waits until the server handled everything sent to it so far,
+/// since it answers this request only afterwards
+fn sync_with_server(server_addr: &SocketAddr) {
+    let socket = create_socket(Some(Duration::from_secs(3))).unwrap();
+    let init_packet = Packet::RRQ {
+        filename: "./files/missing.txt".into(),
+        mode: Octet,
+        options: vec![],
+    };
+    socket
+        .send_to(&init_packet.into_bytes().unwrap(), server_addr)
+        .unwrap();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let amt = socket.recv(&mut buf).unwrap();
+    assert_matches!(Packet::read(&buf[..amt]).unwrap(), Packet::ERROR { .. });
+}
+
+/// Records the thread that completed each transfer
+#[derive(Default)]
+struct ThreadObserver {
+    threads: Mutex<Vec<ThreadId>>,
+}
+
+impl TransferObserver for ThreadObserver {
+    fn on_complete(&self, _: &Path, _: bool, _: u64, _: Duration) {
+        self.threads.lock().unwrap().push(thread::current().id());
+    }
+}
+
+fn workers_test() {
+    let cfg = ServerConfig {
+        workers: 4,
+        ..Default::default()
+    };
+    // the workers can't be run on the calling thread alone
+    let mut server = TftpServer::with_cfg(&cfg).unwrap();
+    assert_matches!(server.run(), Err(TftpError::IoError(_)));
+    // nor share multicast sessions
+    let multicast = ServerConfig {
+        workers: 4,
+        multicast: vec!["239.255.0.1:1758".parse().unwrap()],
+        ..Default::default()
+    };
+    assert!(
+        TftpServer::with_cfg(&multicast).is_err(),
+        "multicast server creation succeeded with several workers"
+    );
+
+    let observer = Arc::new(ThreadObserver::default());
+    let (server_addr, handle, thread) = start_server_with(ServerConfig {
+        observer: Some(observer.clone()),
+        ..cfg
+    });
+
+    // enough clients for the requests to be spread among the workers
+    let clients = (0..16)
+        .map(|_| {
+            thread::spawn(move || {
+                let client = TftpClient::new(server_addr);
+                let mut v = vec![];
+                client.get("./files/hello.txt", &mut v).unwrap();
+                v
+            })
+        })
+        .collect::<Vec<_>>();
+    let contents = fs::read("./files/hello.txt").unwrap();
+    for client in clients {
+        assert!(client.join().unwrap() == contents, "read differs");
+    }
+    // the last ACKs may still be on their way
+    let start = Instant::now();
+    while observer.threads.lock().unwrap().len() < 16 {
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "transfers not completed"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    let threads = observer.threads.lock().unwrap();
+    assert!(
+        threads.iter().collect::<HashSet<_>>().len() > 1,
+        "all transfers were served by the same worker"
+    );
+
+    // the handle stops every worker
+    let deadman = DeadmanThread::start(Duration::from_secs(2), "stopping workers failed");
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+    drop(deadman);
+}
+
+/// A client of a multicast read, receiving the group's data on a separate socket
+struct MulticastClient {
+    socket: UdpSocket,
+    group_socket: Option<UdpSocket>,
+    /// The session's socket, which sends the OACKs
+    session: Option<SocketAddr>,
+    blocks: Vec<Option<Vec<u8>>>,
+}
+
+impl MulticastClient {
+    fn request(server_addr: &SocketAddr, file: &str) -> Self {
+        let socket = create_socket(Some(Duration::from_secs(TIMEOUT))).unwrap();
+        let init_packet = Packet::RRQ {
+            filename: file.into(),
+            mode: Octet,
+            options: vec![TftpOption::Multicast(None)],
+        };
+        socket
+            .send_to(&init_packet.into_bytes().unwrap(), server_addr)
+            .unwrap();
+        MulticastClient {
+            socket,
+            group_socket: None,
+            session: None,
+            blocks: vec![],
+        }
+    }
+
+    /// Receives an OACK, joining the group, and returns whether it makes this client master
+    fn oack(&mut self) -> bool {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let (amt, src) = self.socket.recv_from(&mut buf).unwrap();
+        let group = match Packet::read(&buf[..amt]).unwrap() {
+            Packet::OACK { options } => match options[..] {
+                [TftpOption::Multicast(Some(group))] => group,
+                _ => panic!("unexpected options {:?}", options),
+            },
+            packet => panic!("unexpected packet {:?}", packet),
+        };
+        self.session = Some(src);
+        if self.group_socket.is_none() {
+            self.group_socket = Some(join_group(group.addr));
+        }
+        group.master
+    }
+
+    /// Receives the next DATA packet sent to the group, returning its block number
+    fn data(&mut self) -> Option<u16> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let group_socket = self.group_socket.as_ref().unwrap();
+        let amt = match group_socket.recv(&mut buf) {
+            Ok(amt) => amt,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+            Err(e) => panic!("{:?}", e),
+        };
+        match Packet::read(&buf[..amt]).unwrap() {
+            Packet::DATA { block_num, data } => {
+                let i = block_num as usize - 1;
+                if self.blocks.len() <= i {
+                    self.blocks.resize(i + 1, None);
+                }
+                self.blocks[i] = Some(data);
+                Some(block_num)
+            }
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    fn ack(&self, block: u16) {
+        let packet = Packet::ACK(block).into_bytes().unwrap();
+        self.socket.send_to(&packet, self.session.unwrap()).unwrap();
+    }
+
+    /// Returns the last block received in sequence, and whether that is the final one
+    fn received(&self) -> (u16, bool) {
+        let have = self.blocks.iter().take_while(|b| b.is_some()).count();
+        let last = self.blocks.last().and_then(|b| b.as_ref());
+        let complete = have == self.blocks.len() && last.is_some_and(|b| b.len() < 512);
+        (have as u16, complete)
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        self.blocks
+            .iter()
+            .flat_map(|b| b.clone().unwrap())
+            .collect()
+    }
+}
+
+/// Binds a socket receiving the datagrams sent to a group over the loopback interface
+fn join_group(group: SocketAddr) -> UdpSocket {
+    use nix::sys::socket::{bind, setsockopt, socket, sockopt};
+    use nix::sys::socket::{AddressFamily, SockFlag, SockType, SockaddrIn};
+    use std::net::Ipv4Addr;
+    use std::os::unix::io::AsRawFd;
+
+    // every client of the test listens to the same port
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )
+    .unwrap();
+    setsockopt(&fd, sockopt::ReuseAddr, &true).unwrap();
+    bind(fd.as_raw_fd(), &SockaddrIn::new(0, 0, 0, 0, group.port())).unwrap();
+    let socket = UdpSocket::from(fd);
+    let ip = match group.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => panic!("IPv4 group expected"),
+    };
+    socket.join_multicast_v4(&ip, &Ipv4Addr::LOCALHOST).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    socket
+}
+
+fn multicast_test() {
+    // a port no one else uses
+    let port = UdpSocket::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let group = SocketAddr::new(IpAddr::from([239, 255, 42, 1]), port);
+    let (server_addr, handle, thread) = start_server_with(ServerConfig {
+        multicast: vec![group],
+        ..Default::default()
+    });
+    let contents = (0..512 * 5 + 100).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write("./multicast.bin", &contents).unwrap();
+
+    let mut a = MulticastClient::request(&server_addr, "./multicast.bin");
+    assert!(a.oack(), "first client is not master");
+    a.ack(0);
+    assert_eq!(a.data(), Some(1));
+
+    // joins after the first block was sent
+    let mut b = MulticastClient::request(&server_addr, "./multicast.bin");
+    assert!(!b.oack(), "second client is master");
+    assert_eq!(a.session, b.session);
+
+    loop {
+        let (last, complete) = a.received();
+        a.ack(last);
+        if complete {
+            break;
+        }
+        a.data();
+    }
+    assert!(a.contents() == contents, "master client read differs");
+
+    // the second client takes over once the first one is done
+    assert!(b.oack(), "second client did not become master");
+    while b.data().is_some() {}
+    assert!(b.blocks[0].is_none() && b.blocks[1].is_some());
+    loop {
+        let (last, complete) = b.received();
+        b.ack(last);
+        if complete {
+            break;
+        }
+        b.data();
+    }
+    assert!(b.contents() == contents, "late client read differs");
+
+    assert!(fs::remove_file("./multicast.bin").is_ok());
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+}
+
+fn manual_clock_test() {
+    let cfg = ServerConfig::default();
+    let (server_addr, clock, handle, thread) = start_manual_clock_server();
+
+    let socket = create_socket(Some(Duration::from_secs(3))).unwrap();
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: Octet,
+        options: vec![],
+    };
+    socket
+        .send_to(&init_packet.into_bytes().unwrap(), server_addr)
+        .unwrap();
+    let mut buf = [0; MAX_PACKET_SIZE];
+    let (amt, src) = socket.recv_from(&mut buf).unwrap();
+    let block_1 = Packet::read(&buf[..amt]).unwrap();
+    assert_matches!(block_1, Packet::DATA { block_num: 1, .. });
+    sync_with_server(&server_addr);
+
+    // nothing is resent before the timeout, however long it really takes
+    let start = clock.now();
+    clock.advance(cfg.timeout - Duration::from_millis(1));
+    sync_with_server(&server_addr);
+    // nor does it start over for packets from anyone but the client
+    let stray = create_socket(Some(Duration::from_secs(3))).unwrap();
+    stray
+        .send_to(&Packet::ACK(1).into_bytes().unwrap(), src)
+        .unwrap();
+    let amt = stray.recv(&mut buf).unwrap();
+    assert_matches!(
+        Packet::read(&buf[..amt]).unwrap(),
+        Packet::ERROR {
+            code: ErrorCode::UnknownID,
+            ..
+        }
+    );
+    clock.advance(Duration::from_millis(1));
+    let amt = socket.recv(&mut buf).unwrap();
+    assert_eq!(Packet::read(&buf[..amt]).unwrap(), block_1);
+    assert_eq!(clock.now() - start, cfg.timeout);
+    sync_with_server(&server_addr);
+    socket.set_nonblocking(true).unwrap();
+    assert_matches!(
+        socket.recv(&mut buf), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock,
+        "resent too early"
+    );
+
+    // once the retries run out, the connection is closed
+    clock.advance(cfg.timeout);
+    sync_with_server(&server_addr);
+    socket
+        .send_to(&Packet::ACK(1).into_bytes().unwrap(), src)
+        .unwrap();
+    sync_with_server(&server_addr);
+    assert_matches!(
+        socket.recv(&mut buf), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock,
+        "packet received after connection should have dropped"
+    );
+
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+}
+
+fn stop_hard_test() {
+    let (server_addr, handle, thread) = start_stoppable_server();
+    let mut scratch_buf = [0; MAX_PACKET_SIZE];
+
+    let mut rx =
+        ReadingTransfer::start("./stop_hard.txt", &server_addr, "./files/hello.txt", vec![]);
+    assert_eq!(rx.step(&mut scratch_buf), Some(()));
+
+    let deadman = DeadmanThread::start(Duration::from_secs(2), "hard stop failed");
+    handle.stop_hard();
+    assert_matches!(thread.join(), Ok(Ok(())));
+    drop(deadman);
+
+    // the aborted transfer is notified, possibly after data sent before the stop
+    let packet = loop {
+        let amt = rx.socket.recv(&mut scratch_buf).unwrap();
+        match Packet::read(&scratch_buf[..amt]).unwrap() {
+            Packet::DATA { .. } => continue,
+            p => break p,
+        }
+    };
+    assert_matches!(packet, Packet::ERROR { .. });
+
+    // stopping again does nothing
+    handle.stop_hard();
+    handle.stop_soft(Duration::from_secs(1));
+    assert!(fs::remove_file("./stop_hard.txt").is_ok());
+}
+
+fn stop_soft_test() {
+    let (server_addr, handle, thread) = start_stoppable_server();
+    let mut scratch_buf = [0; MAX_PACKET_SIZE];
+
+    let mut rx =
+        ReadingTransfer::start("./stop_soft.txt", &server_addr, "./files/hello.txt", vec![]);
+    assert_eq!(rx.step(&mut scratch_buf), Some(()));
+
+    let deadman = DeadmanThread::start(Duration::from_secs(5), "soft stop failed");
+    handle.clone().stop_soft(Duration::from_secs(10));
+    // give the server time to process the request
+    thread::sleep(Duration::from_millis(100));
+
+    // new requests are no longer answered
+    let socket = create_socket(Some(Duration::from_millis(300))).unwrap();
+    let init_packet = Packet::RRQ {
+        filename: "./files/hello.txt".into(),
+        mode: Octet,
+        options: vec![],
+    };
+    socket
+        .send_to(&init_packet.into_bytes().unwrap(), server_addr)
+        .unwrap();
+    assert!(socket.recv(&mut scratch_buf).is_err());
+
+    // but the ongoing transfer completes
+    while rx.step(&mut scratch_buf).is_some() {}
+    assert_matches!(thread.join(), Ok(Ok(())));
+    drop(deadman);
+
+    assert_files_identical("./stop_soft.txt", "./files/hello.txt");
+    assert!(fs::remove_file("./stop_soft.txt").is_ok());
+}
+
+fn stop_soft_deadline_test() {
+    let (server_addr, handle, thread) = start_stoppable_server();
+    let mut scratch_buf = [0; MAX_PACKET_SIZE];
+
+    let mut rx = ReadingTransfer::start(
+        "./stop_deadline.txt",
+        &server_addr,
+        "./files/hello.txt",
+        vec![],
+    );
+    assert_eq!(rx.step(&mut scratch_buf), Some(()));
+
+    // the transfer stalls, so it gets aborted when the deadline expires
+    let deadman = DeadmanThread::start(Duration::from_secs(2), "soft stop deadline failed");
+    handle.stop_soft(Duration::from_millis(300));
+    assert_matches!(thread.join(), Ok(Ok(())));
+    drop(deadman);
+
+    assert!(fs::remove_file("./stop_deadline.txt").is_ok());
+}
+
 fn main() {
     env_logger::init();
     let addrs = start_server().unwrap();
@@ -349,10 +1230,25 @@ fn main() {
         rrq_whole_file_test(addr, vec![]).unwrap();
     }
 
-    timeout_test(&server_addr).unwrap();
+    timeout_test();
+    adaptive_timeout_test(&server_addr).unwrap();
     wrq_file_exists_test(&server_addr).unwrap();
     rrq_file_not_found_test(&server_addr).unwrap();
+    long_request_test(&server_addr).unwrap();
+    unknown_tid_test(&server_addr).unwrap();
     interleaved_read_read_same_file(&server_addr);
     wrq_whole_file_test(&server_addr, vec![TftpOption::Blocksize(2050)]).unwrap();
     rrq_whole_file_test(&server_addr, vec![TftpOption::Blocksize(2050)]).unwrap();
+    client_test(&server_addr);
+    congestion_control_test();
+    max_blocksize_test();
+    wildcard_reply_address_test();
+    offload_test();
+    offload_commit_test();
+    workers_test();
+    multicast_test();
+    manual_clock_test();
+    stop_hard_test();
+    stop_soft_test();
+    stop_soft_deadline_test();
 }